@@ -1,10 +1,81 @@
 #![allow(dead_code)]  // the code it warns about is not actually dead, so...
 
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
+use std::cmp::{Ordering, Less, Greater};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Writer};
 use std::rc::Rc;
 
 static INDENTATION: uint = 2;
 
+// where in the source a node came from; line and column start at 1, pos is a byte offset
+#[deriving(Clone, Show)]
+pub struct Span {
+   pub line: uint,
+   pub column: uint,
+   pub pos: uint
+}
+
+// an interned identifier: names with the same text share an id, so comparing and hashing them
+// never touches the text, which is kept alongside only for printing
+#[deriving(Clone)]
+pub struct Name {
+   id: u32,
+   text: Rc<String>
+}
+
+// every name interned so far on this task
+local_data_key!(NAMES: Rc<RefCell<HashMap<String, Name>>>)
+
+impl Name {
+   pub fn new(text: &str) -> Name {
+      let names = match NAMES.get() {
+         Some(names) => names.clone(),
+         None => {
+            let names = Rc::new(RefCell::new(HashMap::new()));
+            NAMES.replace(Some(names.clone()));
+            names
+         }
+      };
+      let mut names = names.borrow_mut();
+      match names.find_equiv(&text) {
+         Some(name) => return name.clone(),
+         None => {}
+      }
+      let name = Name {
+         id: names.len() as u32,
+         text: Rc::new(text.to_string())
+      };
+      names.insert(text.to_string(), name.clone());
+      name
+   }
+
+   pub fn as_slice<'a>(&'a self) -> &'a str {
+      self.text.as_slice()
+   }
+}
+
+impl PartialEq for Name {
+   fn eq(&self, other: &Name) -> bool {
+      self.id == other.id
+   }
+}
+
+impl Eq for Name {}
+
+impl<S: Writer> Hash<S> for Name {
+   fn hash(&self, state: &mut S) {
+      self.id.hash(state)
+   }
+}
+
+impl fmt::Show for Name {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      self.text.fmt(f)
+   }
+}
+
 #[deriving(Clone, PartialEq)]
 pub enum ExprAst {
    Root(RootAst),
@@ -19,8 +90,11 @@ pub enum ExprAst {
    Float(FloatAst),
    Boolean(BooleanAst),
    Nil(NilAst),
-   Comment(CommentAst),
-   Code(CodeAst)
+   Code(CodeAst),
+   Builtin(BuiltinAst),
+   Host(HostAst),
+   Dict(DictAst),
+   Struct(StructAst)
 }
 
 pub trait Ast {
@@ -28,10 +102,20 @@ pub trait Ast {
    //fn eval(&self) -> Option<Box<Any>>;
    fn compile(&self) -> Vec<u8>;
 
-   fn dump(&self) { self.dump_level(0) }
+   fn dump(&self) -> String {
+      let mut buf = String::new();
+      self.dump_level(&mut buf, 0, false);
+      buf
+   }
+
+   fn dump_spans(&self) -> String {
+      let mut buf = String::new();
+      self.dump_level(&mut buf, 0, true);
+      buf
+   }
 
    // XXX: this should in actuality be private...
-   fn dump_level(&self, level: uint);
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool);
 }
 
 #[deriving(Clone, PartialEq)]
@@ -42,22 +126,69 @@ pub struct RootAst {
 #[deriving(Clone, PartialEq)]
 pub struct SexprAst {
    pub op: IdentAst,
-   pub operands: Vec<ExprAst>
+   pub operands: Vec<ExprAst>,
+   // how the operands are evaluated, worked out from the operator when the node is made
+   pub form: SpecialForm,
+   // the builtin the operator referred to when the code was optimized, if the code never binds
+   // that name itself; the interpreter still checks nothing else has bound it before using it
+   pub builtin: Option<::interp::BuiltinFn>,
+   pub span: Span
+}
+
+#[deriving(Clone, PartialEq)]
+pub enum SpecialForm {
+   // every operand is evaluated before the call
+   CallForm,
+   // only the condition is evaluated up front
+   IfForm,
+   // define and set!, whose first operand is the name being bound
+   DefineForm,
+   // the name after as is left alone
+   ImportForm,
+   // fn, defstruct, while and the like receive their operands as code
+   LazyForm
+}
+
+impl SpecialForm {
+   pub fn of(op: &str) -> SpecialForm {
+      match op {
+         "fn" | "defstruct" | "while" | "when" | "case" | "and" | "or" | "export" | "time" | "quote" => LazyForm,
+         "if" => IfForm,
+         "define" | "set!" => DefineForm,
+         "import" => ImportForm,
+         _ => CallForm
+      }
+   }
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct StringAst {
-   pub string: String
+   pub string: String,
+   pub span: Span
 }
 
+// a list is a chain of immutable cons cells, so tails can be shared between lists
 #[deriving(Clone, PartialEq)]
 pub struct ListAst {
-   pub items: Vec<ExprAst>
+   pub head: Option<Rc<ConsCell>>,
+   pub span: Span
+}
+
+#[deriving(Clone, PartialEq)]
+pub struct ConsCell {
+   pub value: ExprAst,
+   pub next: ListAst
+}
+
+pub struct ListItems<'a> {
+   next: &'a Option<Rc<ConsCell>>
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct ArrayAst {
-   pub items: Vec<ExprAst>
+   // shared so that every binding of an array sees mutations made through any other
+   pub items: Rc<RefCell<Vec<ExprAst>>>,
+   pub span: Span
 }
 
 #[deriving(Clone, PartialEq)]
@@ -67,44 +198,404 @@ pub struct PointerAst {
 
 #[deriving(Clone, PartialEq)]
 pub struct IdentAst {
-   pub value: String
+   pub value: Name,
+   pub span: Span
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct SymbolAst {
-   pub value: String
+   pub value: Name,
+   pub span: Span
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct IntegerAst {
-   pub value: i64
+   pub value: i64,
+   pub span: Span
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct FloatAst {
-   pub value: f64
+   pub value: f64,
+   pub span: Span
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct BooleanAst {
-   pub value: bool
+   pub value: bool,
+   pub span: Span
 }
 
 #[deriving(Clone, PartialEq)]
-pub struct NilAst;
-
-#[deriving(Clone, PartialEq)]
-pub struct CommentAst {
-   pub value: String
+pub struct NilAst {
+   pub span: Span
 }
 
 #[deriving(Clone, PartialEq)]
 pub struct CodeAst {
    pub params: ArrayAst,
-   pub code: Vec<ExprAst>,
+   // shared by every copy of the function, since the body never changes once it is defined
+   pub code: Rc<Vec<ExprAst>>,
+   // the body compiled to bytecode, for functions created by compiled code
+   pub chunk: Option<Rc<::bytecode::Chunk>>,
    pub env: Rc<RefCell<::interp::Environment>>
 }
 
+#[deriving(Clone, PartialEq)]
+pub struct BuiltinAst {
+   pub name: String,
+   pub func: ::interp::BuiltinFn
+}
+
+// a function registered by an embedder with Interpreter::register_builtin
+#[deriving(Clone, PartialEq)]
+pub struct HostAst {
+   pub name: String,
+   pub func: ::interp::HostFn
+}
+
+// shared between bindings like ArrayAst; symbol keys are stored under their name
+#[deriving(Clone, PartialEq)]
+pub struct DictAst {
+   pub entries: Rc<RefCell<HashMap<String, ExprAst>>>
+}
+
+// an instance of a type created with defstruct; values line up with fields
+#[deriving(Clone, PartialEq)]
+pub struct StructAst {
+   pub name: Name,
+   pub fields: Vec<Name>,
+   pub values: Vec<ExprAst>
+}
+
+impl Span {
+   pub fn new(line: uint, column: uint, pos: uint) -> Span {
+      Span {
+         line: line,
+         column: column,
+         pos: pos
+      }
+   }
+
+   // for nodes made at runtime rather than by the parser
+   pub fn none() -> Span {
+      Span::new(0, 0, 0)
+   }
+
+   pub fn is_known(&self) -> bool {
+      self.line != 0
+   }
+
+   fn label(&self, spans: bool) -> String {
+      if spans && self.is_known() {
+         format!(" @ {}:{}", self.line, self.column)
+      } else {
+         "".to_string()
+      }
+   }
+}
+
+// two values are equal no matter where they were written
+impl PartialEq for Span {
+   fn eq(&self, _: &Span) -> bool {
+      true
+   }
+}
+
+impl ExprAst {
+   pub fn span(&self) -> Span {
+      match *self {
+         Sexpr(ref ast) => ast.span.clone(),
+         String(ref ast) => ast.span.clone(),
+         List(ref ast) => ast.span.clone(),
+         Array(ref ast) => ast.span.clone(),
+         Ident(ref ast) => ast.span.clone(),
+         Symbol(ref ast) => ast.span.clone(),
+         Integer(ref ast) => ast.span.clone(),
+         Float(ref ast) => ast.span.clone(),
+         Boolean(ref ast) => ast.span.clone(),
+         Nil(ref ast) => ast.span.clone(),
+         _ => Span::none()
+      }
+   }
+
+   pub fn with_span(self, span: Span) -> ExprAst {
+      match self {
+         Sexpr(mut ast) => { ast.span = span; Sexpr(ast) }
+         String(mut ast) => { ast.span = span; String(ast) }
+         List(mut ast) => { ast.span = span; List(ast) }
+         Array(mut ast) => { ast.span = span; Array(ast) }
+         Ident(mut ast) => { ast.span = span; Ident(ast) }
+         Symbol(mut ast) => { ast.span = span; Symbol(ast) }
+         Integer(mut ast) => { ast.span = span; Integer(ast) }
+         Float(mut ast) => { ast.span = span; Float(ast) }
+         Boolean(mut ast) => { ast.span = span; Boolean(ast) }
+         Nil(mut ast) => { ast.span = span; Nil(ast) }
+         other => other
+      }
+   }
+
+   // iron source that parses back to this node, with every form on one line; values that only
+   // exist at runtime come out as the code that would make them
+   pub fn source(&self) -> String {
+      let mut out = String::new();
+      self.write_source(&mut out);
+      out
+   }
+
+   fn write_source(&self, out: &mut String) {
+      match *self {
+         Root(ref ast) => {
+            for item in ast.asts.iter() {
+               item.write_source(out);
+               out.push_char('\n');
+            }
+         }
+         Sexpr(ref ast) => {
+            out.push_char('(');
+            out.push_str(ast.op.value.as_slice());
+            ExprAst::write_operands(out, ast.operands.as_slice());
+         }
+         String(ref ast) => ExprAst::write_string(out, ast.string.as_slice()),
+         List(ref ast) => {
+            let items: Vec<ExprAst> = ast.iter().map(|item| item.clone()).collect();
+            out.push_str("'(");
+            ExprAst::write_items(out, items.as_slice());
+            out.push_char(')');
+         }
+         Array(ref ast) => {
+            out.push_char('[');
+            ExprAst::write_items(out, ast.items.borrow().as_slice());
+            out.push_char(']');
+         }
+         Pointer(ref ast) => ast.pointee.write_source(out),
+         Ident(ref ast) => out.push_str(ast.value.as_slice()),
+         Symbol(ref ast) => {
+            out.push_char('\'');
+            out.push_str(ast.value.as_slice());
+         }
+         Integer(ref ast) => out.push_str(ast.value.to_str().as_slice()),
+         Float(ref ast) => ExprAst::write_float(out, ast.value),
+         Boolean(ref ast) => out.push_str(if ast.value { "true" } else { "false" }),
+         Nil(_) => out.push_str("nil"),
+         Code(ref ast) => {
+            out.push_str("(fn ");
+            Array(ast.params.clone()).write_source(out);
+            ExprAst::write_operands(out, ast.code.as_slice());
+         }
+         Builtin(ref ast) => out.push_str(ast.name.as_slice()),
+         Host(ref ast) => out.push_str(ast.name.as_slice()),
+         Dict(ref ast) => {
+            out.push_str("(dict");
+            let entries = ast.entries.borrow();
+            for key in ast.sorted_keys().iter() {
+               out.push_char(' ');
+               ExprAst::write_string(out, key.as_slice());
+               out.push_char(' ');
+               entries.find(key).unwrap().write_source(out);
+            }
+            out.push_char(')');
+         }
+         Struct(ref ast) => {
+            out.push_char('(');
+            out.push_str(ast.name.as_slice());
+            ExprAst::write_operands(out, ast.values.as_slice());
+         }
+      }
+   }
+
+   fn write_items(out: &mut String, items: &[ExprAst]) {
+      for (i, item) in items.iter().enumerate() {
+         if i > 0 {
+            out.push_char(' ');
+         }
+         item.write_source(out);
+      }
+   }
+
+   // the rest of a form whose operator has already been written
+   fn write_operands(out: &mut String, operands: &[ExprAst]) {
+      for operand in operands.iter() {
+         out.push_char(' ');
+         operand.write_source(out);
+      }
+      out.push_char(')');
+   }
+
+   fn write_string(out: &mut String, value: &str) {
+      out.push_char('"');
+      for ch in value.chars() {
+         match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            ch if ch.is_control() => out.push_str(format!("\\u{{{:x}}}", ch as u32).as_slice()),
+            ch => out.push_char(ch)
+         }
+      }
+      out.push_char('"');
+   }
+
+   // the fewest decimal places that read back as the same float, with a point so that it does
+   // not read back as an integer
+   fn write_float(out: &mut String, value: f64) {
+      // there are no literals for these, so they come out as sums that work them out again,
+      // which the folder leaves for the interpreter
+      if value.is_infinite() {
+         out.push_str(if value > 0.0 { "(+ 1e308 1e308)" } else { "(+ -1e308 -1e308)" });
+         return;
+      } else if value.is_nan() {
+         out.push_str("(+ (+ 1e308 1e308) (+ -1e308 -1e308))");
+         return;
+      }
+      let mut digits = 0u;
+      let mut text = ::std::f64::to_str_digits(value, digits);
+      while from_str::<f64>(text.as_slice()) != Some(value) {
+         digits += 1;
+         text = ::std::f64::to_str_digits(value, digits);
+      }
+      if !text.as_slice().contains_char('.') {
+         text.push_str(".0");
+      }
+      out.push_str(text.as_slice());
+   }
+
+   // shorthands for host code building values to hand to scripts
+   pub fn from_i64(value: i64) -> ExprAst { Integer(IntegerAst::new(value)) }
+   pub fn from_f64(value: f64) -> ExprAst { Float(FloatAst::new(value)) }
+   pub fn from_str(value: &str) -> ExprAst { String(StringAst::new(value.to_string())) }
+   pub fn from_bool(value: bool) -> ExprAst { Boolean(BooleanAst::new(value)) }
+   pub fn from_vec(items: Vec<ExprAst>) -> ExprAst { Array(ArrayAst::new(items)) }
+   pub fn nil() -> ExprAst { Nil(NilAst::new()) }
+
+   // and for taking script values apart again; none of these convert between types, except
+   // that an integer is also accepted as a float
+   pub fn as_i64(&self) -> Option<i64> {
+      match *self {
+         Integer(ref ast) => Some(ast.value),
+         _ => None
+      }
+   }
+
+   pub fn as_f64(&self) -> Option<f64> {
+      match *self {
+         Float(ref ast) => Some(ast.value),
+         Integer(ref ast) => Some(ast.value as f64),
+         _ => None
+      }
+   }
+
+   pub fn as_bool(&self) -> Option<bool> {
+      match *self {
+         Boolean(ref ast) => Some(ast.value),
+         _ => None
+      }
+   }
+
+   pub fn as_str<'a>(&'a self) -> Option<&'a str> {
+      match *self {
+         String(ref ast) => Some(ast.string.as_slice()),
+         _ => None
+      }
+   }
+
+   // the items of an array, borrowed for as long as the Ref is kept
+   pub fn as_slice_of_asts<'a>(&'a self) -> Option<Ref<'a, Vec<ExprAst>>> {
+      match *self {
+         Array(ref ast) => Some(ast.items.borrow()),
+         _ => None
+      }
+   }
+
+   pub fn is_nil(&self) -> bool {
+      match *self {
+         Nil(_) => true,
+         _ => false
+      }
+   }
+
+   pub fn to<T: FromExpr>(&self) -> Option<T> {
+      FromExpr::from_expr(self)
+   }
+}
+
+// Rust values that have an obvious script value, including arrays of them
+pub trait ToExpr {
+   fn to_expr(self) -> ExprAst;
+}
+
+// the other direction, giving None when the shape does not match, even if only one array
+// item is wrong
+pub trait FromExpr {
+   fn from_expr(value: &ExprAst) -> Option<Self>;
+}
+
+impl ToExpr for i64 {
+   fn to_expr(self) -> ExprAst { ExprAst::from_i64(self) }
+}
+
+impl ToExpr for f64 {
+   fn to_expr(self) -> ExprAst { ExprAst::from_f64(self) }
+}
+
+impl ToExpr for bool {
+   fn to_expr(self) -> ExprAst { ExprAst::from_bool(self) }
+}
+
+impl ToExpr for String {
+   fn to_expr(self) -> ExprAst { String(StringAst::new(self)) }
+}
+
+impl ToExpr for ExprAst {
+   fn to_expr(self) -> ExprAst { self }
+}
+
+impl<T: ToExpr> ToExpr for Vec<T> {
+   fn to_expr(self) -> ExprAst {
+      ExprAst::from_vec(self.move_iter().map(|item| item.to_expr()).collect())
+   }
+}
+
+impl FromExpr for i64 {
+   fn from_expr(value: &ExprAst) -> Option<i64> { value.as_i64() }
+}
+
+impl FromExpr for f64 {
+   fn from_expr(value: &ExprAst) -> Option<f64> { value.as_f64() }
+}
+
+impl FromExpr for bool {
+   fn from_expr(value: &ExprAst) -> Option<bool> { value.as_bool() }
+}
+
+impl FromExpr for String {
+   fn from_expr(value: &ExprAst) -> Option<String> { value.as_str().map(|string| string.to_string()) }
+}
+
+impl FromExpr for ExprAst {
+   fn from_expr(value: &ExprAst) -> Option<ExprAst> { Some(value.clone()) }
+}
+
+impl<T: FromExpr> FromExpr for Vec<T> {
+   fn from_expr(value: &ExprAst) -> Option<Vec<T>> {
+      let items = match value.as_slice_of_asts() {
+         Some(items) => items,
+         None => return None
+      };
+      let mut result = Vec::with_capacity(items.len());
+      for item in items.iter() {
+         match FromExpr::from_expr(item) {
+            Some(item) => result.push(item),
+            None => return None
+         }
+      }
+      Some(result)
+   }
+}
+
 impl Ast for ExprAst {
    fn optimize(self) -> Option<ExprAst> {
       match self {
@@ -120,8 +611,11 @@ impl Ast for ExprAst {
          Float(ast) => ast.optimize(),
          Boolean(ast) => ast.optimize(),
          Nil(ast) => ast.optimize(),
-         Comment(ast) => ast.optimize(),
-         Code(ast) => ast.optimize()
+         Code(ast) => ast.optimize(),
+         Builtin(ast) => ast.optimize(),
+         Host(ast) => ast.optimize(),
+         Dict(ast) => ast.optimize(),
+         Struct(ast) => ast.optimize()
       }
    }
 
@@ -139,27 +633,235 @@ impl Ast for ExprAst {
          Float(ref ast) => ast.compile(),
          Boolean(ref ast) => ast.compile(),
          Nil(ref ast) => ast.compile(),
-         Comment(ref ast) => ast.compile(),
-         Code(ref ast) => ast.compile()
+         Code(ref ast) => ast.compile(),
+         Builtin(ref ast) => ast.compile(),
+         Host(ref ast) => ast.compile(),
+         Dict(ref ast) => ast.compile(),
+         Struct(ref ast) => ast.compile()
       }
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       match *self {
-         Root(ref ast) => ast.dump_level(level),
-         Sexpr(ref ast) => ast.dump_level(level),
-         String(ref ast) => ast.dump_level(level),
-         List(ref ast) => ast.dump_level(level),
-         Array(ref ast) => ast.dump_level(level),
-         Pointer(ref ast) => ast.dump_level(level),
-         Ident(ref ast) => ast.dump_level(level),
-         Symbol(ref ast) => ast.dump_level(level),
-         Integer(ref ast) => ast.dump_level(level),
-         Float(ref ast) => ast.dump_level(level),
-         Boolean(ref ast) => ast.dump_level(level),
-         Nil(ref ast) => ast.dump_level(level),
-         Comment(ref ast) => ast.dump_level(level),
-         Code(ref ast) => ast.dump_level(level)
+         Root(ref ast) => ast.dump_level(out, level, spans),
+         Sexpr(ref ast) => ast.dump_level(out, level, spans),
+         String(ref ast) => ast.dump_level(out, level, spans),
+         List(ref ast) => ast.dump_level(out, level, spans),
+         Array(ref ast) => ast.dump_level(out, level, spans),
+         Pointer(ref ast) => ast.dump_level(out, level, spans),
+         Ident(ref ast) => ast.dump_level(out, level, spans),
+         Symbol(ref ast) => ast.dump_level(out, level, spans),
+         Integer(ref ast) => ast.dump_level(out, level, spans),
+         Float(ref ast) => ast.dump_level(out, level, spans),
+         Boolean(ref ast) => ast.dump_level(out, level, spans),
+         Nil(ref ast) => ast.dump_level(out, level, spans),
+         Code(ref ast) => ast.dump_level(out, level, spans),
+         Builtin(ref ast) => ast.dump_level(out, level, spans),
+         Host(ref ast) => ast.dump_level(out, level, spans),
+         Dict(ref ast) => ast.dump_level(out, level, spans),
+         Struct(ref ast) => ast.dump_level(out, level, spans)
+      }
+   }
+}
+
+// a read-only walk over a tree, for tools like linters: each visit_ method is called for the
+// nodes of its kind and by default goes on into their children, so an implementation only
+// overrides the kinds it cares about
+pub trait Visitor {
+   fn visit_root(&mut self, ast: &RootAst) { walk_root(ast, self) }
+   fn visit_sexpr(&mut self, ast: &SexprAst) { walk_sexpr(ast, self) }
+   fn visit_list(&mut self, ast: &ListAst) { walk_list(ast, self) }
+   fn visit_array(&mut self, ast: &ArrayAst) { walk_array(ast, self) }
+   fn visit_ident(&mut self, _: &IdentAst) { }
+   fn visit_symbol(&mut self, _: &SymbolAst) { }
+   fn visit_string(&mut self, _: &StringAst) { }
+   fn visit_integer(&mut self, _: &IntegerAst) { }
+   fn visit_float(&mut self, _: &FloatAst) { }
+   fn visit_boolean(&mut self, _: &BooleanAst) { }
+   fn visit_nil(&mut self, _: &NilAst) { }
+   // functions, dicts and the like, which only exist once the code runs
+   fn visit_value(&mut self, _: &ExprAst) { }
+}
+
+pub fn walk_expr<V: Visitor>(ast: &ExprAst, visitor: &mut V) {
+   match *ast {
+      Root(ref ast) => visitor.visit_root(ast),
+      Sexpr(ref ast) => visitor.visit_sexpr(ast),
+      List(ref ast) => visitor.visit_list(ast),
+      Array(ref ast) => visitor.visit_array(ast),
+      Ident(ref ast) => visitor.visit_ident(ast),
+      Symbol(ref ast) => visitor.visit_symbol(ast),
+      String(ref ast) => visitor.visit_string(ast),
+      Integer(ref ast) => visitor.visit_integer(ast),
+      Float(ref ast) => visitor.visit_float(ast),
+      Boolean(ref ast) => visitor.visit_boolean(ast),
+      Nil(ref ast) => visitor.visit_nil(ast),
+      ref other => visitor.visit_value(other)
+   }
+}
+
+pub fn walk_root<V: Visitor>(ast: &RootAst, visitor: &mut V) {
+   for item in ast.asts.iter() {
+      walk_expr(item, visitor);
+   }
+}
+
+pub fn walk_sexpr<V: Visitor>(ast: &SexprAst, visitor: &mut V) {
+   visitor.visit_ident(&ast.op);
+   for operand in ast.operands.iter() {
+      walk_expr(operand, visitor);
+   }
+}
+
+pub fn walk_list<V: Visitor>(ast: &ListAst, visitor: &mut V) {
+   for item in ast.iter() {
+      walk_expr(item, visitor);
+   }
+}
+
+pub fn walk_array<V: Visitor>(ast: &ArrayAst, visitor: &mut V) {
+   for item in ast.items.borrow().iter() {
+      walk_expr(item, visitor);
+   }
+}
+
+// the same for rewriting a tree, which optimize() uses to fold constants; every method gives
+// back the replacement for the node it was handed, by default the node with its children
+// transformed
+pub trait Transformer {
+   fn transform_sexpr(&mut self, ast: SexprAst) -> ExprAst { Sexpr(walk_sexpr_mut(ast, self)) }
+   fn transform_list(&mut self, ast: ListAst) -> ExprAst { List(walk_list_mut(ast, self)) }
+   fn transform_array(&mut self, ast: ArrayAst) -> ExprAst { Array(walk_array_mut(ast, self)) }
+   // identifiers stay identifiers, since they may be the operator of a form
+   fn transform_ident(&mut self, ast: IdentAst) -> IdentAst { ast }
+   // every other kind of node, none of which have children
+   fn transform_other(&mut self, ast: ExprAst) -> ExprAst { ast }
+}
+
+pub fn transform_expr<T: Transformer>(ast: ExprAst, transformer: &mut T) -> ExprAst {
+   match ast {
+      Root(ast) => Root(walk_root_mut(ast, transformer)),
+      Sexpr(ast) => transformer.transform_sexpr(ast),
+      List(ast) => transformer.transform_list(ast),
+      Array(ast) => transformer.transform_array(ast),
+      Ident(ast) => Ident(transformer.transform_ident(ast)),
+      other => transformer.transform_other(other)
+   }
+}
+
+pub fn walk_root_mut<T: Transformer>(ast: RootAst, transformer: &mut T) -> RootAst {
+   let mut result = RootAst::new();
+   for item in ast.asts.move_iter() {
+      result.push(transform_expr(item, transformer));
+   }
+   result
+}
+
+pub fn walk_sexpr_mut<T: Transformer>(ast: SexprAst, transformer: &mut T) -> SexprAst {
+   let SexprAst { op, operands, builtin, span, .. } = ast;
+   let op = transformer.transform_ident(op);
+   let mut result = Vec::with_capacity(operands.len());
+   for operand in operands.move_iter() {
+      result.push(transform_expr(operand, transformer));
+   }
+   SexprAst {
+      // the operator may have been renamed into or out of a special form
+      form: SpecialForm::of(op.value.as_slice()),
+      op: op,
+      operands: result,
+      builtin: builtin,
+      span: span
+   }
+}
+
+pub fn walk_list_mut<T: Transformer>(ast: ListAst, transformer: &mut T) -> ListAst {
+   let mut items = vec!();
+   for item in ast.iter() {
+      items.push(transform_expr(item.clone(), transformer));
+   }
+   let mut result = ListAst::new(items);
+   result.span = ast.span;
+   result
+}
+
+pub fn walk_array_mut<T: Transformer>(ast: ArrayAst, transformer: &mut T) -> ArrayAst {
+   let mut items = vec!();
+   for item in ast.items.borrow().iter() {
+      items.push(transform_expr(item.clone(), transformer));
+   }
+   let mut result = ArrayAst::new(items);
+   result.span = ast.span;
+   result
+}
+
+// how many nodes of each kind a tree has, with operators counted as identifiers
+pub struct NodeCounter {
+   pub counts: HashMap<&'static str, uint>
+}
+
+impl NodeCounter {
+   pub fn new() -> NodeCounter {
+      NodeCounter {
+         counts: HashMap::new()
+      }
+   }
+
+   fn count(&mut self, kind: &'static str) {
+      self.counts.insert_or_update_with(kind, 1, |_, count| *count += 1);
+   }
+
+   // one line per kind, in alphabetical order
+   pub fn report(&self) -> String {
+      let mut kinds: Vec<&&'static str> = self.counts.keys().collect();
+      kinds.sort();
+      let mut out = String::new();
+      for kind in kinds.move_iter() {
+         out.push_str(format!("{}: {}\n", kind, self.counts.get(kind)).as_slice());
+      }
+      out
+   }
+}
+
+impl Visitor for NodeCounter {
+   fn visit_sexpr(&mut self, ast: &SexprAst) {
+      self.count("sexpr");
+      walk_sexpr(ast, self);
+   }
+
+   fn visit_list(&mut self, ast: &ListAst) {
+      self.count("list");
+      walk_list(ast, self);
+   }
+
+   fn visit_array(&mut self, ast: &ArrayAst) {
+      self.count("array");
+      walk_array(ast, self);
+   }
+
+   fn visit_ident(&mut self, _: &IdentAst) { self.count("ident") }
+   fn visit_symbol(&mut self, _: &SymbolAst) { self.count("symbol") }
+   fn visit_string(&mut self, _: &StringAst) { self.count("string") }
+   fn visit_integer(&mut self, _: &IntegerAst) { self.count("integer") }
+   fn visit_float(&mut self, _: &FloatAst) { self.count("float") }
+   fn visit_boolean(&mut self, _: &BooleanAst) { self.count("boolean") }
+   fn visit_nil(&mut self, _: &NilAst) { self.count("nil") }
+   fn visit_value(&mut self, _: &ExprAst) { self.count("value") }
+}
+
+// renames an identifier everywhere it appears, as an operator, a parameter or a value
+pub struct Renamer {
+   pub from: Name,
+   pub to: Name
+}
+
+impl Transformer for Renamer {
+   fn transform_ident(&mut self, ast: IdentAst) -> IdentAst {
+      if ast.value == self.from {
+         let mut result = IdentAst::new(self.to.clone());
+         result.span = ast.span;
+         result
+      } else {
+         ast
       }
    }
 }
@@ -174,13 +876,254 @@ impl RootAst {
    pub fn push(&mut self, ast: ExprAst) {
       self.asts.push(ast);
    }
+
+   // optimize for code that runs where the names in bound are already defined, e.g. the next
+   // line in the REPL; calls to any of them are left alone since they may not be the builtins.
+   // Calls to the other names in builtins are marked with the function they will run
+   pub fn optimize_with(self, bound: &HashSet<Name>, builtins: &HashMap<Name, ::interp::BuiltinFn>) -> ExprAst {
+      let mut optimizer = Optimizer::new(&self, bound, builtins);
+      Root(optimizer.optimize_root(self))
+   }
+}
+
+// optimizes the forms of a root one at a time, so that each can be changed first, e.g. by
+// expanding its macros; the names bound anywhere in the root are known from the start
+pub struct Optimizer {
+   folder: Folder
+}
+
+impl Optimizer {
+   pub fn new(root: &RootAst, bound: &HashSet<Name>, builtins: &HashMap<Name, ::interp::BuiltinFn>) -> Optimizer {
+      let mut folder = Folder::new(bound.clone(), builtins.clone());
+      walk_root(root, &mut folder);
+      Optimizer {
+         folder: folder
+      }
+   }
+
+   // the form may bind names the root did not, when a macro wrote it, so it is looked at again
+   pub fn optimize(&mut self, ast: ExprAst) -> Option<ExprAst> {
+      let ast = match ast.optimize() {
+         Some(ast) => ast,
+         None => return None
+      };
+      walk_expr(&ast, &mut self.folder);
+      Some(transform_expr(ast, &mut self.folder))
+   }
+
+   pub fn optimize_root(&mut self, root: RootAst) -> RootAst {
+      let mut result = RootAst::new();
+      for ast in root.asts.move_iter() {
+         match self.optimize(ast) {
+            Some(ast) => result.push(ast),
+            None => {}
+         }
+      }
+      result
+   }
+}
+
+// works out calls to +, the comparisons and str whose operands are all literals, replaces an if
+// with a literal condition by the branch it would take, drops calls to the assert builtin, and
+// marks the remaining calls with the builtin they refer to
+struct Folder {
+   // names the code binds itself, any of which could hide a builtin
+   bound: HashSet<Name>,
+   builtins: HashMap<Name, ::interp::BuiltinFn>,
+   // an import could bind anything, so nothing is folded in code that has one
+   enabled: bool
+}
+
+impl Folder {
+   fn new(bound: HashSet<Name>, builtins: HashMap<Name, ::interp::BuiltinFn>) -> Folder {
+      Folder {
+         bound: bound,
+         builtins: builtins,
+         enabled: true
+      }
+   }
+
+   fn fold_sexpr(&mut self, sast: SexprAst) -> ExprAst {
+      let op = sast.op.value.clone();
+      // time prints the code it ran and quote gives it back as data, so both need it the way it
+      // was written
+      if op.as_slice() == "time" || op.as_slice() == "quote" {
+         return Sexpr(sast);
+      }
+      let mut sast = walk_sexpr_mut(sast, self);
+      if !self.enabled || self.bound.contains(&op) {
+         return Sexpr(sast);
+      }
+      // assertions are only checked in debug mode; a function the script calls assert is not one
+      if op.as_slice() == "assert" && self.builtins.contains_key(&op) {
+         return Nil(NilAst::new()).with_span(sast.span.clone());
+      }
+      let folded = match op.as_slice() {
+         "if" => match Folder::fold_if(sast) {
+            Ok(branch) => return branch,
+            Err(unfolded) => {
+               sast = unfolded;
+               None
+            }
+         },
+         "+" => Folder::fold_add(sast.operands.as_slice()),
+         "=" => Folder::fold_equal(sast.operands.as_slice()),
+         "<" => Folder::fold_compare(sast.operands.as_slice(), |ord| ord == Less),
+         ">" => Folder::fold_compare(sast.operands.as_slice(), |ord| ord == Greater),
+         "<=" => Folder::fold_compare(sast.operands.as_slice(), |ord| ord != Greater),
+         ">=" => Folder::fold_compare(sast.operands.as_slice(), |ord| ord != Less),
+         "str" => Folder::fold_str(sast.operands.as_slice()),
+         _ => None
+      };
+      match folded {
+         Some(value) => value.with_span(sast.span.clone()),
+         None => {
+            sast.builtin = self.builtins.find(&op).map(|func| *func);
+            Sexpr(sast)
+         }
+      }
+   }
+
+   // gives back the if unchanged when the branch cannot be picked yet
+   fn fold_if(sast: SexprAst) -> Result<ExprAst, SexprAst> {
+      let taken = match sast.operands.as_slice().get(0) {
+         Some(&Boolean(ref ast)) => Some(ast.value),
+         Some(&Nil(_)) => Some(false),
+         Some(&Integer(_)) | Some(&Float(_)) | Some(&String(_)) => Some(true),
+         _ => None
+      };
+      let arity = sast.operands.len();
+      let span = sast.span.clone();
+      match taken {
+         // anything else is an arity error, which has to happen when the code runs
+         Some(taken) if arity == 2 || arity == 3 => {
+            let branch = if taken { 1 } else { 2 };
+            Ok(sast.operands.move_iter().nth(branch).unwrap_or(Nil(NilAst::new()).with_span(span)))
+         }
+         _ => Err(sast)
+      }
+   }
+
+   // integers stay integers until a float shows up, just like + itself
+   fn fold_add(operands: &[ExprAst]) -> Option<ExprAst> {
+      let mut int_acc = 0;
+      let mut float_acc: Option<f64> = None;
+      for operand in operands.iter() {
+         match *operand {
+            Integer(ref ast) => match float_acc {
+               Some(acc) => float_acc = Some(acc + ast.value as f64),
+               None => int_acc += ast.value
+            },
+            Float(ref ast) => float_acc = Some(float_acc.unwrap_or(int_acc as f64) + ast.value),
+            _ => return None
+         }
+      }
+      match float_acc {
+         // a float that overflowed has no literal to print, so the sum stays in the tree
+         Some(acc) if !acc.is_finite() => None,
+         Some(acc) => Some(Float(FloatAst::new(acc))),
+         None => Some(Integer(IntegerAst::new(int_acc)))
+      }
+   }
+
+   fn fold_equal(operands: &[ExprAst]) -> Option<ExprAst> {
+      if operands.len() < 2 || !operands.iter().all(|operand| Folder::is_literal(operand)) {
+         return None;
+      }
+      let last = operands.last().unwrap();
+      Some(Boolean(BooleanAst::new(operands.iter().all(|operand| ::interp::Environment::values_equal(operand, last)))))
+   }
+
+   // stops at the first pair that fails the test, so later operands that could not be compared
+   // do not matter, the same as when the comparison runs
+   fn fold_compare(operands: &[ExprAst], test: |Ordering| -> bool) -> Option<ExprAst> {
+      if operands.len() < 2 {
+         return None;
+      }
+      for pair in operands.windows(2) {
+         let ord = match (&pair[0], &pair[1]) {
+            (&String(ref left), &String(ref right)) => left.string.cmp(&right.string),
+            (&Integer(ref left), &Integer(ref right)) => left.value.cmp(&right.value),
+            (left, right) => match (left.as_f64(), right.as_f64()) {
+               (Some(left), Some(right)) => match left.partial_cmp(&right) {
+                  Some(ord) => ord,
+                  None => return None
+               },
+               _ => return None
+            }
+         };
+         if !test(ord) {
+            return Some(Boolean(BooleanAst::new(false)));
+         }
+      }
+      Some(Boolean(BooleanAst::new(true)))
+   }
+
+   // floats are left alone, since str formats them in the interpreter
+   fn fold_str(operands: &[ExprAst]) -> Option<ExprAst> {
+      let mut result = String::new();
+      for operand in operands.iter() {
+         match *operand {
+            String(ref ast) => result.push_str(ast.string.as_slice()),
+            Integer(ref ast) => result.push_str(ast.value.to_string().as_slice()),
+            Boolean(ref ast) => result.push_str(ast.value.to_string().as_slice()),
+            _ => return None
+         }
+      }
+      Some(String(StringAst::new(result)))
+   }
+
+   fn is_literal(ast: &ExprAst) -> bool {
+      match *ast {
+         Integer(_) | Float(_) | String(_) | Boolean(_) | Nil(_) | Symbol(_) => true,
+         _ => false
+      }
+   }
+}
+
+// finds the names the code binds before anything is folded
+impl Visitor for Folder {
+   fn visit_sexpr(&mut self, sast: &SexprAst) {
+      match sast.op.value.as_slice() {
+         "import" => self.enabled = false,
+         "define" | "set!" | "defstruct" | "defmacro" => match sast.operands.as_slice().get(0) {
+            Some(&Ident(ref ident)) => { self.bound.insert(ident.value.clone()); }
+            _ => {}
+         },
+         "fn" => match sast.operands.as_slice().get(0) {
+            Some(&Array(ref params)) => {
+               for param in params.items.borrow().iter() {
+                  match *param {
+                     Ident(ref ident) => { self.bound.insert(Name::new(ident.value.as_slice().trim_right_chars('.'))); }
+                     Sexpr(ref default) => { self.bound.insert(default.op.value.clone()); }
+                     _ => {}
+                  }
+               }
+            }
+            _ => {}
+         },
+         _ => {}
+      }
+      walk_sexpr(sast, self);
+   }
+
+   fn visit_list(&mut self, _: &ListAst) { }
+}
+
+impl Transformer for Folder {
+   fn transform_sexpr(&mut self, sast: SexprAst) -> ExprAst {
+      self.fold_sexpr(sast)
+   }
+
+   // quoted lists are data, and an array literal may be the parameters of a function, where a
+   // default like (+ 1) is not a call
+   fn transform_list(&mut self, ast: ListAst) -> ExprAst { List(ast) }
+   fn transform_array(&mut self, ast: ArrayAst) -> ExprAst { Array(ast) }
 }
 
 impl Ast for RootAst {
    fn optimize(self) -> Option<ExprAst> {
-      let mut result = RootAst::new();
-      result.asts = self.asts.move_iter().filter_map(|ast| ast.optimize()).collect();
-      Some(Root(result))
+      Some(self.optimize_with(&HashSet::new(), &HashMap::new()))
    }
 
    fn compile(&self) -> Vec<u8> {
@@ -191,65 +1134,62 @@ impl Ast for RootAst {
       result
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut spaces = String::new();
       for _ in range(0, level * INDENTATION) {
          spaces.push_char(' ');
       }
-      println!("{}RootAst {}", spaces, "{");
+      out.push_str(format!("{}RootAst {}\n", spaces, "{").as_slice());
       for ast in self.asts.iter() {
-         ast.dump_level(level + 1);
+         ast.dump_level(out, level + 1, spans);
       }
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl SexprAst {
    pub fn new(op: IdentAst, operands: Vec<ExprAst>) -> SexprAst {
       SexprAst {
+         form: SpecialForm::of(op.value.as_slice()),
+         builtin: None,
          op: op,
-         operands: operands
-      }
-   }
-
-   fn is_math_op(&self) -> bool {
-      match self.op.value.as_slice() {
-         "add" | "sub" | "mul" | "div" => true,
-         _ => false
+         operands: operands,
+         span: Span::none()
       }
    }
 }
 
 impl Ast for SexprAst {
    fn optimize(self) -> Option<ExprAst> {
-      if self.is_math_op() {
-         // TODO: check if ops can be eliminated
-      }
-      Some(Sexpr(self))
+      let mut result = self;
+      // operands that optimize away still need to occupy their slot
+      result.operands = result.operands.move_iter().map(|ast| ast.optimize().unwrap_or(Nil(NilAst::new()))).collect();
+      Some(Sexpr(result))
    }
 
    fn compile(&self) -> Vec<u8> {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut spaces = String::new();
       for _ in range(0, level * INDENTATION) {
          spaces.push_char(' ');
       }
-      println!("{}SexprAst {}", spaces, "{");
-      self.op.dump_level(level + 1);
+      out.push_str(format!("{}SexprAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      self.op.dump_level(out, level + 1, spans);
       for ast in self.operands.iter() {
-         ast.dump_level(level + 1);
+         ast.dump_level(out, level + 1, spans);
       }
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl StringAst {
    pub fn new(value: String) -> StringAst {
       StringAst {
-         string: value
+         string: value,
+         span: Span::none()
       }
    }
 }
@@ -264,7 +1204,7 @@ impl Ast for StringAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut buf = String::new();
       for _ in range(0, INDENTATION) {
          buf.push_char(' ');
@@ -279,16 +1219,57 @@ impl Ast for StringAst {
             }
             buf
          };
-      println!("{}StringAst {}", spaces, "{");
-      println!("{}{}\"{}\"", spaces, indent, self.string);
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}StringAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      out.push_str(format!("{}{}\"{}\"\n", spaces, indent, self.string).as_slice());
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl ListAst {
    pub fn new(items: Vec<ExprAst>) -> ListAst {
+      let mut list = ListAst::empty();
+      for item in items.move_iter().rev() {
+         list = ListAst::cons(item, list);
+      }
+      list
+   }
+
+   pub fn empty() -> ListAst {
+      ListAst {
+         head: None,
+         span: Span::none()
+      }
+   }
+
+   pub fn cons(value: ExprAst, next: ListAst) -> ListAst {
       ListAst {
-         items: items
+         head: Some(Rc::new(ConsCell {
+            value: value,
+            next: next
+         })),
+         span: Span::none()
+      }
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.head.is_none()
+   }
+
+   pub fn iter<'a>(&'a self) -> ListItems<'a> {
+      ListItems {
+         next: &self.head
+      }
+   }
+}
+
+impl<'a> Iterator<&'a ExprAst> for ListItems<'a> {
+   fn next(&mut self) -> Option<&'a ExprAst> {
+      match *self.next {
+         Some(ref cell) => {
+            self.next = &cell.next.head;
+            Some(&cell.value)
+         }
+         None => None
       }
    }
 }
@@ -302,23 +1283,24 @@ impl Ast for ListAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut spaces = String::new();
       for _ in range(0, level * INDENTATION) {
          spaces.push_char(' ');
       }
-      println!("{}ListAst {}", spaces, "{");
-      for item in self.items.iter() {
-         item.dump_level(level + 1);
+      out.push_str(format!("{}ListAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      for item in self.iter() {
+         item.dump_level(out, level + 1, spans);
       }
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl ArrayAst {
    pub fn new(items: Vec<ExprAst>) -> ArrayAst {
       ArrayAst {
-         items: items
+         items: Rc::new(RefCell::new(items)),
+         span: Span::none()
       }
    }
 }
@@ -332,16 +1314,16 @@ impl Ast for ArrayAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut spaces = String::new();
       for _ in range(0, level * INDENTATION) {
          spaces.push_char(' ');
       }
-      println!("{}ArrayAst {}", spaces, "{");
-      for item in self.items.iter() {
-         item.dump_level(level + 1);
+      out.push_str(format!("{}ArrayAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      for item in self.items.borrow().iter() {
+         item.dump_level(out, level + 1, spans);
       }
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
@@ -354,13 +1336,14 @@ impl Ast for PointerAst {
       vec!()
    }
 
-   fn dump_level(&self, _: uint) { }
+   fn dump_level(&self, _: &mut String, _: uint, _: bool) { }
 }
 
 impl IntegerAst {
    pub fn new(num: i64) -> IntegerAst {
       IntegerAst {
-         value: num
+         value: num,
+         span: Span::none()
       }
    }
 }
@@ -374,7 +1357,7 @@ impl Ast for IntegerAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut buf = String::new();
       for _ in range(0, INDENTATION) {
          buf.push_char(' ');
@@ -389,16 +1372,17 @@ impl Ast for IntegerAst {
             }
             buf
          };
-      println!("{}IntegerAst {}", spaces, "{");
-      println!("{}{}{}", spaces, indent, self.value);
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}IntegerAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      out.push_str(format!("{}{}{}\n", spaces, indent, self.value).as_slice());
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl IdentAst {
-   pub fn new(ident: String) -> IdentAst {
+   pub fn new(ident: Name) -> IdentAst {
       IdentAst {
-         value: ident
+         value: ident,
+         span: Span::none()
       }
    }
 }
@@ -412,7 +1396,7 @@ impl Ast for IdentAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut buf = String::new();
       for _ in range(0, INDENTATION) {
          buf.push_char(' ');
@@ -427,16 +1411,17 @@ impl Ast for IdentAst {
             }
             buf
          };
-      println!("{}IdentAst {}", spaces, "{");
-      println!("{}{}{}", spaces, indent, self.value);
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}IdentAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      out.push_str(format!("{}{}{}\n", spaces, indent, self.value).as_slice());
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl SymbolAst {
-   pub fn new(value: String) -> SymbolAst {
+   pub fn new(value: Name) -> SymbolAst {
       SymbolAst {
-         value: value
+         value: value,
+         span: Span::none()
       }
    }
 }
@@ -450,7 +1435,7 @@ impl Ast for SymbolAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut buf = String::new();
       for _ in range(0, INDENTATION) {
          buf.push_char(' ');
@@ -465,16 +1450,17 @@ impl Ast for SymbolAst {
             }
             buf
          };
-      println!("{}SymbolAst {}", spaces, "{");
-      println!("{}{}{}", spaces, indent, self.value);
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}SymbolAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      out.push_str(format!("{}{}{}\n", spaces, indent, self.value).as_slice());
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl FloatAst {
    pub fn new(value: f64) -> FloatAst {
       FloatAst {
-         value: value
+         value: value,
+         span: Span::none()
       }
    }
 }
@@ -488,7 +1474,7 @@ impl Ast for FloatAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut buf = String::new();
       for _ in range(0, INDENTATION) {
          buf.push_char(' ');
@@ -503,16 +1489,17 @@ impl Ast for FloatAst {
             }
             buf
          };
-      println!("{}FloatAst {}", spaces, "{");
-      println!("{}{}{}", spaces, indent, self.value);
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}FloatAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      out.push_str(format!("{}{}{}\n", spaces, indent, self.value).as_slice());
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl BooleanAst {
    pub fn new(value: bool) -> BooleanAst {
       BooleanAst {
-         value: value
+         value: value,
+         span: Span::none()
       }
    }
 }
@@ -526,7 +1513,7 @@ impl Ast for BooleanAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut buf = String::new();
       for _ in range(0, INDENTATION) {
          buf.push_char(' ');
@@ -541,15 +1528,17 @@ impl Ast for BooleanAst {
             }
             buf
          };
-      println!("{}BooleanAst {}", spaces, "{");
-      println!("{}{}{}", spaces, indent, self.value);
-      println!("{}{}", spaces, "}");
+      out.push_str(format!("{}BooleanAst{} {}\n", spaces, self.span.label(spans), "{").as_slice());
+      out.push_str(format!("{}{}{}\n", spaces, indent, self.value).as_slice());
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
    }
 }
 
 impl NilAst {
    pub fn new() -> NilAst {
-      NilAst
+      NilAst {
+         span: Span::none()
+      }
    }
 }
 
@@ -562,71 +1551,227 @@ impl Ast for NilAst {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
       let mut buf = String::new();
       for _ in range(0, level * INDENTATION) {
          buf.push_char(' ');
       }
-      println!("{}NilAst", buf);
+      out.push_str(format!("{}NilAst{}\n", buf, self.span.label(spans)).as_slice());
+   }
+}
+
+impl CodeAst {
+   pub fn new(params: ArrayAst, code: Vec<ExprAst>, env: Rc<RefCell<::interp::Environment>>) -> CodeAst {
+      CodeAst {
+         params: params,
+         code: Rc::new(code),
+         chunk: None,
+         env: env
+      }
+   }
+
+   pub fn with_chunk(params: ArrayAst, code: Rc<Vec<ExprAst>>, chunk: Rc<::bytecode::Chunk>, env: Rc<RefCell<::interp::Environment>>) -> CodeAst {
+      CodeAst {
+         params: params,
+         code: code,
+         chunk: Some(chunk),
+         env: env
+      }
+   }
+
+   // the same function closing over a different environment
+   pub fn with_env(&self, env: Rc<RefCell<::interp::Environment>>) -> CodeAst {
+      CodeAst {
+         params: self.params.clone(),
+         code: self.code.clone(),
+         chunk: self.chunk.clone(),
+         env: env
+      }
+   }
+}
+
+impl Ast for CodeAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Code(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      vec!()
    }
+
+   fn dump_level(&self, _: &mut String, _: uint, _: bool) { }
 }
 
-impl CommentAst {
-   pub fn new(value: String) -> CommentAst {
-      CommentAst {
-         value: value
+impl BuiltinAst {
+   pub fn new(name: String, func: ::interp::BuiltinFn) -> BuiltinAst {
+      BuiltinAst {
+         name: name,
+         func: func
       }
    }
 }
 
-impl Ast for CommentAst {
+impl Ast for BuiltinAst {
    fn optimize(self) -> Option<ExprAst> {
-      Some(Comment(self))
+      Some(Builtin(self))
    }
 
    fn compile(&self) -> Vec<u8> {
       vec!()
    }
 
-   fn dump_level(&self, level: uint) {
-      let mut buf = String::new();
-      for _ in range(0, INDENTATION) {
-         buf.push_char(' ');
+   fn dump_level(&self, _: &mut String, _: uint, _: bool) { }
+}
+
+impl HostAst {
+   pub fn new(name: String, func: ::interp::HostFn) -> HostAst {
+      HostAst {
+         name: name,
+         func: func
       }
-      let indent = buf.clone();
-      let spaces =
-         if level == 0 {
-            "".to_string()
-         } else {
-            for _ in range(0, (level - 1) * INDENTATION) {
-               buf.push_char(' ');
-            }
-            buf
-         };
-      println!("{}CommentAst {}", spaces, "{");
-      println!("{}{}{}", spaces, indent, self.value);
-      println!("{}{}", spaces, "}");
    }
 }
 
-impl CodeAst {
-   pub fn new(params: ArrayAst, code: Vec<ExprAst>, env: Rc<RefCell<::interp::Environment>>) -> CodeAst {
-      CodeAst {
-         params: params,
-         code: code,
-         env: env
+impl Ast for HostAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Host(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      vec!()
+   }
+
+   fn dump_level(&self, _: &mut String, _: uint, _: bool) { }
+}
+
+impl DictAst {
+   pub fn new(entries: HashMap<String, ExprAst>) -> DictAst {
+      DictAst {
+         entries: Rc::new(RefCell::new(entries))
       }
    }
+
+   // hash map iteration order is arbitrary, so anything visible to scripts goes through this
+   pub fn sorted_keys(&self) -> Vec<String> {
+      let mut keys: Vec<String> = self.entries.borrow().keys().map(|key| key.clone()).collect();
+      keys.sort();
+      keys
+   }
 }
 
-impl Ast for CodeAst {
+impl Ast for DictAst {
    fn optimize(self) -> Option<ExprAst> {
-      Some(Code(self))
+      Some(Dict(self))
+   }
+
+   fn compile(&self) -> Vec<u8> {
+      vec!()
+   }
+
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      out.push_str(format!("{}DictAst {}\n", spaces, "{").as_slice());
+      let entries = self.entries.borrow();
+      for key in self.sorted_keys().iter() {
+         out.push_str(format!("{}  \"{}\":\n", spaces, key).as_slice());
+         entries.find(key).unwrap().dump_level(out, level + 1, spans);
+      }
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
+   }
+}
+
+impl StructAst {
+   pub fn new(name: Name, fields: Vec<Name>, values: Vec<ExprAst>) -> StructAst {
+      StructAst {
+         name: name,
+         fields: fields,
+         values: values
+      }
+   }
+}
+
+impl Ast for StructAst {
+   fn optimize(self) -> Option<ExprAst> {
+      Some(Struct(self))
    }
 
    fn compile(&self) -> Vec<u8> {
       vec!()
    }
 
-   fn dump_level(&self, _: uint) { }
+   fn dump_level(&self, out: &mut String, level: uint, spans: bool) {
+      let mut spaces = String::new();
+      for _ in range(0, level * INDENTATION) {
+         spaces.push_char(' ');
+      }
+      out.push_str(format!("{}StructAst {} {}\n", spaces, self.name, "{").as_slice());
+      for (field, value) in self.fields.iter().zip(self.values.iter()) {
+         out.push_str(format!("{}  {}:\n", spaces, field).as_slice());
+         value.dump_level(out, level + 1, spans);
+      }
+      out.push_str(format!("{}{}\n", spaces, "}").as_slice());
+   }
+}
+
+#[cfg(test)]
+mod test {
+   use super::*;
+
+   #[test]
+   fn scalars_round_trip() {
+      assert_eq!(ExprAst::from_i64(-7).as_i64(), Some(-7));
+      assert_eq!(ExprAst::from_f64(2.5).as_f64(), Some(2.5));
+      assert_eq!(ExprAst::from_bool(true).as_bool(), Some(true));
+      assert_eq!(ExprAst::from_str("héllo").as_str(), Some("héllo"));
+      assert!(ExprAst::nil().is_nil());
+      assert_eq!(42i64.to_expr().to::<i64>(), Some(42));
+      assert_eq!("text".to_string().to_expr().to::<String>(), Some("text".to_string()));
+   }
+
+   #[test]
+   fn extractors_do_not_convert_between_types() {
+      assert_eq!(ExprAst::from_str("1").as_i64(), None);
+      assert_eq!(ExprAst::from_f64(1.0).as_i64(), None);
+      assert_eq!(ExprAst::from_i64(1).as_bool(), None);
+      assert_eq!(ExprAst::nil().as_str(), None);
+      assert!(!ExprAst::from_bool(false).is_nil());
+      // the one exception: an integer is also a float
+      assert_eq!(ExprAst::from_i64(3).as_f64(), Some(3.0));
+   }
+
+   #[test]
+   fn arrays_round_trip() {
+      let value = vec!(1i64, 2, 3).to_expr();
+      assert_eq!(value.as_slice_of_asts().map(|items| items.len()), Some(3));
+      assert_eq!(value.to::<Vec<i64>>(), Some(vec!(1, 2, 3)));
+      let nested = vec!(vec!(1.5f64), vec!(), vec!(2.0, 3.0)).to_expr();
+      assert_eq!(nested.to::<Vec<Vec<f64>>>(), Some(vec!(vec!(1.5), vec!(), vec!(2.0, 3.0))));
+      let empty: Vec<bool> = vec!();
+      assert_eq!(empty.to_expr().to::<Vec<bool>>(), Some(vec!()));
+   }
+
+   #[test]
+   fn mixed_arrays_only_extract_as_values() {
+      let mixed = ExprAst::from_vec(vec!(ExprAst::from_i64(1), ExprAst::from_str("two"), ExprAst::nil()));
+      assert_eq!(mixed.to::<Vec<i64>>(), None);
+      assert_eq!(mixed.to::<Vec<String>>(), None);
+      assert_eq!(mixed.to::<Vec<Vec<i64>>>(), None);
+      let items = mixed.to::<Vec<ExprAst>>().unwrap();
+      assert_eq!(items.len(), 3);
+      assert_eq!(items[1].as_str(), Some("two"));
+      assert!(items[2].is_nil());
+      assert_eq!(ExprAst::from_i64(1).to::<Vec<i64>>(), None);
+      assert!(ExprAst::from_i64(1).as_slice_of_asts().is_none());
+   }
+
+   #[test]
+   fn non_finite_floats_print_as_source_that_parses() {
+      for value in [1.0f64 / 0.0, -1.0 / 0.0, 0.0 / 0.0].iter() {
+         let text = ExprAst::from_f64(*value).source();
+         assert!(::parser::Parser::new().parse_code(text.clone()).is_ok(), "{} does not parse", text);
+      }
+   }
 }
@@ -0,0 +1,435 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ast::*;
+use interp::{Interpreter, InterpError, InterpResult, Environment, EnvValue, EnvCode, Value, BuiltinFn, UnknownIdent};
+
+// a flat form of the tree that eval_node walks; anything without a dedicated instruction
+// compiles to the same steps eval_node takes, so both give the same results and errors
+#[deriving(Clone, PartialEq)]
+pub enum Instr {
+   // counts the step for a sexpr or array before any of its operands or items are evaluated
+   Enter(Span),
+   // a literal, which costs a step like any other node
+   Const(ExprAst),
+   // collects the values of that many array items into a new array
+   MakeArray(uint),
+   // an operand that a special form takes unevaluated
+   Push(ExprAst),
+   // the value bound to names[slot]
+   Load(uint, Span),
+   // gives the binding names[slot] already has the value on top of the stack, which stays there
+   Store(uint, Span),
+   Call(CallSite),
+   Guard(GuardSite),
+   Jump(uint),
+   // pops the condition
+   JumpIfFalse(uint),
+   Pop,
+   MakeClosure(Rc<Proto>, Span)
+}
+
+#[deriving(Clone, PartialEq)]
+pub struct CallSite {
+   pub slot: uint,
+   pub nargs: uint,
+   // what the optimizer resolved the operator to, if anything
+   pub builtin: Option<BuiltinFn>,
+   pub span: Span
+}
+
+// the instructions after a guard inline a builtin; if names[slot] is bound to anything else by
+// the time they run, execution goes to target, where the form is compiled as an ordinary call
+#[deriving(Clone, PartialEq)]
+pub struct GuardSite {
+   pub slot: uint,
+   pub func: BuiltinFn,
+   pub builtin: Option<BuiltinFn>,
+   pub target: uint
+}
+
+// what (fn [params] body...) compiles to; every function made from it shares the body and its code
+#[deriving(PartialEq)]
+pub struct Proto {
+   pub params: ArrayAst,
+   pub body: Rc<Vec<ExprAst>>,
+   pub chunk: Rc<Chunk>
+}
+
+#[deriving(PartialEq)]
+pub struct Chunk {
+   pub code: Vec<Instr>,
+   // every name the code loads or calls; instructions refer to them by index
+   pub names: Vec<Name>
+}
+
+struct Compiler {
+   code: Vec<Instr>,
+   names: Vec<Name>,
+   slots: HashMap<Name, uint>
+}
+
+// compiles nodes to code that leaves the value of each of them on the stack, in order
+pub fn compile(nodes: &[ExprAst]) -> Chunk {
+   let mut compiler = Compiler {
+      code: vec!(),
+      names: vec!(),
+      slots: HashMap::new()
+   };
+   for node in nodes.iter() {
+      compiler.node(node);
+   }
+   Chunk {
+      code: compiler.code,
+      names: compiler.names
+   }
+}
+
+impl Compiler {
+   fn emit(&mut self, instr: Instr) -> uint {
+      self.code.push(instr);
+      self.code.len() - 1
+   }
+
+   // points the jump at idx to the next instruction to be emitted
+   fn patch(&mut self, idx: uint) {
+      let target = self.code.len();
+      match *self.code.get_mut(idx) {
+         Guard(ref mut guard) => guard.target = target,
+         Jump(ref mut dest) | JumpIfFalse(ref mut dest) => *dest = target,
+         _ => unreachable!()
+      }
+   }
+
+   fn slot(&mut self, name: &Name) -> uint {
+      match self.slots.find(name) {
+         Some(&slot) => return slot,
+         None => {}
+      }
+      let slot = self.names.len();
+      self.names.push(name.clone());
+      self.slots.insert(name.clone(), slot);
+      slot
+   }
+
+   fn node(&mut self, node: &ExprAst) {
+      match *node {
+         Sexpr(ref sast) => self.sexpr(sast),
+         Ident(ref ast) => {
+            let slot = self.slot(&ast.value);
+            self.emit(Load(slot, ast.span.clone()));
+         }
+         Array(ref ast) => {
+            self.emit(Enter(ast.span.clone()));
+            let items = ast.items.borrow();
+            for item in items.iter() {
+               self.node(item);
+            }
+            self.emit(MakeArray(items.len()));
+         }
+         List(ref ast) => {
+            self.emit(Const(Environment::list_value(ast)));
+         }
+         _ => {
+            self.emit(Const(node.clone()));
+         }
+      }
+   }
+
+   fn sexpr(&mut self, sast: &SexprAst) {
+      let ops = sast.operands.len();
+      let params = ops > 0 && match sast.operands[0] {
+         Array(_) => true,
+         _ => false
+      };
+      let named = ops > 0 && match sast.operands[0] {
+         Ident(_) => true,
+         _ => false
+      };
+      match (sast.form.clone(), sast.op.value.as_slice()) {
+         (IfForm, _) if ops == 2 || ops == 3 => self.guarded(sast, Environment::ifexpr),
+         (LazyForm, "while") if ops > 0 => self.guarded(sast, Environment::whileexpr),
+         (LazyForm, "fn") if params => self.guarded(sast, Environment::function),
+         (DefineForm, "set!") if ops == 2 && named => self.guarded(sast, Environment::assign),
+         _ => self.call(sast)
+      }
+   }
+
+   fn guarded(&mut self, sast: &SexprAst, func: BuiltinFn) {
+      let slot = self.slot(&sast.op.value);
+      let guard = self.emit(Guard(GuardSite {
+         slot: slot,
+         func: func,
+         builtin: sast.builtin,
+         target: 0
+      }));
+      self.emit(Enter(sast.span.clone()));
+      match sast.op.value.as_slice() {
+         "if" => self.if_form(sast),
+         "while" => self.while_form(sast),
+         "set!" => self.set_form(sast),
+         _ => self.fn_form(sast)
+      }
+      let end = self.emit(Jump(0));
+      self.patch(guard);
+      self.call(sast);
+      self.patch(end);
+   }
+
+   fn if_form(&mut self, sast: &SexprAst) {
+      self.node(&sast.operands[0]);
+      let skip = self.emit(JumpIfFalse(0));
+      self.node(&sast.operands[1]);
+      let end = self.emit(Jump(0));
+      self.patch(skip);
+      if sast.operands.len() == 3 {
+         self.node(&sast.operands[2]);
+      } else {
+         // there is no else branch to give a value
+         self.emit(Push(Nil(NilAst::new())));
+      }
+      self.patch(end);
+   }
+
+   // the result stays on the stack, replaced by each body node in turn, so it ends up as the value
+   // of the last one evaluated or nil if the body never ran
+   fn while_form(&mut self, sast: &SexprAst) {
+      self.emit(Push(Nil(NilAst::new())));
+      let start = self.code.len();
+      self.node(&sast.operands[0]);
+      let exit = self.emit(JumpIfFalse(0));
+      for node in sast.operands.slice_from(1).iter() {
+         self.emit(Pop);
+         self.node(node);
+      }
+      self.emit(Jump(start));
+      self.patch(exit);
+   }
+
+   fn set_form(&mut self, sast: &SexprAst) {
+      let slot = match sast.operands[0] {
+         Ident(ref ast) => self.slot(&ast.value),
+         _ => unreachable!()
+      };
+      self.node(&sast.operands[1]);
+      self.emit(Store(slot, sast.span.clone()));
+   }
+
+   fn fn_form(&mut self, sast: &SexprAst) {
+      let params = match sast.operands[0] {
+         Array(ref ast) => ast.clone(),
+         _ => unreachable!()
+      };
+      let body = sast.operands.slice_from(1).to_vec();
+      let chunk = compile(body.as_slice());
+      self.emit(MakeClosure(Rc::new(Proto {
+         params: params,
+         body: Rc::new(body),
+         chunk: Rc::new(chunk)
+      }), sast.span.clone()));
+   }
+
+   // the steps eval_node takes for a sexpr: special forms keep some operands unevaluated, and
+   // then whatever the operator names is called
+   fn call(&mut self, sast: &SexprAst) {
+      let slot = self.slot(&sast.op.value);
+      self.emit(Enter(sast.span.clone()));
+      match sast.form {
+         LazyForm => for node in sast.operands.iter() {
+            self.emit(Push(node.clone()));
+         },
+         IfForm => for (i, node) in sast.operands.iter().enumerate() {
+            if i == 0 {
+               self.node(node);
+            } else {
+               self.emit(Push(node.clone()));
+            }
+         },
+         ImportForm => {
+            // the name in (import "mod" as m) is not evaluated
+            let mut naming = false;
+            for node in sast.operands.iter() {
+               match *node {
+                  Ident(ref ast) if naming || ast.value.as_slice() == "as" => {
+                     naming = !naming;
+                     self.emit(Push(node.clone()));
+                  }
+                  _ => self.node(node)
+               }
+            }
+         }
+         DefineForm => for (i, node) in sast.operands.iter().enumerate() {
+            if i == 0 {
+               self.emit(Push(node.clone()));
+            } else {
+               self.node(node);
+            }
+         },
+         CallForm => for node in sast.operands.iter() {
+            self.node(node);
+         }
+      }
+      self.emit(Call(CallSite {
+         slot: slot,
+         nargs: sast.operands.len(),
+         builtin: sast.builtin,
+         span: sast.span.clone()
+      }));
+   }
+}
+
+struct Frame<'a> {
+   env: Rc<RefCell<Environment>>,
+   names: &'a [Name],
+   // where each name was found, filled in on first use
+   slots: Vec<Option<Rc<RefCell<EnvValue>>>>,
+   // the version of the scopes from env up when the slots were filled in; a new binding in any
+   // of them may hide a name found further up
+   version: uint
+}
+
+impl<'a> Frame<'a> {
+   fn lookup(&mut self, slot: uint) -> Option<Rc<RefCell<EnvValue>>> {
+      let version = chain_version(&self.env);
+      if version != self.version {
+         for cached in self.slots.mut_iter() {
+            *cached = None;
+         }
+         self.version = version;
+      }
+      if self.slots[slot].is_none() {
+         *self.slots.get_mut(slot) = self.env.borrow().find_slot(&self.names[slot]);
+      }
+      self.slots[slot].clone()
+   }
+
+   // whether a guarded form's operator still names the builtin its inline code stands for
+   fn holds(&mut self, guard: &GuardSite) -> bool {
+      let names = self.names;
+      match Interpreter::cached_builtin(&self.env, &names[guard.slot], guard.builtin) {
+         Some(found) => found == guard.func,
+         None => match self.lookup(guard.slot) {
+            Some(found) => match value_of(&found) {
+               EnvCode(found) => found == guard.func,
+               _ => false
+            },
+            None => false
+         }
+      }
+   }
+
+   fn spend_fuel(&self, span: Span) -> InterpResult<()> {
+      Interpreter::spend_fuel(&self.env).map_err(|f| f.at(span))
+   }
+}
+
+// every scope's version only ever goes up, so the sum changes whenever any of them binds a name
+fn chain_version(env: &Rc<RefCell<Environment>>) -> uint {
+   let mut version = 0;
+   let mut current = env.clone();
+   loop {
+      version += current.borrow().version;
+      let parent = current.borrow().parent.clone();
+      match parent {
+         Some(parent) => current = parent,
+         None => return version
+      }
+   }
+}
+
+fn value_of(slot: &Rc<RefCell<EnvValue>>) -> EnvValue {
+   let value = slot.borrow().clone();
+   value
+}
+
+// runs chunk in env; errors get the span of the innermost node that failed, as in execute_node
+pub fn run(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, chunk: &Chunk) -> InterpResult<()> {
+   let version = chain_version(&env);
+   let mut frame = Frame {
+      env: env,
+      names: chunk.names.as_slice(),
+      slots: Vec::from_elem(chunk.names.len(), None),
+      version: version
+   };
+   let mut pc = 0;
+   while pc < chunk.code.len() {
+      match chunk.code[pc] {
+         Enter(ref span) => try!(frame.spend_fuel(span.clone())),
+         Const(ref value) => {
+            try!(frame.spend_fuel(value.span()));
+            stack.push(value.clone());
+         }
+         MakeArray(len) => {
+            let items = Environment::take_args(stack, len);
+            stack.push(Array(ArrayAst::new(items)));
+         }
+         Push(ref value) => stack.push(value.clone()),
+         Load(slot, ref span) => {
+            try!(frame.spend_fuel(span.clone()));
+            let value = match frame.lookup(slot) {
+               Some(found) => match value_of(&found) {
+                  Value(value) => value,
+                  EnvCode(func) => Builtin(BuiltinAst::new(frame.names[slot].to_string(), func))
+               },
+               None => {
+                  let name = &frame.names[slot];
+                  let message = format!("ident {} not declared{}", name, frame.env.borrow().suggestion(name.as_slice()));
+                  return Err(InterpError::new(UnknownIdent, message).at(span.clone()));
+               }
+            };
+            stack.push(value);
+         }
+         Store(slot, ref span) => {
+            let value = stack.pop().unwrap();
+            match frame.lookup(slot) {
+               Some(found) => frame.env.borrow().assign_slot(&frame.names[slot], &found, Value(value.clone())),
+               None => {
+                  let message = format!("cannot set! undeclared variable {}", frame.names[slot]);
+                  return Err(InterpError::new(UnknownIdent, message).at(span.clone()));
+               }
+            }
+            stack.push(value);
+         }
+         Call(ref site) => try!(call(&mut frame, stack, site)),
+         Guard(ref guard) => if !frame.holds(guard) {
+            pc = guard.target;
+            continue;
+         },
+         Jump(target) => {
+            pc = target;
+            continue;
+         }
+         JumpIfFalse(target) => if !Environment::truthy(&stack.pop().unwrap()) {
+            pc = target;
+            continue;
+         },
+         Pop => {
+            stack.pop();
+         }
+         MakeClosure(ref proto, ref span) => {
+            try!(Environment::check_params(&proto.params).map_err(|f| f.at(span.clone())));
+            let code = CodeAst::with_chunk(proto.params.clone(), proto.body.clone(), proto.chunk.clone(), frame.env.clone());
+            stack.push(super::ast::Code(code));
+         }
+      }
+      pc += 1;
+   }
+   Ok(())
+}
+
+fn call(frame: &mut Frame, stack: &mut Vec<ExprAst>, site: &CallSite) -> InterpResult<()> {
+   let names = frame.names;
+   let name = &names[site.slot];
+   let thing = match Interpreter::cached_builtin(&frame.env, name, site.builtin) {
+      Some(func) => EnvCode(func),
+      None => match frame.lookup(site.slot) {
+         Some(found) => value_of(&found),
+         None => {
+            let message = format!("unknown function {}{}", name, frame.env.borrow().suggestion(name.as_slice()));
+            return Err(InterpError::new(UnknownIdent, message).at(site.span.clone()));
+         }
+      }
+   };
+   Interpreter::apply(frame.env.clone(), stack, name, thing, site.nargs).map_err(|f| f.at(site.span.clone()))
+}
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+
+use ast::*;
+use interp::{InterpError, UnknownIdent, ArityError, ValueError, UNLIMITED};
+
+// a mistake found without running the code, reported just like the error it would cause
+pub type Diagnostic = InterpError;
+
+// known holds the names bound before the code runs, i.e. the builtins and the prelude, and
+// arities how many operands each builtin takes
+pub fn check(root: &RootAst, known: HashSet<Name>, arities: HashMap<Name, (uint, uint)>) -> Vec<Diagnostic> {
+   let mut binder = Binder {
+      bound: HashSet::new(),
+      macros: HashSet::new(),
+      imports: false
+   };
+   walk_root(root, &mut binder);
+   let mut checker = Checker {
+      known: known,
+      arities: arities,
+      bound: binder.bound,
+      macros: binder.macros,
+      imports: binder.imports,
+      scopes: vec!(),
+      diagnostics: vec!()
+   };
+   walk_root(root, &mut checker);
+   checker.diagnostics
+}
+
+// every name the code binds anywhere; a function may well use a name defined after it, so the
+// order they are bound in is not checked
+struct Binder {
+   bound: HashSet<Name>,
+   macros: HashSet<Name>,
+   // an import could bind anything
+   imports: bool
+}
+
+impl Visitor for Binder {
+   fn visit_sexpr(&mut self, sast: &SexprAst) {
+      let operands = sast.operands.as_slice();
+      match sast.op.value.as_slice() {
+         "import" => self.imports = true,
+         "define" => match operands.get(0) {
+            Some(&Ident(ref ident)) => { self.bound.insert(ident.value.clone()); }
+            _ => {}
+         },
+         "defmacro" => match operands.get(0) {
+            Some(&Ident(ref ident)) => { self.macros.insert(ident.value.clone()); }
+            _ => {}
+         },
+         "defstruct" => match (operands.get(0), operands.get(1)) {
+            (Some(&Ident(ref ident)), Some(&Array(ref fields))) => {
+               let name = ident.value.as_slice();
+               self.bound.insert(ident.value.clone());
+               self.bound.insert(Name::new(format!("{}?", name).as_slice()));
+               for field in fields.items.borrow().iter() {
+                  match *field {
+                     Ident(ref field) => { self.bound.insert(Name::new(format!("{}-{}", name, field.value).as_slice())); }
+                     _ => {}
+                  }
+               }
+            }
+            _ => {}
+         },
+         _ => {}
+      }
+      walk_sexpr(sast, self);
+   }
+
+   fn visit_list(&mut self, _: &ListAst) { }
+}
+
+struct Checker {
+   known: HashSet<Name>,
+   arities: HashMap<Name, (uint, uint)>,
+   bound: HashSet<Name>,
+   // what a macro's operands mean is up to the macro
+   macros: HashSet<Name>,
+   imports: bool,
+   // the parameters of each function the walk is inside
+   scopes: Vec<HashSet<Name>>,
+   diagnostics: Vec<Diagnostic>
+}
+
+impl Checker {
+   fn is_param(&self, name: &Name) -> bool {
+      self.scopes.iter().any(|scope| scope.contains(name))
+   }
+
+   fn reference(&mut self, ident: &IdentAst) {
+      let name = &ident.value;
+      if self.imports || self.is_param(name) || self.bound.contains(name) || self.known.contains(name) {
+         return;
+      }
+      let err = InterpError::new(UnknownIdent, format!("ident {} not declared", name));
+      self.diagnostics.push(err.at(ident.span.clone()));
+   }
+
+   // only for builtins the code has not rebound, since anything else could take any number
+   fn arity(&mut self, sast: &SexprAst) {
+      let name = &sast.op.value;
+      if self.is_param(name) || self.bound.contains(name) || !self.known.contains(name) {
+         return;
+      }
+      let nargs = sast.operands.len();
+      match self.arities.find(name) {
+         Some(&(min, max)) if nargs < min || nargs > max => {
+            let expected =
+               if min == max {
+                  min.to_string()
+               } else if max == UNLIMITED {
+                  format!("at least {}", min)
+               } else {
+                  format!("{} to {}", min, max)
+               };
+            let err = InterpError::new(ArityError, format!("{} expects {} arguments but was given {}", name, expected, nargs));
+            self.diagnostics.push(err.at(sast.span.clone()));
+         }
+         _ => {}
+      }
+   }
+
+   // binds a function's parameters in a new scope, checking each default as it goes, since a
+   // default can use the parameters before it
+   fn enter(&mut self, params: &ArrayAst) {
+      self.scopes.push(HashSet::new());
+      for param in params.items.borrow().iter() {
+         let ident = match *param {
+            Ident(ref ident) => ident.clone(),
+            Sexpr(ref default) => {
+               for operand in default.operands.iter() {
+                  walk_expr(operand, self);
+               }
+               default.op.clone()
+            }
+            _ => continue
+         };
+         let name = Name::new(ident.value.as_slice().trim_right_chars('.'));
+         if !self.scopes.mut_last().unwrap().insert(name.clone()) {
+            let err = InterpError::new(ValueError, format!("parameter {} appears more than once", name));
+            self.diagnostics.push(err.at(ident.span.clone()));
+         }
+      }
+   }
+}
+
+impl Visitor for Checker {
+   fn visit_sexpr(&mut self, sast: &SexprAst) {
+      if self.macros.contains(&sast.op.value) {
+         return;
+      }
+      self.arity(sast);
+      let operands = sast.operands.as_slice();
+      match (sast.op.value.as_slice(), operands.get(0)) {
+         // data rather than code
+         ("quote", _) | ("import", _) | ("defstruct", _) => return,
+         // the name is being bound, not looked up
+         ("define", Some(&Ident(_))) => {
+            for operand in operands.slice_from(1).iter() {
+               walk_expr(operand, self);
+            }
+            return;
+         }
+         ("defmacro", Some(&Ident(_))) => match operands.get(1) {
+            Some(&Array(ref params)) => {
+               self.enter(params);
+               for expr in operands.slice_from(2).iter() {
+                  walk_expr(expr, self);
+               }
+               self.scopes.pop();
+               return;
+            }
+            _ => {}
+         },
+         ("fn", Some(&Array(ref params))) => {
+            self.enter(params);
+            for expr in operands.slice_from(1).iter() {
+               walk_expr(expr, self);
+            }
+            self.scopes.pop();
+            return;
+         }
+         _ => {}
+      }
+      self.reference(&sast.op);
+      for operand in sast.operands.iter() {
+         walk_expr(operand, self);
+      }
+   }
+
+   fn visit_list(&mut self, _: &ListAst) { }
+
+   fn visit_ident(&mut self, ident: &IdentAst) {
+      self.reference(ident);
+   }
+}
@@ -1,525 +1,4823 @@
 #![allow(raw_pointer_deriving)]
 
 use std::cell::RefCell;
+use std::cmp::{Ordering, Less, Equal, Greater};
+use std::cmp;
 use std::collections;
 use std::f64;
+use std::fmt;
 use std::io;
+use std::io::process;
+use std::io::timer;
+use std::os;
 use std::rc::Rc;
 
-use parser::Parser;
+use regex::Regex;
+use time;
+
+use parser::{Parser, ParseError};
 use ast::*;
+use bytecode;
+use check;
+use check::Diagnostic;
+use json;
+
+static PRELUDE: &'static str = include_str!("prelude.irl");
+
+// room on the evaluation stack before it has to grow, which is plenty for most scripts
+static STACK_SIZE: uint = 256;
+
+// how many expansions deep a macro may go before it is taken to be expanding forever
+static MACRO_DEPTH: uint = 64;
 
-#[deriving(PartialEq)]
+// the most operands a builtin that takes any number of them is registered with
+pub static UNLIMITED: uint = ::std::uint::MAX;
+
+macro_rules! throw (
+   ($kind:expr, $($arg:tt)*) => (
+      return Err(InterpError::new($kind, format!($($arg)*)))
+   )
+)
+
+#[deriving(Clone, PartialEq)]
 pub enum InterpMode {
    Debug,
    Release
 }
 
+// how top-level forms are run: by walking their trees, or compiled to bytecode first
 #[deriving(Clone, PartialEq)]
-enum EnvValue {
-   EnvCode(fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst),
-   Value(ExprAst)
+pub enum Backend {
+   TreeWalker,
+   Bytecode
 }
 
-impl PartialEq for fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-   fn eq(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst) -> bool {
-      let other: *const () = unsafe { ::std::mem::transmute(other) };
-      let this: *const () = unsafe { ::std::mem::transmute(self) };
-      this == other
-   }
-
-   fn ne(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst) -> bool {
-      !self.eq(other)
-   }
+// how dump_ast shows the tree: indented with a line per node, as iron source that parses back
+// to the same tree, or as the number of nodes of each kind
+#[deriving(Clone, PartialEq)]
+pub enum AstFormat {
+   TreeFormat,
+   SexprFormat,
+   CountsFormat
 }
 
-pub struct Interpreter {
-   mode: InterpMode,
-   parser: Parser,
-   pub env: Rc<RefCell<Environment>>,
-   stack: Vec<ExprAst>
+#[deriving(Clone, PartialEq, Show)]
+pub enum ErrorKind {
+   TypeError,
+   ArityError,
+   UnknownIdent,
+   IndexError,
+   ValueError,
+   IoError,
+   ImportError,
+   AssertionError,
+   SyntaxError,
+   LimitError,
+   // not a mistake: (exit n) unwinds back to execute the same way errors do
+   Exit(int)
 }
 
+// what a script did wrong, handed back to whoever is running the interpreter
 #[deriving(Clone, PartialEq)]
-pub struct Environment {
-   pub parent: Option<Rc<RefCell<Environment>>>,
-   pub values: collections::HashMap<String, EnvValue>
+pub struct InterpError {
+   pub kind: ErrorKind,
+   pub message: String,
+   pub span: Span,
+   pub file: Option<String>
 }
 
-impl Interpreter {
-   pub fn new() -> Interpreter {
-      let mut env = Environment::new(None);
-      env.populate_default();
-      Interpreter {
-         parser: Parser::new(),
-         mode: Release,
-         env: Rc::new(RefCell::new(env)),
-         stack: vec!()
+impl InterpError {
+   pub fn new(kind: ErrorKind, message: String) -> InterpError {
+      InterpError {
+         kind: kind,
+         message: message,
+         span: Span::none(),
+         file: None
       }
    }
 
-   pub fn set_mode(&mut self, mode: InterpMode) {
-      self.mode = mode;
+   pub fn from_parse(err: ParseError) -> InterpError {
+      let mut result = InterpError::new(SyntaxError, format!("{}{}", err.desc, err.context()));
+      result.span = Span::new(err.line, err.column, 0);
+      result
    }
 
-   pub fn set_file(&mut self, file: String) {
-      self.env.clone().borrow_mut().values.insert("FILE".to_string(), Value(String(StringAst::new(file))));
+   // the innermost node that failed knows best where the problem is, so later calls keep it
+   pub fn at(mut self, span: Span) -> InterpError {
+      if !self.span.is_known() {
+         self.span = span;
+      }
+      self
    }
 
-   pub fn load_code(&mut self, code: String) {
-      self.parser.load_code(code);
+   pub fn in_file(mut self, file: String) -> InterpError {
+      if self.file.is_none() && file.len() > 0 {
+         self.file = Some(file);
+      }
+      self
    }
 
-   pub fn execute(&mut self) -> int {
-      debug!("execute");
-      let mut root: RootAst = match self.parser.parse() { Root(ast) => ast, _ => unreachable!() };
-      if self.mode != Debug {
-         root = match root.optimize().unwrap() { Root(ast) => ast, _ => unreachable!() };
+   pub fn kind_name(&self) -> &'static str {
+      match self.kind {
+         TypeError => "type error",
+         ArityError => "arity error",
+         UnknownIdent => "unknown identifier",
+         IndexError => "index error",
+         ValueError => "value error",
+         IoError => "io error",
+         ImportError => "import error",
+         AssertionError => "assertion error",
+         SyntaxError => "syntax error",
+         LimitError => "limit error",
+         Exit(_) => "exit"
       }
-      for ast in root.asts.iter() {
-         Interpreter::execute_node(self.env.clone(), &mut self.stack, ast);
-         self.stack.clear();
-      }
-      0 // exit status
    }
+}
 
-   pub fn execute_node(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, node: &ExprAst) {
-      debug!("execute_node");
-      let stacklen = stack.len();
-      match *node {
-         Sexpr(ref sast) => {
-            let val: &str = sast.op.value.as_slice();
-            match val {
-               "fn" => {
-                  for subast in sast.operands.iter() {
-                     stack.push(subast.clone());
-                  }
-               }
-               "if" => {
-                  if sast.operands.len() > 0 {
-                     Interpreter::execute_node(env.clone(), stack, &sast.operands[0]);
-                  }
-                  for subast in sast.operands.slice_from(1).iter() {
-                     stack.push(subast.clone());
-                  }
-               }
-               "define" | "set" => {
-                  if sast.operands.len() > 0 {
-                     stack.push(sast.operands[0].clone());
-                     for subast in sast.operands.slice_from(1).iter() {
-                        Interpreter::execute_node(env.clone(), stack, subast);
-                     }
-                  }
-               }
-               _ => {
-                  for subast in sast.operands.iter() {
-                     Interpreter::execute_node(env.clone(), stack, subast);
-                  }
-               }
-            };
-            let thing = match env.borrow().find(&sast.op.value) {
-               Some(thing) => thing,
-               None => fail!("Could not find key")  // XXX: also fix
-            };
-            match thing {
-               EnvCode(thunk) => {
-                  debug!("executing thunk...");
-                  let val = thunk(env, stack as *mut Vec<ExprAst>, sast.operands.len());
-                  stack.push(val);
-               }
-               Value(ast) => match ast {
-                  super::ast::Code(ast) => {
-                     debug!("evaluating code...");
-                     let mut count = 0;
-                     let mut subenv = Environment::new(Some(ast.env.clone()));
-                     let mut len = sast.operands.len();
-                     if len > ast.params.items.len() {
-                        for _ in range(0, len - ast.params.items.len()) {
-                           stack.pop();
-                        }
-                        len = ast.params.items.len();
-                     }
-                     let idx = stack.len() - len;
-                     debug!("begin params");
-                     for param in ast.params.items.iter() {
-                        match *param {
-                           Ident(ref idast) => {
-                              debug!("\t{}", idast.value);
-                              let slice = idast.value.as_slice();
-                              if slice.ends_with("...") {
-                                 let vec = Vec::from_fn(len - count, |_| stack.remove(idx).unwrap());
-                                 subenv.values.insert(slice.slice_to(slice.len() - 3).to_string(),
-                                                      Value(Array(ArrayAst::new(vec))));
-                              } else {
-                                 subenv.values.insert(idast.value.clone(), Value(stack.remove(idx).unwrap()));
-                              }
-                           }
-                           _ => fail!() // XXX: fix
-                        };
-                        count += 1;
-                     }
-                     debug!("end params");
-                     let subenv = Rc::new(RefCell::new(subenv));
-                     for subast in ast.code.iter() {
-                        Interpreter::execute_node(subenv.clone(), stack, subast);
-                     }
-                  }
-                  _ => fail!("Not executable")  // XXX: fix
-               }
-            };
+impl fmt::Show for InterpError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      if !self.span.is_known() {
+         write!(f, "{}: {}", self.kind_name(), self.message)
+      } else {
+         match self.file {
+            Some(ref file) => write!(f, "{} at {}:{}:{}: {}", self.kind_name(), file, self.span.line, self.span.column, self.message),
+            None => write!(f, "{} at {}:{}: {}", self.kind_name(), self.span.line, self.span.column, self.message)
          }
-         Ident(ref ast) => match env.borrow().find(&ast.value) {
-            Some(val) => match val {
-               Value(ref val) => stack.push(val.clone()),
-               EnvCode(_) => fail!()  // TODO: this should not actually fail
-            },
-            None => fail!("ident {} not declared", ast.value)
-         },
-         ref other => stack.push(other.clone())  // XXX: probably can be fixed
       }
-      for _ in range(stacklen + 1, stack.len()) {
-         let len = stack.len();
-         stack.remove(len - 1);
+   }
+}
+
+pub type InterpResult<T> = Result<T, InterpError>;
+
+pub type BuiltinFn = fn(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst>;
+
+#[deriving(Clone, PartialEq)]
+pub enum EnvValue {
+   EnvCode(BuiltinFn),
+   Value(ExprAst)
+}
+
+impl PartialEq for fn(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+   fn eq(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst>) -> bool {
+      *self as uint == *other as uint
+   }
+
+   fn ne(&self, other: &fn(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst>) -> bool {
+      !self.eq(other)
+   }
+}
+
+// what a function registered with Interpreter::register_builtin is called with
+pub struct CallContext<'a> {
+   pub name: &'a str,
+   // already evaluated, in the order they were written
+   pub args: &'a [ExprAst],
+   pub env: Rc<RefCell<Environment>>
+}
+
+pub trait HostFunction {
+   fn call(&self, cx: &mut CallContext) -> InterpResult<ExprAst>;
+}
+
+impl HostFunction for fn(&mut CallContext) -> InterpResult<ExprAst> {
+   fn call(&self, cx: &mut CallContext) -> InterpResult<ExprAst> {
+      (*self)(cx)
+   }
+}
+
+pub struct HostFn {
+   pub func: Rc<Box<HostFunction>>
+}
+
+impl HostFn {
+   pub fn new(func: Box<HostFunction>) -> HostFn {
+      HostFn {
+         func: Rc::new(func)
+      }
+   }
+}
+
+impl Clone for HostFn {
+   fn clone(&self) -> HostFn {
+      HostFn {
+         func: self.func.clone()
       }
    }
+}
 
-   pub fn dump_ast(&mut self) {
-      self.parser.parse().dump();
+impl PartialEq for HostFn {
+   fn eq(&self, other: &HostFn) -> bool {
+      let this: *const Box<HostFunction> = &*self.func;
+      let other: *const Box<HostFunction> = &*other.func;
+      this == other
    }
 }
 
-impl Environment {
-   pub fn new(parent: Option<Rc<RefCell<Environment>>>) -> Environment {
-      Environment {
-         parent: parent,
-         values: collections::HashMap::new()
+// a copy of every scope reachable from the script, taken by Interpreter::snapshot
+pub struct EnvSnapshot {
+   env: Rc<RefCell<Environment>>
+}
+
+// copies a graph of environments and the values in them, keeping whatever was shared in the
+// original (slots shared by closures and imports, arrays bound to two names, a function stored
+// in the scope it closes over) shared in the copy
+struct EnvCopier {
+   envs: collections::HashMap<uint, Rc<RefCell<Environment>>>,
+   slots: collections::HashMap<uint, Rc<RefCell<EnvValue>>>,
+   arrays: collections::HashMap<uint, ArrayAst>,
+   dicts: collections::HashMap<uint, DictAst>
+}
+
+impl EnvCopier {
+   fn new() -> EnvCopier {
+      EnvCopier {
+         envs: collections::HashMap::new(),
+         slots: collections::HashMap::new(),
+         arrays: collections::HashMap::new(),
+         dicts: collections::HashMap::new()
       }
    }
 
-   pub fn find(&self, key: &String) -> Option<EnvValue> {
-      match self.values.find(key) {
-         Some(m) => Some(m.clone()),
-         None => match self.parent.clone() {
-            Some(env) => (*env).clone().unwrap().find(key),
-            None => None
-         }
+   fn copy_env(&mut self, env: &Rc<RefCell<Environment>>) -> Rc<RefCell<Environment>> {
+      let key = &**env as *const RefCell<Environment> as uint;
+      match self.envs.find(&key) {
+         Some(copy) => return copy.clone(),
+         None => {}
+      }
+      let parent = env.borrow().parent.clone().map(|parent| self.copy_env(&parent));
+      let copy = Rc::new(RefCell::new(Environment {
+         parent: parent,
+         values: collections::HashMap::new(),
+         context: env.borrow().context.clone(),
+         version: 0
+      }));
+      // registered before the values are copied, since functions in it usually close over it
+      self.envs.insert(key, copy.clone());
+      let slots: Vec<(Name, Rc<RefCell<EnvValue>>)> = env.borrow().values.iter().map(|(name, slot)| (name.clone(), slot.clone())).collect();
+      for (name, slot) in slots.move_iter() {
+         let slot = self.copy_slot(&slot);
+         copy.borrow_mut().values.insert(name, slot);
       }
+      copy
    }
 
-   pub fn replace(&mut self, key: String, value: EnvValue) -> bool {
-      if self.values.contains_key(&key) {
-         self.values.insert(key, value);
-         true
-      } else {
-         match self.parent {
-            Some(ref env) => env.borrow_mut().replace(key, value),
-            None => false
-         }
+   fn copy_slot(&mut self, slot: &Rc<RefCell<EnvValue>>) -> Rc<RefCell<EnvValue>> {
+      let key = &**slot as *const RefCell<EnvValue> as uint;
+      match self.slots.find(&key) {
+         Some(copy) => return copy.clone(),
+         None => {}
       }
+      let copy = Rc::new(RefCell::new(Value(Nil(NilAst::new()))));
+      self.slots.insert(key, copy.clone());
+      let value = slot.borrow().clone();
+      *copy.borrow_mut() = match value {
+         Value(value) => Value(self.copy_value(&value)),
+         other => other
+      };
+      copy
    }
 
-   pub fn populate_default(&mut self) {
-      self.values.insert("FILE".to_string(), Value(String(StringAst::new("".to_string()))));
-      self.values.insert("+".to_string(), EnvCode(Environment::add));
-      self.values.insert("=".to_string(), EnvCode(Environment::equal));
-      self.values.insert("print".to_string(), EnvCode(Environment::print));
-      self.values.insert("if".to_string(), EnvCode(Environment::ifexpr));
-      self.values.insert("define".to_string(), EnvCode(Environment::define));
-      self.values.insert("fn".to_string(), EnvCode(Environment::function));
-      self.values.insert("get".to_string(), EnvCode(Environment::get));
-      self.values.insert("set".to_string(), EnvCode(Environment::set));
-      self.values.insert("len".to_string(), EnvCode(Environment::len));
-      self.values.insert("import".to_string(), EnvCode(Environment::importexpr));
-      self.values.insert("type".to_string(), EnvCode(Environment::type_obj));
-   }
-
-   fn add(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("add");
-      let mut ops = ops;
-      let mut val = 0f64;
-      let mut decimal = false;
-      while ops > 0 {
-         match unsafe { (*stack).pop() }.unwrap() {
-            Integer(ref ast) => {
-               val += ast.value as f64;
+   fn copy_value(&mut self, value: &ExprAst) -> ExprAst {
+      match *value {
+         super::ast::Code(ref ast) => super::ast::Code(ast.with_env(self.copy_env(&ast.env))),
+         Array(ref ast) => {
+            let key = &*ast.items as *const RefCell<Vec<ExprAst>> as uint;
+            match self.arrays.find(&key) {
+               Some(copy) => return Array(copy.clone()),
+               None => {}
             }
-            Float(ref ast) => {
-               decimal = true;
-               val += ast.value;
+            let copy = ArrayAst::new(vec!());
+            self.arrays.insert(key, copy.clone());
+            let items = ast.items.borrow().clone();
+            let items: Vec<ExprAst> = items.iter().map(|item| self.copy_value(item)).collect();
+            *copy.items.borrow_mut() = items;
+            Array(copy)
+         }
+         Dict(ref ast) => {
+            let key = &*ast.entries as *const RefCell<collections::HashMap<String, ExprAst>> as uint;
+            match self.dicts.find(&key) {
+               Some(copy) => return Dict(copy.clone()),
+               None => {}
             }
-            _ => {
-               fail!("NYI"); // XXX: implement obviously
+            let copy = DictAst::new(collections::HashMap::new());
+            self.dicts.insert(key, copy.clone());
+            let entries = ast.entries.borrow().clone();
+            for (name, value) in entries.iter() {
+               let value = self.copy_value(value);
+               copy.entries.borrow_mut().insert(name.clone(), value);
             }
+            Dict(copy)
          }
-         ops -= 1;
+         List(ref ast) => {
+            let items: Vec<ExprAst> = ast.iter().map(|item| item.clone()).collect();
+            List(ListAst::new(items.iter().map(|item| self.copy_value(item)).collect()))
+         }
+         Struct(ref ast) => Struct(StructAst::new(ast.name.clone(), ast.fields.clone(),
+                                                   ast.values.iter().map(|value| self.copy_value(value)).collect())),
+         ref other => other.clone()
       }
-      if decimal { Float(FloatAst::new(val)) } else { Integer(IntegerAst::new(val as i64)) }
    }
+}
 
-   fn print(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("print");
-      let mut ops = ops;
-      while ops > 0 {
-         match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
-            Integer(ref ast) => print!("{}", ast.value),
-            Float(ref ast) => print!("{}", f64::to_str_digits(ast.value, 15)),
-            String(ref ast) => {
-               let mut output = String::new();
-               let mut escape = false;
-               for ch in ast.string.as_slice().chars() {
-                  if ch == '\\' {
-                     if escape {
-                        escape = false;
-                        output.push_char('\\');
-                     } else {
-                        escape = true;
-                     }
-                  } else if escape {
-                     match ch {
-                        'n' => println!("{}", output),
-                        't' => print!("{}\t", output),
-                        other => fail!("\\\\{} not a valid escape sequence", other)  // XXX: fix
-                     }
-                     escape = false;
-                     output.truncate(0);
-                  } else {
-                     output.push_char(ch);
-                  }
-               }
-               if escape {
-                  fail!("unterminated escape sequence");  // XXX: fix
-               }
-               print!("{}", output);
-            },
-            Symbol(ast) => print!("'{}", ast.value),
-            Boolean(ast) => print!("{}", ast.value),
-            _ => fail!()  // XXX: more of the same
-         }
-         ops -= 1;
+pub struct Interpreter {
+   parser: Parser,
+   pub env: Rc<RefCell<Environment>>,
+   stack: Vec<ExprAst>,
+   // every macro defined so far, which are expanded in each top-level form before it runs
+   macros: collections::HashMap<Name, Macro>
+}
+
+#[deriving(Clone)]
+struct Macro {
+   code: CodeAst,
+   // where the defmacro was, for errors while expanding it
+   span: Span
+}
+
+// replaces uses of macros with the code they give back, registering any defmacro it finds on
+// the way; the first error stops the expansion
+struct Expander<'a> {
+   macros: &'a mut collections::HashMap<Name, Macro>,
+   env: Rc<RefCell<Environment>>,
+   stack: &'a mut Vec<ExprAst>,
+   file: String,
+   depth: uint,
+   error: Option<InterpError>
+}
+
+#[deriving(Clone, PartialEq)]
+pub struct Environment {
+   pub parent: Option<Rc<RefCell<Environment>>>,
+   pub values: collections::HashMap<Name, Rc<RefCell<EnvValue>>>,
+   pub context: Rc<RefCell<Context>>,
+   // goes up every time a name is bound here, so that lookups cached by the bytecode know when a
+   // new binding may have hidden the one they found
+   pub version: uint
+}
+
+// state that belongs to the whole interpreter, shared by every environment it creates
+#[deriving(Clone, PartialEq)]
+pub struct Context {
+   pub mode: InterpMode,
+   pub backend: Backend,
+   // directories given by the embedder to look in for non-relative imports
+   pub module_paths: Vec<Path>,
+   // top-level bindings of every module imported so far, keyed by absolute path
+   pub modules: collections::HashMap<String, collections::HashMap<Name, Rc<RefCell<EnvValue>>>>,
+   // modules that are part way through loading, outermost first
+   pub loading: Vec<String>,
+   // the names each module loading now has passed to export, keyed like modules; they are kept
+   // here rather than in the module's scope so that nothing the module defines can touch them
+   pub exports: collections::HashMap<String, Vec<Name>>,
+   // set by Interpreter::new_bare, and passed on to the modules it imports
+   pub bare: bool,
+   // when set, getenv and setenv use this map instead of the process environment
+   pub env_vars: Option<collections::HashMap<String, String>>,
+   pub fs_allowed: bool,
+   // off unless the embedder asks for it, since exec and system can do anything the host can
+   pub exec_allowed: bool,
+   pub input: Input,
+   pub output: Output,
+   // the buffer behind output after Interpreter::capture_output
+   pub captured: Option<Rc<RefCell<Vec<u8>>>>,
+   // where eprint, eprintln and the interpreter's own error reports go
+   pub error_output: Output,
+   pub captured_errors: Option<Rc<RefCell<Vec<u8>>>>,
+   pub clock: ClockSource,
+   // steps left before execution stops, if limited at all
+   pub fuel: Option<u64>,
+   // the reading of clock.monotonic() at which execution stops
+   pub deadline: Option<f64>,
+   // names bound in the builtins scope, and the ones among them that have been bound again or
+   // assigned to since; the optimizer's resolved calls are only used for names not in shadowed
+   pub builtin_names: collections::HashSet<Name>,
+   pub shadowed: collections::HashSet<Name>,
+   // the fewest and most operands each builtin takes, as it was registered with
+   pub arities: collections::HashMap<Name, (uint, uint)>,
+   pub regexes: RegexCache
+}
+
+// where read-line and read-all take their input from, shared by every copy so that reading
+// through one advances them all
+pub struct Input {
+   pub reader: Rc<RefCell<io::BufferedReader<Box<Reader>>>>
+}
+
+impl Input {
+   pub fn new(reader: Box<Reader>) -> Input {
+      Input {
+         reader: Rc::new(RefCell::new(io::BufferedReader::new(reader)))
       }
-      Integer(IntegerAst::new(0))  // TODO: this should probably be result of output
    }
+}
 
-   // should be able to take stuff like (define var value)
-   fn define(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("define");
-      let ops = ops;
-      if ops != 2 {
-         fail!("define can only take two arguments");  // XXX: fix
+impl Clone for Input {
+   fn clone(&self) -> Input {
+      Input {
+         reader: self.reader.clone()
       }
-      let valast = match unsafe { (*stack).pop() }.unwrap() {
-         Sexpr(ast) => {
-            Interpreter::execute_node(env.clone(), unsafe { ::std::mem::transmute(stack) }, &Sexpr(ast));
-            unsafe { (*stack).pop() }.unwrap()
-         }
-         other => other
-      };
-      let name = match unsafe { (*stack).pop() }.unwrap() {
-         Ident(ref ast) => ast.value.clone(),
-         _ => fail!("define must take ident for first argument")  // XXX: fix
-      };
-      // TODO: add checking in env to see if conflicting names
-      env.clone().borrow_mut().values.insert(name.clone(), Value(valast.clone()));
-      valast
    }
+}
 
-   fn function(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("function");
-      let mut ops = ops;
-      let mut code = vec!();
-      if ops == 0 {
-         fail!("fn need at least one argument");  // XXX: fix
-      }
-      let params = match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
-         Array(ast) => ast,
-         _ => fail!() // XXX: fix
-      };
-      ops -= 1;
-      while ops > 0 {
-         unsafe { code.push((*stack).remove((*stack).len() - ops).unwrap()); }
-         ops -= 1;
+impl PartialEq for Input {
+   fn eq(&self, other: &Input) -> bool {
+      let this: *const RefCell<io::BufferedReader<Box<Reader>>> = &*self.reader;
+      let other: *const RefCell<io::BufferedReader<Box<Reader>>> = &*other.reader;
+      this == other
+   }
+}
+
+// where print and println write to; every write is flushed straight away so that script
+// output stays in order with anything the host writes to the same place
+pub struct Output {
+   pub writer: Rc<RefCell<Box<Writer>>>
+}
+
+impl Output {
+   pub fn new(writer: Box<Writer>) -> Output {
+      Output {
+         writer: Rc::new(RefCell::new(writer))
       }
-      super::ast::Code(CodeAst::new(params, code, env.clone()))
    }
+}
 
-   fn get(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("get");
-      if ops != 2 {
-         fail!("get only takes two values (list/array and index)");  // XXX: fix
+impl Clone for Output {
+   fn clone(&self) -> Output {
+      Output {
+         writer: self.writer.clone()
       }
-      let arr = match unsafe { (*stack).remove((*stack).len() - 2) }.unwrap() {
-         Array(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      let idx = match unsafe { (*stack).pop() }.unwrap() {
-         Integer(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      let idx =
-         if idx.value < 0 {
-            let arrlen = arr.items.len();
-            if arrlen < -idx.value as uint {
-               fail!("absolute value of {} is too large for the array/list", idx.value); // XXX: fix
-            } else {
-               arrlen + idx.value as uint
-            }
-         } else {
-            idx.value as uint
-         };
-      // TODO: check bounds
-      arr.items[idx].clone()
    }
+}
 
-   fn set(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("set");
-      if ops != 3 {
-         fail!("set only takes three values (list/array, index, value)");  // XXX: fix
-      }
-      let (idast, mut arrast) = match unsafe { (*stack).remove((*stack).len() - 3) }.unwrap() {
-         Array(_) => return Nil(NilAst::new()),
-         Ident(ast) => match env.clone().borrow().find(&ast.value) {
-            Some(val) => match val {
-               Value(ref val) => match val {
-                  &Array(ref arrast) => (ast, arrast.clone()),
-                  _ => fail!() // XXX: fix
-               },
-               EnvCode(_) => fail!() // XXX: fix
-            },
-            None => fail!() // XXX: fix
-         },
-         _ => fail!()  // XXX: fix
-      };
-      let idx = match unsafe { (*stack).remove((*stack).len() - 2) }.unwrap() {
-         Integer(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      let value = unsafe { (*stack).pop() }.unwrap();
-      let idx =
-         if idx.value < 0 {
-            let arrlen = arrast.items.len();
-            if arrlen < -idx.value as uint {
-               fail!("absolute value of {} is too large for the array/list", idx.value); // XXX: fix
-            } else {
-               arrlen + idx.value as uint
-            }
-         } else {
-            idx.value as uint
-         };
-      // TODO: fix this horrifically inefficient mess
-      let mut vec: Vec<ExprAst> = arrast.items.clone().move_iter().collect();
-      vec.grow_set(idx, &Nil(NilAst::new()), value);
-      arrast.items = vec;
-      env.clone().borrow_mut().replace(idast.value, Value(Array(arrast)));
-      Nil(NilAst::new())
+impl PartialEq for Output {
+   fn eq(&self, other: &Output) -> bool {
+      let this: *const RefCell<Box<Writer>> = &*self.writer;
+      let other: *const RefCell<Box<Writer>> = &*other.writer;
+      this == other
    }
+}
 
-   fn len(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("len");
-      if ops != 1 {
-         fail!("get only takes one value (list/array)");  // XXX: fix
-      }
-      let arr = match unsafe { (*stack).pop() }.unwrap() {
-         Array(ast) => ast,
-         _ => fail!()  // XXX: fix
-      };
-      Integer(IntegerAst::new(arr.items.len() as i64))
+// what now, clock and sleep ask about time, so that embedders and tests can substitute their own
+pub trait Clock {
+   // milliseconds since the Unix epoch
+   fn now(&self) -> i64;
+   // seconds since some fixed point, never going backwards
+   fn monotonic(&self) -> f64;
+   fn sleep(&self, ms: u64);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+   fn now(&self) -> i64 {
+      let spec = time::get_time();
+      spec.sec * 1000 + (spec.nsec / 1000000) as i64
    }
 
-   fn equal(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("equal");
-      let mut ops = ops;
-      if ops < 2 {
-         fail!("= needs at least two operands"); // XXX: fix
-      }
-      let cmpast = unsafe { (*stack).pop() }.unwrap();
-      ops -= 1;
-      while ops > 0 {
-         if unsafe { (*stack).pop() }.unwrap() != cmpast {
-            return Boolean(BooleanAst::new(false));
-         }
-         ops -= 1;
-      }
-      Boolean(BooleanAst::new(true))
+   fn monotonic(&self) -> f64 {
+      time::precise_time_ns() as f64 / 1e9
    }
 
-   fn ifexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      debug!("if");
-      if ops < 2 || ops > 3 {
-         fail!("if needs >= 2 && <= 4 operands");  // XXX: fix
-      }
-      let cond = match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
-         Boolean(ast) => ast.value,
-         _ => fail!() // XXX: fix
-      };
-      let ontrue = unsafe { (*stack).remove((*stack).len() - ops + 1) }.unwrap();
-      if ops - 2 > 0 {
-         let onfalse = unsafe { (*stack).pop() }.unwrap();
-         if !cond {
-            Interpreter::execute_node(env.clone(), unsafe { ::std::mem::transmute(stack) }, &onfalse);
-         }
-      }
-      if cond {
-         Interpreter::execute_node(env.clone(), unsafe { ::std::mem::transmute(stack) }, &ontrue);
+   fn sleep(&self, ms: u64) {
+      timer::sleep(ms);
+   }
+}
+
+pub struct ClockSource {
+   pub clock: Rc<Box<Clock>>
+}
+
+impl ClockSource {
+   pub fn new(clock: Box<Clock>) -> ClockSource {
+      ClockSource {
+         clock: Rc::new(clock)
       }
-      unsafe { (*stack).pop() }.unwrap()
    }
+}
 
-   fn importexpr(env: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      let mut ops = ops;
-      if ops == 0 {
-         fail!("import requires at least one operand"); // XXX: fix
+impl Clone for ClockSource {
+   fn clone(&self) -> ClockSource {
+      ClockSource {
+         clock: self.clock.clone()
       }
-      while ops > 0 {
-         match unsafe { (*stack).remove((*stack).len() - ops) }.unwrap() {
-            String(ast) => {
-               let slice = ast.string.as_slice();
-               let mut path = if slice.starts_with("./") || slice.starts_with("../") {
-                  Path::new(match env.clone().borrow().find(&"FILE".to_string()).unwrap() {
-                     Value(val) => match val {
-                        String(ast) => ast.string,
-                        _ => fail!() // XXX: fix
-                     },
-                     EnvCode(_) => fail!() // XXX: fix
-                  }).dir_path()
-               } else {
-                  fail!();
-                  Path::new("MODULE DIRECTORY GOES HERE") // TODO: ...
-               }.join(Path::new(slice));
-               if !slice.ends_with(".irl") {
-                  path.set_extension("irl");
-               }
-               let code = match io::File::open(&path) {
-                  Ok(m) => m,
-                  Err(_) => fail!() // XXX: fix
-               }.read_to_string().unwrap();
-               let mut interp = Interpreter::new();
-               interp.load_code(code);
-               interp.set_file(path.as_str().unwrap().to_string());
-               interp.execute();
-               env.borrow_mut().values.extend((*interp.env).clone().unwrap().values.move_iter());
-            }
-            _ => fail!() // XXX: fix
-         }
-         ops -= 1;
+   }
+}
+
+impl PartialEq for ClockSource {
+   fn eq(&self, other: &ClockSource) -> bool {
+      let this: *const Box<Clock> = &*self.clock;
+      let other: *const Box<Clock> = &*other.clock;
+      this == other
+   }
+}
+
+// the patterns the re- builtins have compiled, keyed by their text, so that using one in a loop
+// only compiles it once
+pub struct RegexCache {
+   pub patterns: Rc<RefCell<collections::HashMap<String, Regex>>>
+}
+
+impl RegexCache {
+   pub fn new() -> RegexCache {
+      RegexCache {
+         patterns: Rc::new(RefCell::new(collections::HashMap::new()))
       }
-      Nil(NilAst::new())
    }
+}
 
-   fn type_obj(_: Rc<RefCell<Environment>>, stack: *mut Vec<ExprAst>, ops: uint) -> ExprAst {
-      if ops != 1 {
-         fail!("type only takes one object"); // XXX: fix
+impl Clone for RegexCache {
+   fn clone(&self) -> RegexCache {
+      RegexCache {
+         patterns: self.patterns.clone()
       }
-      Symbol(SymbolAst::new(match unsafe { (*stack).pop() }.unwrap() {
-         Integer(_) => "integer",
-         Float(_) => "float",
-         Array(_) => "array",
-         List(_) => "list",
-         String(_) => "string",
+   }
+}
+
+impl PartialEq for RegexCache {
+   fn eq(&self, other: &RegexCache) -> bool {
+      let this: *const RefCell<collections::HashMap<String, Regex>> = &*self.patterns;
+      let other: *const RefCell<collections::HashMap<String, Regex>> = &*other.patterns;
+      this == other
+   }
+}
+
+// a Writer whose bytes the interpreter can still get at after handing it over as output
+struct CaptureWriter {
+   buf: Rc<RefCell<Vec<u8>>>
+}
+
+impl Writer for CaptureWriter {
+   fn write(&mut self, buf: &[u8]) -> io::IoResult<()> {
+      self.buf.borrow_mut().push_all(buf);
+      Ok(())
+   }
+}
+
+impl Context {
+   pub fn new() -> Context {
+      Context {
+         mode: Release,
+         backend: TreeWalker,
+         module_paths: vec!(),
+         modules: collections::HashMap::new(),
+         loading: vec!(),
+         exports: collections::HashMap::new(),
+         bare: false,
+         env_vars: None,
+         fs_allowed: true,
+         exec_allowed: false,
+         input: Input::new(box io::stdio::stdin_raw() as Box<Reader>),
+         output: Output::new(box io::stdout() as Box<Writer>),
+         captured: None,
+         error_output: Output::new(box io::stderr() as Box<Writer>),
+         captured_errors: None,
+         clock: ClockSource::new(box SystemClock as Box<Clock>),
+         fuel: None,
+         deadline: None,
+         builtin_names: collections::HashSet::new(),
+         shadowed: collections::HashSet::new(),
+         arities: collections::HashMap::new(),
+         regexes: RegexCache::new()
+      }
+   }
+
+   // IRON_PATH comes first, then the paths added through the interpreter, then the lib
+   // directory next to the executable
+   pub fn module_search_path(&self) -> Vec<Path> {
+      let mut dirs = match os::getenv("IRON_PATH") {
+         Some(value) => os::split_paths(value.as_slice()),
+         None => vec!()
+      };
+      dirs.push_all(self.module_paths.as_slice());
+      match os::self_exe_path() {
+         Some(dir) => dirs.push(dir.join("lib")),
+         None => {}
+      }
+      dirs
+   }
+}
+
+impl Interpreter {
+   pub fn new() -> Interpreter {
+      Interpreter::with_context(Rc::new(RefCell::new(Context::new())))
+   }
+
+   // only the builtins, without the prelude, for embedders that want a minimal sandbox
+   pub fn new_bare() -> Interpreter {
+      let mut context = Context::new();
+      context.bare = true;
+      Interpreter::with_context(Rc::new(RefCell::new(context)))
+   }
+
+   // imported modules run in their own interpreter but share the mode, module paths and
+   // module cache of the one that imported them
+   fn with_context(context: Rc<RefCell<Context>>) -> Interpreter {
+      let mut builtins = Environment::new(None);
+      builtins.context = context.clone();
+      builtins.populate_default();
+      let builtins = Rc::new(RefCell::new(builtins));
+      if !context.borrow().bare {
+         Interpreter::load_prelude(builtins.clone());
+      }
+      // scripts get their own scope so that they can shadow builtins with define
+      let env = Environment::new(Some(builtins));
+      Interpreter {
+         parser: Parser::new(),
+         env: Rc::new(RefCell::new(env)),
+         stack: Vec::with_capacity(STACK_SIZE),
+         macros: collections::HashMap::new()
+      }
+   }
+
+   // the prelude is part of the binary, so a failure here is a bug in iron rather than in a script
+   fn load_prelude(env: Rc<RefCell<Environment>>) {
+      let root = match Parser::new().parse_code(PRELUDE.to_string()) {
+         Ok(Root(ast)) => ast,
+         Ok(_) => unreachable!(),
+         Err(f) => fail!("prelude: {}", f)
+      };
+      let mut stack = vec!();
+      for ast in root.asts.iter() {
+         match Interpreter::execute_node(env.clone(), &mut stack, ast) {
+            Ok(()) => stack.clear(),
+            Err(f) => fail!("prelude: {}", f)
+         }
+      }
+   }
+
+   // makes a Rust function callable from scripts just like print; scripts can shadow it with define
+   pub fn register_builtin(&mut self, name: &str, func: Box<HostFunction>) {
+      let builtins = self.env.borrow().parent.clone().unwrap();
+      builtins.borrow_mut().insert(Name::new(name), Value(Host(HostAst::new(name.to_string(), HostFn::new(func)))));
+   }
+
+   // binds name in the script's scope, or changes the binding if the script already has one
+   pub fn set_global(&mut self, name: &str, value: ExprAst) {
+      let key = Name::new(name);
+      let exists = self.env.borrow().values.contains_key(&key);
+      if exists {
+         self.env.borrow_mut().replace(key, Value(value));
+      } else {
+         self.env.borrow_mut().insert(key, Value(value));
+      }
+   }
+
+   // what a script would get by evaluating name, builtins included
+   pub fn get_global(&self, name: &str) -> Option<ExprAst> {
+      match self.env.borrow().find(&Name::new(name)) {
+         Some(Value(value)) => Some(value),
+         Some(EnvCode(func)) => Some(Builtin(BuiltinAst::new(name.to_string(), func))),
+         None => None
+      }
+   }
+
+   // calls a function the script defined (or any builtin) as if the script had written (name args...)
+   pub fn call(&mut self, name: &str, args: Vec<ExprAst>) -> InterpResult<ExprAst> {
+      let func = match self.get_global(name) {
+         Some(func) => func,
+         None => return Err(InterpError::new(UnknownIdent, format!("unknown function {}", name)).in_file(self.error_file()))
+      };
+      let result = Interpreter::call_value(self.env.clone(), &mut self.stack, &func, args);
+      self.stack.clear();
+      result.map_err(|f| f.in_file(self.error_file()))
+   }
+
+   // throws away everything scripts have defined, including their imports, and starts again from
+   // the builtins and prelude; FILE, ARGV and builtins registered by the embedder are kept
+   pub fn reset(&mut self) {
+      let old = self.env.clone();
+      let context = old.borrow().context.clone();
+      context.borrow_mut().modules.clear();
+      let fresh = Interpreter::with_context(context);
+      {
+         let builtins = old.borrow().parent.clone().unwrap();
+         let fresh_builtins = fresh.env.borrow().parent.clone().unwrap();
+         for (name, slot) in builtins.borrow().values.iter() {
+            match *slot.borrow() {
+               Value(Host(ref ast)) => fresh_builtins.borrow_mut().insert(name.clone(), Value(Host(ast.clone()))),
+               _ => {}
+            }
+         }
+      }
+      for name in ["FILE", "ARGV"].iter() {
+         match old.borrow().values.find(&Name::new(*name)) {
+            Some(slot) => fresh.env.borrow_mut().insert(Name::new(*name), slot.borrow().clone()),
+            None => {}
+         }
+      }
+      self.env = fresh.env;
+      self.stack.clear();
+   }
+
+   // a copy of the environment that restore can go back to; the interpreter-wide settings (mode,
+   // input and output, the module cache) are not part of it
+   pub fn snapshot(&self) -> EnvSnapshot {
+      EnvSnapshot {
+         env: EnvCopier::new().copy_env(&self.env)
+      }
+   }
+
+   // the snapshot is copied again, so it can be restored any number of times. Functions the
+   // host got hold of before restoring still close over the environment they were created in,
+   // not the restored one, so they keep seeing (and making) changes the restore discarded;
+   // fetch them again with get_global afterwards
+   pub fn restore(&mut self, snapshot: &EnvSnapshot) {
+      self.env = EnvCopier::new().copy_env(&snapshot.env);
+      self.stack.clear();
+   }
+
+   pub fn set_mode(&mut self, mode: InterpMode) {
+      self.env.borrow().context.borrow_mut().mode = mode;
+   }
+
+   pub fn mode(&self) -> InterpMode {
+      self.env.borrow().context.borrow().mode.clone()
+   }
+
+   pub fn set_backend(&mut self, backend: Backend) {
+      self.env.borrow().context.borrow_mut().backend = backend;
+   }
+
+   pub fn backend(&self) -> Backend {
+      self.env.borrow().context.borrow().backend.clone()
+   }
+
+   // keeps scripts away from the process environment; getenv and setenv see only vars
+   pub fn sandbox_env_vars(&mut self, vars: collections::HashMap<String, String>) {
+      self.env.borrow().context.borrow_mut().env_vars = Some(vars);
+   }
+
+   // whether scripts may use read-file, write-file and friends; imports are not affected
+   pub fn allow_fs(&mut self, allowed: bool) {
+      self.env.borrow().context.borrow_mut().fs_allowed = allowed;
+   }
+
+   pub fn allow_exec(&mut self, allowed: bool) {
+      self.env.borrow().context.borrow_mut().exec_allowed = allowed;
+   }
+
+   // replaces stdin as the source for read-line and read-all
+   pub fn set_input(&mut self, reader: Box<Reader>) {
+      self.env.borrow().context.borrow_mut().input = Input::new(reader);
+   }
+
+   // replaces stdout as the destination for print and println
+   pub fn set_output(&mut self, writer: Box<Writer>) {
+      let context = self.env.borrow().context.clone();
+      context.borrow_mut().output = Output::new(writer);
+      context.borrow_mut().captured = None;
+   }
+
+   // collects everything the script prints so it can be read back with captured_output
+   pub fn capture_output(&mut self) {
+      let buf = Rc::new(RefCell::new(vec!()));
+      let context = self.env.borrow().context.clone();
+      context.borrow_mut().output = Output::new(box CaptureWriter { buf: buf.clone() } as Box<Writer>);
+      context.borrow_mut().captured = Some(buf);
+   }
+
+   pub fn captured_output(&self) -> Option<Vec<u8>> {
+      self.env.borrow().context.borrow().captured.as_ref().map(|buf| buf.borrow().clone())
+   }
+
+   // replaces stderr as the destination for eprint, eprintln and report_error
+   pub fn set_error_output(&mut self, writer: Box<Writer>) {
+      let context = self.env.borrow().context.clone();
+      context.borrow_mut().error_output = Output::new(writer);
+      context.borrow_mut().captured_errors = None;
+   }
+
+   pub fn capture_error_output(&mut self) {
+      let buf = Rc::new(RefCell::new(vec!()));
+      let context = self.env.borrow().context.clone();
+      context.borrow_mut().error_output = Output::new(box CaptureWriter { buf: buf.clone() } as Box<Writer>);
+      context.borrow_mut().captured_errors = Some(buf);
+   }
+
+   pub fn captured_error_output(&self) -> Option<Vec<u8>> {
+      self.env.borrow().context.borrow().captured_errors.as_ref().map(|buf| buf.borrow().clone())
+   }
+
+   // writes an error on its own line to the error output; there is nowhere left to report a
+   // failure to do so, so that is ignored
+   pub fn report_error<T: fmt::Show>(&self, err: &T) {
+      let destination = self.env.borrow().context.borrow().error_output.clone();
+      let mut writer = destination.writer.borrow_mut();
+      match writer.write_line(format!("{}", err).as_slice()) {
+         Ok(()) => { let _ = writer.flush(); }
+         Err(_) => {}
+      }
+   }
+
+   // every evaluated node uses up one unit of fuel; None (the default) means no limit
+   pub fn set_fuel(&mut self, fuel: Option<u64>) {
+      self.env.borrow().context.borrow_mut().fuel = fuel;
+   }
+
+   pub fn fuel(&self) -> Option<u64> {
+      self.env.borrow().context.borrow().fuel
+   }
+
+   // stops execution once ms milliseconds from now have passed, according to the interpreter's clock
+   pub fn set_deadline(&mut self, ms: Option<u64>) {
+      let shared = self.env.borrow().context.clone();
+      let mut context = shared.borrow_mut();
+      let now = context.clock.clock.monotonic();
+      context.deadline = ms.map(|ms| now + ms as f64 / 1000.0);
+   }
+
+   pub fn set_clock(&mut self, clock: Box<Clock>) {
+      self.env.borrow().context.borrow_mut().clock = ClockSource::new(clock);
+   }
+
+   pub fn add_module_path(&mut self, path: Path) {
+      self.env.borrow().context.borrow_mut().module_paths.push(path);
+   }
+
+   // FILE is kept absolute so that relative imports still resolve if the working directory changes
+   pub fn set_file(&mut self, file: String) {
+      let path = os::make_absolute(&Path::new(file.as_slice()));
+      self.env.clone().borrow_mut().insert(Name::new("FILE"), Value(String(StringAst::new(path.display().to_string()))));
+   }
+
+   // the arguments given to the script, not including the interpreter or the script's path
+   pub fn set_args(&mut self, args: Vec<String>) {
+      let args = args.move_iter().map(|arg| String(StringAst::new(arg))).collect();
+      self.env.clone().borrow_mut().insert(Name::new("ARGV"), Value(Array(ArrayAst::new(args))));
+   }
+
+   pub fn file(&self) -> String {
+      match self.env.borrow().find(&Name::new("FILE")) {
+         Some(Value(String(ast))) => ast.string,
+         _ => "".to_string()
+      }
+   }
+
+   // errors name the file relative to the working directory when it is inside it
+   fn error_file(&self) -> String {
+      let file = self.file();
+      let cwd = os::getcwd();
+      let path = Path::new(file.as_slice());
+      if file.len() > 0 && cwd.is_ancestor_of(&path) {
+         match path.path_relative_from(&cwd) {
+            Some(relative) => return relative.display().to_string(),
+            None => {}
+         }
+      }
+      file
+   }
+
+   pub fn load_code(&mut self, code: String) {
+      self.parser.load_code(code);
+   }
+
+   // renames an identifier throughout the loaded code, which is replaced by the source of the
+   // renamed tree, so positions in later errors refer to that rather than to the original
+   pub fn rename(&mut self, from: &str, to: &str) -> Result<(), ParseError> {
+      let root = try!(self.parser.parse());
+      let mut renamer = Renamer { from: Name::new(from), to: Name::new(to) };
+      self.load_code(transform_expr(root, &mut renamer).source());
+      Ok(())
+   }
+
+   // the exit status is the one given to exit, or 0 if the script runs to the end
+   pub fn execute(&mut self) -> InterpResult<int> {
+      debug!("execute");
+      match self.eval() {
+         Ok(_) => Ok(0),
+         Err(InterpError { kind: Exit(status), .. }) => Ok(status),
+         Err(f) => Err(f)
+      }
+   }
+
+   // runs the loaded code and gives back the value of the last form, keeping every definition
+   // for later calls; an exit comes back as an error so that exiting from an imported module
+   // also stops the script that imported it
+   pub fn eval(&mut self) -> InterpResult<ExprAst> {
+      let root = match self.parser.parse() {
+         Ok(Root(ast)) => ast,
+         Ok(_) => unreachable!(),
+         Err(f) => return Err(InterpError::from_parse(f).in_file(self.error_file()))
+      };
+      let mut optimizer = self.optimizer(&root);
+      let mut last = Nil(NilAst::new());
+      let backend = self.backend();
+      for ast in root.asts.move_iter() {
+         // so that each form can use the macros defined by the ones before it
+         let ast = match self.expand(ast.clone()) {
+            Ok(expanded) => expanded,
+            Err(f) => {
+               self.stack.clear();
+               return Err(f.at(ast.span()).in_file(self.error_file()));
+            }
+         };
+         // only now, so that the code macros wrote is optimized too
+         let ast = match optimizer {
+            Some(ref mut optimizer) => match optimizer.optimize(ast) {
+               Some(ast) => ast,
+               None => continue
+            },
+            None => ast
+         };
+         let result = match backend {
+            TreeWalker => Interpreter::execute_node(self.env.clone(), &mut self.stack, &ast),
+            Bytecode => {
+               let chunk = bytecode::compile(::std::slice::ref_slice(&ast));
+               bytecode::run(self.env.clone(), &mut self.stack, &chunk).map_err(|f| f.at(ast.span()))
+            }
+         };
+         let value = self.stack.pop();
+         match result {
+            Ok(()) => {
+               debug_assert!(self.stack.is_empty(), "{} values left on the stack after a top-level form", self.stack.len());
+               match value {
+                  Some(value) => last = value,
+                  None => {}
+               }
+            }
+            Err(f) => {
+               // a failed form may leave its operands behind
+               self.stack.clear();
+               return Err(f.in_file(self.error_file()));
+            }
+         }
+      }
+      Ok(last)
+   }
+
+   fn expand(&mut self, ast: ExprAst) -> InterpResult<ExprAst> {
+      let file = self.error_file();
+      let env = self.env.clone();
+      let mut expander = Expander {
+         macros: &mut self.macros,
+         env: env,
+         stack: &mut self.stack,
+         file: file,
+         depth: 0,
+         error: None
+      };
+      let result = transform_expr(ast, &mut expander);
+      match expander.error {
+         Some(f) => Err(f),
+         None => Ok(result)
+      }
+   }
+
+   // debug mode runs the code exactly as written; otherwise it is optimized first, taking care
+   // not to fold calls to names that earlier code has bound in the script's scope
+   fn optimizer(&self, root: &RootAst) -> Option<Optimizer> {
+      if self.mode() == Debug {
+         return None;
+      }
+      let bound = self.env.borrow().values.keys().map(|key| key.clone()).collect();
+      let builtins = self.env.borrow().parent.clone().unwrap();
+      let funcs = builtins.borrow().values.iter().filter_map(|(name, slot)| match *slot.borrow() {
+         EnvCode(func) => Some((name.clone(), func)),
+         _ => None
+      }).collect();
+      Some(Optimizer::new(root, &bound, &funcs))
+   }
+
+   fn optimize(&self, root: RootAst) -> RootAst {
+      match self.optimizer(&root) {
+         Some(mut optimizer) => optimizer.optimize_root(root),
+         None => root
+      }
+   }
+
+   pub fn eval_str(&mut self, code: &str) -> InterpResult<ExprAst> {
+      self.load_code(code.to_string());
+      self.eval()
+   }
+
+   // what print would show for value, or its type in angle brackets if print cannot show it
+   pub fn describe_value(value: &ExprAst) -> String {
+      match Environment::format_value(value) {
+         Ok(text) => text,
+         Err(_) => format!("<{}>", Environment::type_name(value))
+      }
+   }
+
+   pub fn execute_node(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, node: &ExprAst) -> InterpResult<()> {
+      debug!("execute_node");
+      let stacklen = stack.len();
+      let result = match Interpreter::spend_fuel(&env) {
+         Ok(()) => Interpreter::eval_node(env, stack, node),
+         Err(f) => Err(f)
+      };
+      match result {
+         Err(f) => Err(f.at(node.span())),
+         ok => {
+            // every node leaves exactly one value behind, whatever its operands pushed meanwhile
+            debug_assert!(stack.len() == stacklen + 1, "one node took the stack from {} values to {}", stacklen, stack.len());
+            ok
+         }
+      }
+   }
+
+   // counts one step against the fuel and checks the deadline; once either runs out, every
+   // later step fails too
+   pub fn spend_fuel(env: &Rc<RefCell<Environment>>) -> InterpResult<()> {
+      let shared = env.borrow().context.clone();
+      let mut context = shared.borrow_mut();
+      match context.fuel {
+         Some(0) => throw!(LimitError, "execution budget exceeded"),
+         Some(fuel) => context.fuel = Some(fuel - 1),
+         None => {}
+      }
+      match context.deadline {
+         Some(deadline) if context.clock.clock.monotonic() >= deadline => throw!(LimitError, "execution deadline passed"),
+         _ => {}
+      }
+      Ok(())
+   }
+
+   fn eval_node(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, node: &ExprAst) -> InterpResult<()> {
+      let stacklen = stack.len();
+      match *node {
+         Sexpr(ref sast) => {
+            match sast.form {
+               LazyForm => {
+                  for subast in sast.operands.iter() {
+                     stack.push(subast.clone());
+                  }
+               }
+               IfForm => {
+                  if Interpreter::is_builtin(&env, sast, Environment::ifexpr) {
+                     // the branch taken runs where it is instead of being copied onto the stack
+                     return Interpreter::eval_if(env, stack, sast);
+                  }
+                  if sast.operands.len() > 0 {
+                     try!(Interpreter::execute_node(env.clone(), stack, &sast.operands[0]));
+                  }
+                  for subast in sast.operands.slice_from(1).iter() {
+                     stack.push(subast.clone());
+                  }
+               }
+               ImportForm => {
+                  // the name in (import "mod" as m) is not evaluated
+                  let mut naming = false;
+                  for subast in sast.operands.iter() {
+                     match *subast {
+                        Ident(ref ast) if naming || ast.value.as_slice() == "as" => {
+                           naming = !naming;
+                           stack.push(subast.clone());
+                        }
+                        _ => try!(Interpreter::execute_node(env.clone(), stack, subast))
+                     }
+                  }
+               }
+               DefineForm => {
+                  if sast.operands.len() > 0 {
+                     stack.push(sast.operands[0].clone());
+                     for subast in sast.operands.slice_from(1).iter() {
+                        try!(Interpreter::execute_node(env.clone(), stack, subast));
+                     }
+                  }
+               }
+               CallForm => {
+                  for subast in sast.operands.iter() {
+                     try!(Interpreter::execute_node(env.clone(), stack, subast));
+                  }
+               }
+            };
+            // special forms push their operands unevaluated, so count what is actually on the stack
+            let nargs = stack.len() - stacklen;
+            let thing = match Interpreter::cached_builtin(&env, &sast.op.value, sast.builtin) {
+               Some(func) => EnvCode(func),
+               None => match env.borrow().find(&sast.op.value) {
+                  Some(thing) => thing,
+                  None => throw!(UnknownIdent, "unknown function {}{}", sast.op.value, env.borrow().suggestion(sast.op.value.as_slice()))
+               }
+            };
+            try!(Interpreter::apply(env, stack, &sast.op.value, thing, nargs));
+         }
+         Ident(ref ast) => {
+            let found = env.borrow().with_value(&ast.value, |val| match *val {
+               Value(ref val) => val.clone(),
+               EnvCode(func) => Builtin(BuiltinAst::new(ast.value.to_string(), func))
+            });
+            match found {
+               Some(val) => stack.push(val),
+               None => throw!(UnknownIdent, "ident {} not declared{}", ast.value, env.borrow().suggestion(ast.value.as_slice()))
+            }
+         }
+         // each evaluation of an array literal creates a new array from the values of its items
+         Array(ref ast) => {
+            let items = ast.items.borrow();
+            for item in items.iter() {
+               try!(Interpreter::execute_node(env.clone(), stack, item));
+            }
+            let values = Environment::take_args(stack, items.len());
+            stack.push(Array(ArrayAst::new(values)));
+         }
+         List(ref ast) => stack.push(Environment::list_value(ast)),
+         ref other => stack.push(other.clone())  // XXX: probably can be fixed
+      }
+      Ok(())
+   }
+
+   // calls what the operator of a sexpr named with the top nargs values on the stack, leaving the
+   // result in their place
+   pub fn apply(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, name: &Name, thing: EnvValue, nargs: uint) -> InterpResult<()> {
+      let stacklen = stack.len() - nargs;
+      match thing {
+         EnvCode(thunk) => {
+            debug!("executing thunk...");
+            let val = try!(thunk(env, stack, nargs));
+            try!(Interpreter::check_stack(name.as_slice(), stack, stacklen));
+            stack.push(val);
+         }
+         Value(ast) => match ast {
+            super::ast::Code(ast) => {
+               let val = try!(Interpreter::call_code(name.as_slice(), stack, &ast, nargs));
+               stack.push(val);
+            }
+            // a builtin that was bound to another name, e.g. (define add +)
+            Builtin(ast) => {
+               let val = try!((ast.func)(env, stack, nargs));
+               try!(Interpreter::check_stack(name.as_slice(), stack, stacklen));
+               stack.push(val);
+            }
+            Host(ast) => {
+               let val = try!(Interpreter::call_host(env, stack, &ast, nargs));
+               stack.push(val);
+            }
+            other => throw!(TypeError, "{} is a value of type {}, not a function", name, Environment::type_name(&other))
+         }
+      }
+      Ok(())
+   }
+
+   // whether the operator still refers to func, i.e. nothing the script defined has hidden the builtin
+   fn is_builtin(env: &Rc<RefCell<Environment>>, sast: &SexprAst, func: BuiltinFn) -> bool {
+      match Interpreter::cached_builtin(env, &sast.op.value, sast.builtin) {
+         Some(found) => found == func,
+         None => env.borrow().with_value(&sast.op.value, |value| match *value {
+            EnvCode(found) => found == func,
+            _ => false
+         }).unwrap_or(false)
+      }
+   }
+
+   // the builtin the optimizer found for the operator, unless the name has been bound anywhere
+   // else since, in which case it has to be looked up again
+   pub fn cached_builtin(env: &Rc<RefCell<Environment>>, name: &Name, builtin: Option<BuiltinFn>) -> Option<BuiltinFn> {
+      match builtin {
+         Some(func) => {
+            let env = env.borrow();
+            let context = env.context.borrow();
+            if context.shadowed.is_empty() || !context.shadowed.contains(name) {
+               Some(func)
+            } else {
+               None
+            }
+         }
+         None => None
+      }
+   }
+
+   // what ifexpr does, but the branches are evaluated in place rather than passed on the stack
+   fn eval_if(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, sast: &SexprAst) -> InterpResult<()> {
+      let ops = sast.operands.len();
+      if ops > 0 {
+         try!(Interpreter::execute_node(env.clone(), stack, &sast.operands[0]));
+      }
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "if takes a condition, a branch, and an optional else branch but was given {} operands", ops);
+      }
+      if Environment::truthy(&stack.pop().unwrap()) {
+         Interpreter::execute_node(env, stack, &sast.operands[1])
+      } else if ops == 3 {
+         Interpreter::execute_node(env, stack, &sast.operands[2])
+      } else {
+         // there is no else branch to give a value
+         stack.push(Nil(NilAst::new()));
+         Ok(())
+      }
+   }
+
+   // a builtin must consume exactly the arguments it was given; leftovers are dropped, but
+   // taking more than that would have eaten values belonging to the enclosing expression
+   fn check_stack(name: &str, stack: &mut Vec<ExprAst>, stacklen: uint) -> InterpResult<()> {
+      if stack.len() < stacklen {
+         throw!(ArityError, "{} took more arguments than it was given", name);
+      }
+      stack.truncate(stacklen);
+      Ok(())
+   }
+
+   // calls a builtin or code value with already evaluated arguments and returns its result
+   pub fn call_value(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, func: &ExprAst, args: Vec<ExprAst>) -> InterpResult<ExprAst> {
+      let nargs = args.len();
+      stack.push_all_move(args);
+      match *func {
+         Builtin(ref ast) => (ast.func)(env, stack, nargs),
+         Host(ref ast) => Interpreter::call_host(env, stack, ast, nargs),
+         super::ast::Code(ref ast) => Interpreter::call_code("anonymous function", stack, ast, nargs),
+         ref other => throw!(TypeError, "a value of type {} is not callable", Environment::type_name(other))
+      }
+   }
+
+   fn call_host(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ast: &HostAst, nargs: uint) -> InterpResult<ExprAst> {
+      let args = Environment::take_args(stack, nargs);
+      let mut cx = CallContext {
+         name: ast.name.as_slice(),
+         args: args.as_slice(),
+         env: env
+      };
+      ast.func.func.call(&mut cx)
+   }
+
+   // binds the top nargs values on the stack to the parameters of ast and evaluates its body
+   fn call_code(name: &str, stack: &mut Vec<ExprAst>, ast: &CodeAst, nargs: uint) -> InterpResult<ExprAst> {
+      debug!("evaluating code...");
+      let stacklen = stack.len() - nargs;
+      let mut subenv = Environment::new(Some(ast.env.clone()));
+      let params = ast.params.items.borrow();
+      let variadic = match params.last() {
+         Some(&Ident(ref idast)) => idast.value.as_slice().ends_with("..."),
+         _ => false
+      };
+      // parameters with defaults and the rest parameter may go without arguments
+      let required = params.iter().filter(|param| match **param {
+         Ident(ref idast) => !idast.value.as_slice().ends_with("..."),
+         _ => false
+      }).count();
+      if nargs < required || (!variadic && nargs > params.len()) {
+         let expected =
+            if variadic {
+               format!("at least {}", required)
+            } else if required == params.len() {
+               required.to_string()
+            } else {
+               format!("{} to {}", required, params.len())
+            };
+         throw!(ArityError, "{} expects {} arguments but was given {}", name, expected, nargs);
+      }
+      let mut args = Environment::take_args(stack, nargs).move_iter();
+      debug!("begin params");
+      for param in params.iter() {
+         match *param {
+            Ident(ref idast) => {
+               debug!("\t{}", idast.value);
+               let slice = idast.value.as_slice();
+               if slice.ends_with("...") {
+                  subenv.insert(Name::new(slice.slice_to(slice.len() - 3)),
+                                       Value(Array(ArrayAst::new(args.by_ref().collect()))));
+               } else {
+                  subenv.insert(idast.value.clone(), Value(args.next().unwrap()));
+               }
+            }
+            // (name default), where default is evaluated where the function was defined
+            Sexpr(ref default) => {
+               let value = match args.next() {
+                  Some(value) => value,
+                  None => {
+                     try!(Interpreter::execute_node(ast.env.clone(), stack, &default.operands[0]));
+                     stack.pop().unwrap()
+                  }
+               };
+               subenv.insert(default.op.value.clone(), Value(value));
+            }
+            // check_params only lets names and (name default) through
+            _ => unreachable!()
+         };
+      }
+      debug!("end params");
+      let subenv = Rc::new(RefCell::new(subenv));
+      match ast.chunk {
+         Some(ref chunk) => try!(bytecode::run(subenv, stack, &**chunk)),
+         None => for subast in ast.code.iter() {
+            try!(Interpreter::execute_node(subenv.clone(), stack, subast));
+         }
+      }
+      // the value of the last expression in the body is the result of the call
+      let result =
+         if stack.len() > stacklen {
+            stack.pop().unwrap()
+         } else {
+            Nil(NilAst::new())
+         };
+      stack.truncate(stacklen);
+      Ok(result)
+   }
+
+   // parses the loaded code without running any of it
+   pub fn check(&mut self) -> Result<(), ParseError> {
+      try!(self.parser.parse());
+      Ok(())
+   }
+
+   // the mistakes in a script that are certain to be errors once it runs, found without running
+   // anything, so editors can ask for them as the code is written
+   pub fn check_file(&mut self, path: &Path) -> Vec<Diagnostic> {
+      let file = path.display().to_string();
+      let code = match io::File::open(path).read_to_string() {
+         Ok(code) => code,
+         Err(f) => return vec!(InterpError::new(IoError, format!("could not read {}: {}", file, f)))
+      };
+      let root = match Parser::new().parse_code(code) {
+         Ok(Root(root)) => root,
+         Ok(_) => unreachable!(),
+         Err(f) => return vec!(InterpError::from_parse(f).in_file(file))
+      };
+      let mut known = collections::HashSet::new();
+      let mut env = Some(self.env.clone());
+      loop {
+         let parent = match env {
+            Some(ref scope) => {
+               known.extend(scope.borrow().values.keys().map(|name| name.clone()));
+               scope.borrow().parent.clone()
+            }
+            None => break
+         };
+         env = parent;
+      }
+      let arities = self.env.borrow().context.borrow().arities.clone();
+      check::check(&root, known, arities).move_iter().map(|diagnostic| diagnostic.in_file(file.clone())).collect()
+   }
+
+   // the tree the interpreter would run: with spans in debug mode, optimized otherwise
+   pub fn dump_ast(&mut self, format: AstFormat) -> Result<String, ParseError> {
+      let root = match try!(self.parser.parse()) {
+         Root(ast) => self.optimize(ast),
+         _ => unreachable!()
+      };
+      Ok(match format {
+         SexprFormat => Root(root).source(),
+         CountsFormat => {
+            let mut counter = NodeCounter::new();
+            walk_root(&root, &mut counter);
+            counter.report()
+         }
+         TreeFormat if self.mode() == Debug => root.dump_spans(),
+         TreeFormat => root.dump()
+      })
+   }
+
+   pub fn print_ast(&mut self, format: AstFormat) -> Result<(), ParseError> {
+      print!("{}", try!(self.dump_ast(format)));
+      Ok(())
+   }
+}
+
+impl<'a> Expander<'a> {
+   // (defmacro name [params] body...) takes effect as soon as it is expanded, leaving nil
+   // behind for when the code runs
+   fn define(&mut self, sast: SexprAst) -> InterpResult<ExprAst> {
+      if sast.operands.len() < 2 {
+         throw!(ArityError, "defmacro takes a name, a parameter array and a body");
+      }
+      let name = match sast.operands[0] {
+         Ident(ref ast) => ast.value.clone(),
+         ref other => throw!(TypeError, "defmacro expected a name but found {}", Environment::type_name(other))
+      };
+      let params = match sast.operands[1] {
+         Array(ref ast) => ast.clone(),
+         ref other => throw!(TypeError, "defmacro expected a parameter array but found {}", Environment::type_name(other))
+      };
+      try!(Environment::check_params(&params));
+      // the body may use macros itself
+      let mut body = vec!();
+      for node in sast.operands.slice_from(2).iter() {
+         body.push(transform_expr(node.clone(), self));
+      }
+      let code = CodeAst::new(params, body, self.env.clone());
+      self.macros.insert(name, Macro { code: code, span: sast.span.clone() });
+      Ok(Nil(NilAst::new()).with_span(sast.span))
+   }
+
+   // runs the macro on the operands as they were written and turns the value it gives back
+   // into the code that replaces the use
+   fn expand(&mut self, sast: &SexprAst) -> InterpResult<ExprAst> {
+      let name = sast.op.value.clone();
+      if self.depth == MACRO_DEPTH {
+         throw!(LimitError, "expanding {} went more than {} macros deep", name, MACRO_DEPTH);
+      }
+      let definition = self.macros.get(&name).clone();
+      for operand in sast.operands.iter() {
+         self.stack.push(Environment::quoted(operand.clone(), false));
+      }
+      let value = match Interpreter::call_code(name.as_slice(), &mut *self.stack, &definition.code, sast.operands.len()) {
+         Ok(value) => value,
+         Err(f) => {
+            let site = &definition.span;
+            let place =
+               if self.file.len() > 0 {
+                  format!("{}:{}:{}", self.file, site.line, site.column)
+               } else {
+                  format!("{}:{}", site.line, site.column)
+               };
+            let mut err = InterpError::new(f.kind, format!("{} (in macro {} defined at {})", f.message, name, place));
+            err.span = sast.span.clone();
+            return Err(err);
+         }
+      };
+      let code = try!(Environment::unquoted(value)).with_span(sast.span.clone());
+      self.depth += 1;
+      let result = transform_expr(code, self);
+      self.depth -= 1;
+      Ok(result)
+   }
+}
+
+impl<'a> Transformer for Expander<'a> {
+   fn transform_sexpr(&mut self, sast: SexprAst) -> ExprAst {
+      if self.error.is_some() || sast.op.value.as_slice() == "quote" {
+         return Sexpr(sast);
+      }
+      let result =
+         if sast.op.value.as_slice() == "defmacro" {
+            self.define(sast.clone())
+         } else if self.macros.contains_key(&sast.op.value) {
+            self.expand(&sast)
+         } else {
+            return Sexpr(walk_sexpr_mut(sast, self));
+         };
+      match result {
+         Ok(code) => code,
+         Err(f) => {
+            if self.error.is_none() {
+               self.error = Some(f.at(sast.span.clone()));
+            }
+            Sexpr(sast)
+         }
+      }
+   }
+
+   // quoted lists are data
+   fn transform_list(&mut self, ast: ListAst) -> ExprAst { List(ast) }
+}
+
+impl Environment {
+   pub fn new(parent: Option<Rc<RefCell<Environment>>>) -> Environment {
+      let context = match parent {
+         Some(ref env) => env.borrow().context.clone(),
+         None => Rc::new(RefCell::new(Context::new()))
+      };
+      Environment {
+         parent: parent,
+         values: collections::HashMap::new(),
+         context: context,
+         version: 0
+      }
+   }
+
+   // every name that could be looked up from this scope, sorted and without duplicates
+   pub fn visible_keys(&self) -> Vec<String> {
+      let mut keys: Vec<String> = self.values.keys().map(|key| key.as_slice().to_string()).collect();
+      match self.parent {
+         Some(ref env) => keys.push_all_move(env.borrow().visible_keys()),
+         None => {}
+      }
+      keys.sort();
+      keys.dedup();
+      keys
+   }
+
+   // in debug mode, points out the visible name closest to a misspelled one
+   pub fn suggestion(&self, name: &str) -> String {
+      if self.context.borrow().mode != Debug {
+         return String::new();
+      }
+      let mut best: Option<(uint, String)> = None;
+      for key in self.visible_keys().move_iter() {
+         let distance = Environment::edit_distance(name, key.as_slice());
+         let better = match best {
+            Some((ref best_distance, _)) => distance < *best_distance,
+            None => true
+         };
+         if distance <= cmp::max(1, name.char_len() / 3) && better {
+            best = Some((distance, key));
+         }
+      }
+      match best {
+         Some((_, key)) => format!(" (did you mean {}?)", key),
+         None => String::new()
+      }
+   }
+
+   // levenshtein distance in characters
+   fn edit_distance(left: &str, right: &str) -> uint {
+      let right: Vec<char> = right.chars().collect();
+      let mut prev: Vec<uint> = range(0, right.len() + 1).collect();
+      for (i, lch) in left.chars().enumerate() {
+         let mut current = vec!(i + 1);
+         for (j, rch) in right.iter().enumerate() {
+            let cost = if lch == *rch { 0 } else { 1 };
+            let value = cmp::min(cmp::min(prev[j + 1] + 1, current[j] + 1), prev[j] + cost);
+            current.push(value);
+         }
+         prev = current;
+      }
+      prev[right.len()]
+   }
+
+   // binds key in this scope, shadowing any binding of the same name in a parent
+   pub fn insert(&mut self, key: Name, value: EnvValue) {
+      self.insert_slot(key, Rc::new(RefCell::new(value)));
+   }
+
+   // binds key to a slot that may be shared with other scopes, as imports are
+   pub fn insert_slot(&mut self, key: Name, slot: Rc<RefCell<EnvValue>>) {
+      self.note_binding(&key);
+      self.version += 1;
+      self.values.insert(key, slot);
+   }
+
+   // the first binding of a name in the builtins scope makes it a builtin; any later binding of
+   // that name, there or elsewhere, means calls the optimizer resolved to it cannot be trusted
+   fn note_binding(&self, key: &Name) {
+      let mut context = self.context.borrow_mut();
+      if self.parent.is_none() && !self.values.contains_key(key) {
+         context.builtin_names.insert(key.clone());
+      } else if context.builtin_names.contains(key) {
+         context.shadowed.insert(key.clone());
+      }
+   }
+
+   pub fn find(&self, key: &Name) -> Option<EnvValue> {
+      self.with_value(key, |value| value.clone())
+   }
+
+   // lets func look at the value bound to key without copying it out of its slot
+   pub fn with_value<T>(&self, key: &Name, func: |&EnvValue| -> T) -> Option<T> {
+      self.find_slot(key).map(|slot| func(&*slot.borrow()))
+   }
+
+   // every closure that captured the scope holding key shares this slot; the scopes are walked
+   // one at a time, borrowing each parent only while its own map is searched
+   pub fn find_slot(&self, key: &Name) -> Option<Rc<RefCell<EnvValue>>> {
+      match self.values.find(key) {
+         Some(slot) => return Some(slot.clone()),
+         None => {}
+      }
+      let mut parent = self.parent.clone();
+      loop {
+         let env = match parent {
+            Some(env) => env,
+            None => return None
+         };
+         let next = {
+            let scope = env.borrow();
+            match scope.values.find(key) {
+               Some(slot) => return Some(slot.clone()),
+               None => scope.parent.clone()
+            }
+         };
+         parent = next;
+      }
+   }
+
+   pub fn replace(&mut self, key: Name, value: EnvValue) -> bool {
+      match self.find_slot(&key) {
+         Some(slot) => {
+            self.assign_slot(&key, &slot, value);
+            true
+         }
+         None => false
+      }
+   }
+
+   // what replace does once the slot key refers to from this scope has been found
+   pub fn assign_slot(&self, key: &Name, slot: &Rc<RefCell<EnvValue>>, value: EnvValue) {
+      self.note_binding(key);
+      *slot.borrow_mut() = value;
+   }
+
+   // the arity is checked by the builtin itself when it runs, and by --check beforehand, so the
+   // two have to agree
+   fn builtin(&mut self, name: &str, min: uint, max: uint, func: BuiltinFn) {
+      self.context.borrow_mut().arities.insert(Name::new(name), (min, max));
+      self.insert(Name::new(name), EnvCode(func));
+   }
+
+   pub fn populate_default(&mut self) {
+      self.insert(Name::new("FILE"), Value(String(StringAst::new("".to_string()))));
+      self.insert(Name::new("ARGV"), Value(Array(ArrayAst::new(vec!()))));
+      self.builtin("+", 0, UNLIMITED, Environment::add);
+      self.builtin("=", 2, UNLIMITED, Environment::equal);
+      self.builtin("<", 2, UNLIMITED, Environment::less);
+      self.builtin(">", 2, UNLIMITED, Environment::greater);
+      self.builtin("<=", 2, UNLIMITED, Environment::less_equal);
+      self.builtin(">=", 2, UNLIMITED, Environment::greater_equal);
+      self.builtin("print", 0, UNLIMITED, Environment::print);
+      self.builtin("println", 0, UNLIMITED, Environment::println);
+      self.builtin("eprint", 0, UNLIMITED, Environment::eprint);
+      self.builtin("eprintln", 0, UNLIMITED, Environment::eprintln);
+      self.builtin("str", 0, UNLIMITED, Environment::strexpr);
+      self.builtin("format", 1, UNLIMITED, Environment::format);
+      self.builtin("read", 1, 1, Environment::read);
+      self.builtin("if", 2, 3, Environment::ifexpr);
+      self.builtin("while", 1, UNLIMITED, Environment::whileexpr);
+      self.builtin("when", 1, UNLIMITED, Environment::when);
+      self.builtin("case", 1, UNLIMITED, Environment::case);
+      self.builtin("quote", 1, 1, Environment::quote);
+      self.builtin("and", 0, UNLIMITED, Environment::and);
+      self.builtin("or", 0, UNLIMITED, Environment::or);
+      self.builtin("define", 2, 2, Environment::define);
+      self.builtin("fn", 1, UNLIMITED, Environment::function);
+      self.builtin("get", 2, 2, Environment::get);
+      self.builtin("set", 3, 3, Environment::set);
+      self.builtin("set!", 2, 2, Environment::assign);
+      self.builtin("defined?", 1, 1, Environment::is_defined);
+      self.builtin("push", 2, 2, Environment::push);
+      self.builtin("pop", 1, 1, Environment::pop);
+      self.builtin("insert", 3, 3, Environment::insert);
+      self.builtin("remove-at", 2, 2, Environment::remove_at);
+      self.builtin("slice", 2, 3, Environment::slice);
+      self.builtin("concat", 0, UNLIMITED, Environment::concat);
+      self.builtin("map", 2, UNLIMITED, Environment::map);
+      self.builtin("filter", 2, 2, Environment::filter);
+      self.builtin("reduce", 3, 3, Environment::reduce);
+      self.builtin("range", 1, 3, Environment::range);
+      self.builtin("any?", 2, 2, Environment::any);
+      self.builtin("all?", 2, 2, Environment::all);
+      self.builtin("reverse", 1, 1, Environment::reverse);
+      self.builtin("sort", 1, 2, Environment::sort);
+      self.builtin("first", 1, 1, Environment::first);
+      self.builtin("last", 1, 1, Environment::last);
+      self.builtin("rest", 1, 1, Environment::rest);
+      self.builtin("zip", 1, UNLIMITED, Environment::zip);
+      self.builtin("flatten", 1, 2, Environment::flatten);
+      self.builtin("take", 2, 2, Environment::take);
+      self.builtin("drop", 2, 2, Environment::drop);
+      self.builtin("partition", 2, 2, Environment::partition);
+      self.builtin("unique", 1, 1, Environment::unique);
+      self.builtin("count", 2, 2, Environment::count);
+      self.builtin("sum", 1, 1, Environment::sum);
+      self.builtin("product", 1, 1, Environment::product);
+      self.builtin("avg", 1, 1, Environment::avg);
+      self.builtin("make-array", 1, 2, Environment::make_array);
+      self.builtin("copy", 1, 1, Environment::copy);
+      self.builtin("get-in", 2, 2, Environment::get_in);
+      self.builtin("set-in", 3, 3, Environment::set_in);
+      self.builtin("resize", 2, 3, Environment::resize);
+      self.builtin("cons", 2, 2, Environment::cons);
+      self.builtin("head", 1, 1, Environment::head);
+      self.builtin("tail", 1, 1, Environment::tail);
+      self.builtin("list", 0, UNLIMITED, Environment::list);
+      self.builtin("dict", 0, UNLIMITED, Environment::dict);
+      self.builtin("dget", 2, 3, Environment::dget);
+      self.builtin("dset", 3, 3, Environment::dset);
+      self.builtin("dremove", 2, 2, Environment::dremove);
+      self.builtin("keys", 1, 1, Environment::keys);
+      self.builtin("values", 1, 1, Environment::values);
+      self.builtin("has-key?", 2, 2, Environment::has_key);
+      self.builtin("merge", 0, UNLIMITED, Environment::merge);
+      self.builtin("each-pair", 2, 2, Environment::each_pair);
+      self.builtin("pairs", 1, 1, Environment::pairs);
+      self.builtin("integer?", 1, 1, Environment::is_integer);
+      self.builtin("float?", 1, 1, Environment::is_float);
+      self.builtin("number?", 1, 1, Environment::is_number);
+      self.builtin("string?", 1, 1, Environment::is_string);
+      self.builtin("symbol?", 1, 1, Environment::is_symbol);
+      self.builtin("array?", 1, 1, Environment::is_array);
+      self.builtin("list?", 1, 1, Environment::is_list);
+      self.builtin("dict?", 1, 1, Environment::is_dict);
+      self.builtin("boolean?", 1, 1, Environment::is_boolean);
+      self.builtin("nil?", 1, 1, Environment::is_nil);
+      self.builtin("fn?", 1, 1, Environment::is_fn);
+      self.builtin("defstruct", 2, 2, Environment::defstruct);
+      self.builtin("len", 1, 1, Environment::len);
+      self.builtin("substr", 2, 3, Environment::substr);
+      self.builtin("split", 2, 2, Environment::split);
+      self.builtin("join", 2, 2, Environment::join);
+      self.builtin("upper", 1, 1, Environment::upper);
+      self.builtin("lower", 1, 1, Environment::lower);
+      self.builtin("trim", 1, 1, Environment::trim);
+      self.builtin("trim-left", 1, 1, Environment::trim_left);
+      self.builtin("trim-right", 1, 1, Environment::trim_right);
+      self.builtin("contains?", 2, 2, Environment::contains);
+      self.builtin("index-of", 2, 3, Environment::index_of);
+      self.builtin("starts-with?", 2, 2, Environment::starts_with);
+      self.builtin("ends-with?", 2, 2, Environment::ends_with);
+      self.builtin("replace", 3, 4, Environment::replace_str);
+      self.builtin("re-match", 2, 2, Environment::re_match);
+      self.builtin("re-find-all", 2, 2, Environment::re_find_all);
+      self.builtin("re-replace", 3, 3, Environment::re_replace);
+      self.builtin("re-split", 2, 2, Environment::re_split);
+      self.builtin("json-parse", 1, 1, Environment::json_parse);
+      self.builtin("json-encode", 1, 2, Environment::json_encode);
+      self.builtin("chars", 1, 1, Environment::chars);
+      self.builtin("string-from-chars", 1, 1, Environment::string_from_chars);
+      self.builtin("repeat", 2, 2, Environment::repeat);
+      self.builtin("pad-left", 2, 3, Environment::pad_left);
+      self.builtin("pad-right", 2, 3, Environment::pad_right);
+      self.builtin("getenv", 1, 1, Environment::getenv);
+      self.builtin("setenv", 2, 2, Environment::setenv);
+      self.builtin("read-file", 1, 1, Environment::read_file);
+      self.builtin("write-file", 2, 2, Environment::write_file);
+      self.builtin("append-file", 2, 2, Environment::append_file);
+      self.builtin("file-exists?", 1, 1, Environment::file_exists);
+      self.builtin("read-line", 0, 0, Environment::read_line);
+      self.builtin("read-all", 0, 0, Environment::read_all);
+      self.builtin("exit", 0, 1, Environment::exit);
+      self.builtin("exec", 1, 2, Environment::exec);
+      self.builtin("system", 1, 2, Environment::system);
+      self.builtin("now", 0, 0, Environment::now);
+      self.builtin("clock", 0, 0, Environment::clock);
+      self.builtin("sleep", 1, 1, Environment::sleep);
+      self.builtin("time", 1, 1, Environment::time_expr);
+      self.builtin("export", 0, UNLIMITED, Environment::export);
+      self.builtin("import", 1, UNLIMITED, Environment::importexpr);
+      self.builtin("type", 1, 1, Environment::type_obj);
+      self.builtin("assert", 1, 2, Environment::assert);
+   }
+
+   fn add(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("add");
+      let args = Environment::take_args(stack, ops);
+      Environment::accumulate("+", args.as_slice(), 0, |acc, val| acc + val, |acc, val| acc + val)
+   }
+
+   // integers stay integers until a float shows up, after which the result is a float
+   fn accumulate(name: &str, values: &[ExprAst], init: i64, int_op: |i64, i64| -> i64, float_op: |f64, f64| -> f64) -> InterpResult<ExprAst> {
+      let mut int_acc = init;
+      let mut float_acc: Option<f64> = None;
+      for (idx, value) in values.iter().enumerate() {
+         match *value {
+            Integer(ref ast) => match float_acc {
+               Some(acc) => float_acc = Some(float_op(acc, ast.value as f64)),
+               None => int_acc = int_op(int_acc, ast.value)
+            },
+            Float(ref ast) => float_acc = Some(float_op(float_acc.unwrap_or(int_acc as f64), ast.value)),
+            ref other => throw!(TypeError, "{} expected a number at index {} but found {}", name, idx, Environment::type_name(other))
+         }
+      }
+      Ok(match float_acc {
+         Some(acc) => Float(FloatAst::new(acc)),
+         None => Integer(IntegerAst::new(int_acc))
+      })
+   }
+
+   fn print(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("print");
+      let destination = env.borrow().context.borrow().output.clone();
+      Environment::write_values(destination, stack, ops, false)
+   }
+
+   fn println(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("println");
+      let destination = env.borrow().context.borrow().output.clone();
+      Environment::write_values(destination, stack, ops, true)
+   }
+
+   fn eprint(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("eprint");
+      let destination = env.borrow().context.borrow().error_output.clone();
+      Environment::write_values(destination, stack, ops, false)
+   }
+
+   fn eprintln(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("eprintln");
+      let destination = env.borrow().context.borrow().error_output.clone();
+      Environment::write_values(destination, stack, ops, true)
+   }
+
+   // returns what was written so that (define msg (print ...)) is useful
+   fn write_values(destination: Output, stack: &mut Vec<ExprAst>, ops: uint, newline: bool) -> InterpResult<ExprAst> {
+      let mut output = String::new();
+      for value in Environment::take_args(stack, ops).iter() {
+         output.push_str(try!(Environment::format_value(value)).as_slice());
+      }
+      if newline {
+         output.push_char('\n');
+      }
+      try!(Environment::write_output(destination, output.as_slice()));
+      Ok(String(StringAst::new(output)))
+   }
+
+   fn write_output(destination: Output, text: &str) -> InterpResult<()> {
+      let mut writer = destination.writer.borrow_mut();
+      let result = match writer.write_str(text) {
+         Ok(()) => writer.flush(),
+         Err(f) => Err(f)
+      };
+      match result {
+         Ok(()) => Ok(()),
+         Err(f) => throw!(IoError, "cannot write output: {}", Environment::describe_io_error(&f))
+      }
+   }
+
+   // (time expr) reports how long expr took on the error output and gives back its value
+   fn time_expr(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("time");
+      if ops != 1 {
+         throw!(ArityError, "time only takes one expression");
+      }
+      let expr = Environment::take_args(stack, ops).pop().unwrap();
+      let source = env.borrow().context.borrow().clock.clone();
+      let start = source.clock.monotonic();
+      let value = try!(Environment::eval_operand(env.clone(), stack, &expr));
+      let elapsed = (source.clock.monotonic() - start) * 1000.0;
+      let destination = env.borrow().context.borrow().error_output.clone();
+      try!(Environment::write_output(destination, format!("time: {} took {} ms\n", expr.source(), f64::to_str_digits(elapsed, 3)).as_slice()));
+      Ok(value)
+   }
+
+   fn strexpr(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("str");
+      let mut result = String::new();
+      for value in Environment::take_args(stack, ops).iter() {
+         result.push_str(try!(Environment::format_value(value)).as_slice());
+      }
+      Ok(String(StringAst::new(result)))
+   }
+
+   // parses a string holding exactly one value and gives back what was written, unevaluated, so
+   // that anything print shows for symbols, numbers and the like reads back as the same value
+   fn read(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("read");
+      if ops != 1 {
+         throw!(ArityError, "read only takes one value (string)");
+      }
+      let text = try!(Environment::expect_string("read", &Environment::take_args(stack, ops)[0]));
+      match Parser::new().parse_code(text.clone()) {
+         Ok(Root(mut root)) => {
+            if root.asts.len() != 1 {
+               throw!(ValueError, "read expected one value in {} but found {}", text, root.asts.len());
+            }
+            // data, the same as quote would give, so that nothing read is ever run by accident
+            Ok(Environment::quoted(root.asts.pop().unwrap(), true))
+         }
+         Ok(_) => unreachable!(),
+         Err(f) => throw!(ValueError, "read could not parse {}: {}", text, f.desc)
+      }
+   }
+
+   fn format(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("format");
+      if ops == 0 {
+         throw!(ArityError, "format needs at least a format string");
+      }
+      let args = Environment::take_args(stack, ops);
+      try!(Environment::expect_string("format", &args[0]));
+      let template = try!(Environment::format_value(&args[0]));
+      let mut values = args.slice_from(1).iter();
+      let mut used = 0u;
+      let mut result = String::new();
+      let mut chars = template.as_slice().chars();
+      loop {
+         match chars.next() {
+            Some('{') => match chars.next() {
+               Some('{') => result.push_char('{'),
+               Some('}') => {
+                  match values.next() {
+                     Some(value) => result.push_str(try!(Environment::format_value(value)).as_slice()),
+                     None => throw!(ValueError, "format string has more placeholders than the {} argument(s) given", ops - 1)
+                  }
+                  used += 1;
+               }
+               _ => throw!(ValueError, "format string has an unmatched '{{' (use '{{{{' for a literal brace)")
+            },
+            Some('}') => match chars.next() {
+               Some('}') => result.push_char('}'),
+               _ => throw!(ValueError, "format string has an unmatched '}}' (use '}}}}' for a literal brace)")
+            },
+            Some(ch) => result.push_char(ch),
+            None => break
+         }
+      }
+      if used < ops - 1 {
+         throw!(ValueError, "format string has {} placeholder(s) but {} argument(s) were given", used, ops - 1);
+      }
+      Ok(String(StringAst::new(result)))
+   }
+
+   // shared by print and str so that the two always agree on how values look
+   fn format_value(value: &ExprAst) -> InterpResult<String> {
+      Environment::format_nested(value, &mut vec!())
+   }
+
+   // open holds the arrays and dicts being formatted further out, so that one which contains
+   // itself comes out as ... rather than going round forever
+   fn format_nested(value: &ExprAst, open: &mut Vec<uint>) -> InterpResult<String> {
+      Ok(match *value {
+         Integer(ref ast) => ast.value.to_string(),
+         Float(ref ast) => f64::to_str_digits(ast.value, 15),
+         String(ref ast) => ast.string.clone(),
+         Symbol(ref ast) => format!("'{}", ast.value),
+         Boolean(ref ast) => ast.value.to_string(),
+         Nil(_) => "nil".to_string(),
+         Array(ref ast) => {
+            let id = &*ast.items as *const RefCell<Vec<ExprAst>> as uint;
+            if open.contains(&id) {
+               return Ok("...".to_string());
+            }
+            open.push(id);
+            let mut items = vec!();
+            for item in ast.items.borrow().iter() {
+               items.push(try!(Environment::format_nested(item, open)));
+            }
+            open.pop();
+            format!("[{}]", items.connect(" "))
+         }
+         List(ref ast) => {
+            let mut items = vec!();
+            for item in ast.iter() {
+               items.push(try!(Environment::format_nested(item, open)));
+            }
+            format!("({})", items.connect(" "))
+         }
+         Dict(ref ast) => {
+            let id = &*ast.entries as *const RefCell<collections::HashMap<String, ExprAst>> as uint;
+            if open.contains(&id) {
+               return Ok("...".to_string());
+            }
+            open.push(id);
+            let entries = ast.entries.borrow();
+            let mut items = vec!();
+            for key in ast.sorted_keys().iter() {
+               items.push(format!("{}: {}", key, try!(Environment::format_nested(entries.find(key).unwrap(), open))));
+            }
+            open.pop();
+            format!("{{{}}}", items.connect(", "))
+         }
+         Struct(ref ast) => {
+            let mut items = vec!();
+            for (field, value) in ast.fields.iter().zip(ast.values.iter()) {
+               items.push(format!("{}: {}", field, try!(Environment::format_nested(value, open))));
+            }
+            format!("{}{{{}}}", ast.name, items.connect(", "))
+         }
+         super::ast::Code(ref ast) => format!("<fn {}>", Array(ast.params.clone()).source()),
+         Builtin(ref ast) => format!("<builtin {}>", ast.name),
+         Host(ref ast) => format!("<builtin {}>", ast.name),
+         ref other => throw!(TypeError, "cannot format a value of type {}", Environment::type_name(other))
+      })
+   }
+
+   // should be able to take stuff like (define var value)
+   fn define(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("define");
+      let ops = ops;
+      if ops != 2 {
+         throw!(ArityError, "define can only take two arguments");
+      }
+      let valast = stack.pop().unwrap();
+      let name = match stack.pop().unwrap() {
+         Ident(ref ast) => ast.value.clone(),
+         _ => throw!(TypeError, "define must take ident for first argument")
+      };
+      try!(Environment::check_unbound(&env, &name));
+      env.clone().borrow_mut().insert(name.clone(), Value(valast.clone()));
+      Ok(valast)
+   }
+
+   // define only makes new bindings; changing one that is already in this scope is set!'s job
+   fn check_unbound(env: &Rc<RefCell<Environment>>, name: &Name) -> InterpResult<()> {
+      if env.borrow().values.contains_key(name) {
+         throw!(ValueError, "{} is already defined in this scope, use set! to change it", name);
+      }
+      Ok(())
+   }
+
+   pub fn function(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("function");
+      if ops == 0 {
+         throw!(ArityError, "fn need at least one argument");
+      }
+      let mut args = Environment::take_args(stack, ops).move_iter();
+      let params = match args.next().unwrap() {
+         Array(ast) => ast,
+         other => throw!(TypeError, "fn expected an array of parameters but found {}", Environment::type_name(&other))
+      };
+      try!(Environment::check_params(&params));
+      Ok(super::ast::Code(CodeAst::new(params, args.collect(), env.clone())))
+   }
+
+   // (defstruct point [x y]) defines point, point?, point-x and point-y in the current scope
+   fn defstruct(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("defstruct");
+      if ops != 2 {
+         throw!(ArityError, "defstruct only takes two values (name and field array)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let mut fields = vec!();
+      for field in try!(Environment::array_items("defstruct", args.pop().unwrap())).move_iter() {
+         match field {
+            Ident(ast) => fields.push(ast.value),
+            other => throw!(TypeError, "defstruct fields must be names but found {}", Environment::type_name(&other))
+         }
+      }
+      let name = match args.pop().unwrap() {
+         Ident(ast) => ast.value,
+         other => throw!(TypeError, "defstruct expected a name but found {}", Environment::type_name(&other))
+      };
+      let tag = Symbol(SymbolAst::new(name.clone()));
+      let instance = Ident(IdentAst::new(Name::new("instance")));
+      let params: Vec<ExprAst> = fields.iter().map(|field| Ident(IdentAst::new(field.clone()))).collect();
+
+      let mut operands = vec!(tag.clone(), Array(ArrayAst::new(fields.iter().map(|field| Symbol(SymbolAst::new(field.clone()))).collect())));
+      operands.push_all(params.as_slice());
+      let mut defs = vec!((name.clone(), Environment::struct_code(&env, params, "%struct-new", Environment::struct_new, operands)));
+      defs.push((Name::new(format!("{}?", name).as_slice()), Environment::struct_code(&env, vec!(instance.clone()), "%struct-is", Environment::struct_is, vec!(tag.clone(), instance.clone()))));
+      for field in fields.iter() {
+         let operands = vec!(tag.clone(), Symbol(SymbolAst::new(field.clone())), instance.clone());
+         defs.push((Name::new(format!("{}-{}", name, field).as_slice()), Environment::struct_code(&env, vec!(instance.clone()), "%struct-get", Environment::struct_get, operands)));
+      }
+      // nothing is bound unless every name is free, so a clash cannot leave half a struct behind
+      for &(ref key, _) in defs.iter() {
+         try!(Environment::check_unbound(&env, key));
+      }
+      for (key, value) in defs.move_iter() {
+         env.borrow_mut().insert(key, Value(value));
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   // builds (fn [params...] (op operands...)) for one of the functions defstruct generates; the
+   // helpers are never bound to a name, so the call carries the builtin itself and nothing a
+   // script defines can stand in for it
+   fn struct_code(env: &Rc<RefCell<Environment>>, params: Vec<ExprAst>, op: &str, func: BuiltinFn, operands: Vec<ExprAst>) -> ExprAst {
+      let mut sast = SexprAst::new(IdentAst::new(Name::new(op)), operands);
+      sast.builtin = Some(func);
+      let body = Sexpr(sast);
+      super::ast::Code(CodeAst::new(ArrayAst::new(params), vec!(body), env.clone()))
+   }
+
+   // (%struct-new 'name ['field ...] value ...), called by defstruct constructors
+   fn struct_new(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("%struct-new");
+      if ops < 2 {
+         throw!(ArityError, "%struct-new takes a name, a field array and the values");
+      }
+      let mut args = Environment::take_args(stack, ops).move_iter();
+      let name = try!(Environment::struct_name("%struct-new", args.next().unwrap()));
+      let mut fields = vec!();
+      for field in try!(Environment::array_items("%struct-new", args.next().unwrap())).move_iter() {
+         fields.push(try!(Environment::struct_name("%struct-new", field)));
+      }
+      let values: Vec<ExprAst> = args.collect();
+      if values.len() != fields.len() {
+         throw!(ArityError, "{} takes {} values but was given {}", name, fields.len(), values.len());
+      }
+      Ok(Struct(StructAst::new(name, fields, values)))
+   }
+
+   // (%struct-get 'name 'field instance)
+   fn struct_get(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("%struct-get");
+      if ops != 3 {
+         throw!(ArityError, "%struct-get only takes three values (name, field, instance)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let instance = args.pop().unwrap();
+      let field = try!(Environment::struct_name("%struct-get", args.pop().unwrap()));
+      let name = try!(Environment::struct_name("%struct-get", args.pop().unwrap()));
+      match instance {
+         Struct(ast) => {
+            if ast.name != name {
+               throw!(TypeError, "{}-{} expected a {} but found a {}", name, field, name, ast.name);
+            }
+            match ast.fields.iter().position(|other| *other == field) {
+               Some(idx) => Ok(ast.values[idx].clone()),
+               None => throw!(ValueError, "{} has no field {}", name, field)
+            }
+         }
+         other => throw!(TypeError, "{}-{} expected a {} but found {}", name, field, name, Environment::type_name(&other))
+      }
+   }
+
+   // (%struct-is 'name value)
+   fn struct_is(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("%struct-is");
+      if ops != 2 {
+         throw!(ArityError, "%struct-is only takes two values (name, value)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let value = args.pop().unwrap();
+      let name = try!(Environment::struct_name("%struct-is", args.pop().unwrap()));
+      Ok(Boolean(BooleanAst::new(match value {
+         Struct(ast) => ast.name == name,
+         _ => false
+      })))
+   }
+
+   fn struct_name(name: &str, value: ExprAst) -> InterpResult<Name> {
+      match value {
+         Symbol(ast) => Ok(ast.value),
+         other => throw!(TypeError, "{} expected a symbol but found {}", name, Environment::type_name(&other))
+      }
+   }
+
+   // once a parameter has a default, every later one needs one too, and a rest parameter must come last
+   pub fn check_params(params: &ArrayAst) -> InterpResult<()> {
+      let params = params.items.borrow();
+      let mut defaulted = None;
+      for (idx, param) in params.iter().enumerate() {
+         match *param {
+            Ident(ref ast) => {
+               if ast.value.as_slice().ends_with("...") {
+                  if idx + 1 != params.len() {
+                     throw!(ValueError, "rest parameter {} must be the last parameter", ast.value);
+                  }
+               } else {
+                  match defaulted {
+                     Some(ref name) => throw!(ValueError, "parameter {} without a default follows parameter {} with one", ast.value, name),
+                     None => {}
+                  }
+               }
+            }
+            Sexpr(ref ast) => {
+               if ast.operands.len() != 1 {
+                  throw!(ValueError, "parameter {} must have exactly one default value", ast.op.value);
+               }
+               defaulted = Some(ast.op.value.clone());
+            }
+            ref other => throw!(TypeError, "fn parameters must be names but found {}", Environment::type_name(other))
+         }
+      }
+      Ok(())
+   }
+
+   fn get(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("get");
+      if ops != 2 {
+         throw!(ArityError, "get only takes two values (list/array and index)");
+      }
+      let idx = try!(Environment::expect_integer("get", &stack.pop().unwrap()));
+      match stack.pop().unwrap() {
+         Array(arr) => {
+            let items = arr.items.borrow();
+            let idx = try!(Environment::normalize_index("get", idx, items.len(), false));
+            Ok(items[idx].clone())
+         }
+         List(list) => {
+            let items: Vec<&ExprAst> = list.iter().collect();
+            let idx = try!(Environment::normalize_index("get", idx, items.len(), false));
+            Ok(items[idx].clone())
+         }
+         other => throw!(TypeError, "get expected an array or list but found {}", Environment::type_name(&other))
+      }
+   }
+
+   // looks the name up through every enclosing scope, just like evaluating it would
+   fn is_defined(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("defined?");
+      if ops != 1 {
+         throw!(ArityError, "defined? only takes one value (symbol)");
+      }
+      let name = match Environment::take_args(stack, ops).pop().unwrap() {
+         Symbol(ast) => ast.value,
+         other => throw!(TypeError, "defined? expected a symbol but found {}", Environment::type_name(&other))
+      };
+      let found = env.borrow().find_slot(&name).is_some();
+      Ok(Boolean(BooleanAst::new(found)))
+   }
+
+   // changes an existing binding, wherever in the enclosing scopes it is, and returns the new value
+   pub fn assign(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("set!");
+      if ops != 2 {
+         throw!(ArityError, "set! only takes two values (name and value)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let value = args.pop().unwrap();
+      let name = match args.pop().unwrap() {
+         Ident(ast) => ast.value,
+         other => throw!(TypeError, "set! expected a variable name but found {}", Environment::type_name(&other))
+      };
+      if !env.borrow_mut().replace(name.clone(), Value(value.clone())) {
+         throw!(UnknownIdent, "cannot set! undeclared variable {}", name);
+      }
+      Ok(value)
+   }
+
+   fn set(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("set");
+      if ops == 2 {
+         throw!(ArityError, "set takes an array, an index and a value; use set! to assign to a variable");
+      }
+      if ops != 3 {
+         throw!(ArityError, "set only takes three values (list/array, index, value)");
+      }
+      let arrast = try!(Environment::expect_array("set", Environment::take_arg(stack, 3)));
+      let idx = try!(Environment::expect_integer("set", &Environment::take_arg(stack, 2)));
+      let value = stack.pop().unwrap();
+      let idx = try!(Environment::normalize_index("set", idx, arrast.items.borrow().len(), false));
+      *arrast.items.borrow_mut().get_mut(idx) = value;
+      Ok(Nil(NilAst::new()))
+   }
+
+   // growing an array has to be asked for explicitly, set will not do it
+   fn resize(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("resize");
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "resize takes an array name, a length, and an optional fill value");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let fill = if ops == 3 { args.pop().unwrap() } else { Nil(NilAst::new()) };
+      let len = try!(Environment::expect_integer("resize", &args.pop().unwrap()));
+      if len < 0 {
+         throw!(ValueError, "resize length must not be negative");
+      }
+      let arrast = try!(Environment::expect_array("resize", args.pop().unwrap()));
+      let mut items = arrast.items.borrow_mut();
+      let len = len as uint;
+      if len < items.len() {
+         items.truncate(len);
+      } else {
+         for _ in range(items.len(), len) {
+            items.push(Environment::deep_copy(&fill));
+         }
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   fn get_in(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("get-in");
+      if ops != 2 {
+         throw!(ArityError, "get-in only takes two values (array and path)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let path = try!(Environment::array_items("get-in", args.pop().unwrap()));
+      if path.len() == 0 {
+         return Ok(args.pop().unwrap());
+      }
+      let (arrast, idx) = try!(Environment::walk_path("get-in", args.pop().unwrap(), path.as_slice()));
+      let items = arrast.items.borrow();
+      Ok(items[idx].clone())
+   }
+
+   fn set_in(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("set-in");
+      if ops != 3 {
+         throw!(ArityError, "set-in only takes three values (array name, path, value)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let value = args.pop().unwrap();
+      let path = try!(Environment::array_items("set-in", args.pop().unwrap()));
+      if path.len() == 0 {
+         throw!(ArityError, "set-in needs a path with at least one index");
+      }
+      let (arrast, idx) = try!(Environment::walk_path("set-in", args.pop().unwrap(), path.as_slice()));
+      *arrast.items.borrow_mut().get_mut(idx) = value;
+      Ok(Nil(NilAst::new()))
+   }
+
+   // follows every index in path but the last, returning the innermost array and the last index
+   fn walk_path(name: &str, target: ExprAst, path: &[ExprAst]) -> InterpResult<(ArrayAst, uint)> {
+      let mut current = target;
+      for (segment, item) in path.iter().enumerate() {
+         let arrast = match current {
+            Array(ast) => ast,
+            other => throw!(TypeError, "{} path segment {} reached a value of type {} rather than an array", name, segment, Environment::type_name(&other))
+         };
+         let idx = try!(Environment::expect_integer(name, item));
+         let len = arrast.items.borrow().len();
+         let resolved = match Environment::normalize_index(name, idx, len, false) {
+            Ok(resolved) => resolved,
+            Err(_) => throw!(IndexError, "{} path segment {} (index {}) is out of bounds for an array of length {}", name, segment, idx, len)
+         };
+         if segment + 1 == path.len() {
+            return Ok((arrast, resolved));
+         }
+         current = arrast.items.borrow()[resolved].clone();
+      }
+      unreachable!()
+   }
+
+   fn push(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("push");
+      if ops != 2 {
+         throw!(ArityError, "push only takes two values (array and value)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let value = args.pop().unwrap();
+      let arrast = try!(Environment::expect_array("push", args.pop().unwrap()));
+      arrast.items.borrow_mut().push(value);
+      Ok(Nil(NilAst::new()))
+   }
+
+   // popping an empty array gives nil rather than an error
+   fn pop(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("pop");
+      if ops != 1 {
+         throw!(ArityError, "pop only takes one value (array)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let arrast = try!(Environment::expect_array("pop", args.pop().unwrap()));
+      let value = arrast.items.borrow_mut().pop();
+      Ok(match value {
+         Some(value) => value,
+         None => Nil(NilAst::new())
+      })
+   }
+
+   fn insert(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("insert");
+      if ops != 3 {
+         throw!(ArityError, "insert only takes three values (array, index, value)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let value = args.pop().unwrap();
+      let idx = try!(Environment::expect_integer("insert", &args.pop().unwrap()));
+      let arrast = try!(Environment::expect_array("insert", args.pop().unwrap()));
+      let mut items = arrast.items.borrow_mut();
+      let idx = try!(Environment::normalize_index("insert", idx, items.len(), true));
+      items.insert(idx, value);
+      Ok(Nil(NilAst::new()))
+   }
+
+   fn remove_at(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("remove-at");
+      if ops != 2 {
+         throw!(ArityError, "remove-at only takes two values (array and index)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let idx = try!(Environment::expect_integer("remove-at", &args.pop().unwrap()));
+      let arrast = try!(Environment::expect_array("remove-at", args.pop().unwrap()));
+      let mut items = arrast.items.borrow_mut();
+      let idx = try!(Environment::normalize_index("remove-at", idx, items.len(), false));
+      Ok(items.remove(idx).unwrap())
+   }
+
+   // unlike get, out of range indices are clamped to the ends of the array
+   fn slice(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("slice");
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "slice takes an array, a start index, and an optional end index");
+      }
+      let args = Environment::take_args(stack, ops);
+      let items = try!(Environment::array_items("slice", args[0].clone()));
+      let len = items.len();
+      let clamp = |idx: i64| -> uint {
+         match Environment::normalize_index("slice", idx, len, true) {
+            Ok(idx) => idx,
+            Err(_) => if idx < 0 { 0 } else { len }
+         }
+      };
+      let start = clamp(try!(Environment::expect_integer("slice", &args[1])));
+      let end =
+         if ops == 3 {
+            clamp(try!(Environment::expect_integer("slice", &args[2])))
+         } else {
+            len
+         };
+      if start >= end {
+         Ok(Array(ArrayAst::new(vec!())))
+      } else {
+         Ok(Array(ArrayAst::new(items.slice(start, end).to_vec())))
+      }
+   }
+
+   // concatenates arrays, or strings if the first operand is a string
+   fn concat(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("concat");
+      let args = Environment::take_args(stack, ops);
+      match args.as_slice().head() {
+         Some(&String(_)) => {
+            let mut result = String::new();
+            for arg in args.iter() {
+               result.push_str(try!(Environment::expect_string("concat", arg)).as_slice());
+            }
+            Ok(String(StringAst::new(result)))
+         }
+         _ => {
+            let mut result = vec!();
+            for arg in args.iter() {
+               result.push_all_move(try!(Environment::array_items("concat", arg.clone())));
+            }
+            Ok(Array(ArrayAst::new(result)))
+         }
+      }
+   }
+
+   // with several arrays, f is called with one element from each and the shortest array wins
+   fn map(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("map");
+      if ops < 2 {
+         throw!(ArityError, "map takes a function and at least one array");
+      }
+      let args = Environment::take_args(stack, ops);
+      let mut arrs = vec!();
+      for arg in args.slice_from(1).iter() {
+         arrs.push(try!(Environment::array_items("map", arg.clone())));
+      }
+      let len = arrs.iter().map(|items| items.len()).min().unwrap();
+      let mut result = Vec::with_capacity(len);
+      for i in range(0, len) {
+         let fargs = arrs.iter().map(|items| items[i].clone()).collect();
+         result.push(try!(Interpreter::call_value(env.clone(), stack, &args[0], fargs)));
+      }
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   fn filter(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("filter");
+      if ops != 2 {
+         throw!(ArityError, "filter only takes two values (predicate and array)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let items = try!(Environment::array_items("filter", args[1].clone()));
+      let mut result = vec!();
+      for item in items.move_iter() {
+         if try!(Environment::test_predicate(env.clone(), stack, &args[0], item.clone())) {
+            result.push(item);
+         }
+      }
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   fn reduce(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("reduce");
+      if ops != 3 {
+         throw!(ArityError, "reduce only takes three values (function, initial value, array)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let items = try!(Environment::array_items("reduce", args.pop().unwrap()));
+      let mut acc = args.pop().unwrap();
+      for item in items.move_iter() {
+         acc = try!(Interpreter::call_value(env.clone(), stack, &args[0], vec!(acc, item)));
+      }
+      Ok(acc)
+   }
+
+   fn any(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("any?");
+      Ok(Boolean(BooleanAst::new(try!(Environment::find_match("any?", env, stack, ops, true)))))
+   }
+
+   fn all(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("all?");
+      Ok(Boolean(BooleanAst::new(!try!(Environment::find_match("all?", env, stack, ops, false)))))
+   }
+
+   // calls the predicate on each element until it returns target, short-circuiting
+   fn find_match(name: &str, env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint, target: bool) -> InterpResult<bool> {
+      if ops != 2 {
+         throw!(ArityError, "{} only takes two values (predicate and array)", name);
+      }
+      let args = Environment::take_args(stack, ops);
+      let items = try!(Environment::array_items(name, args[1].clone()));
+      for item in items.move_iter() {
+         if try!(Environment::test_predicate(env.clone(), stack, &args[0], item)) == target {
+            return Ok(true);
+         }
+      }
+      Ok(false)
+   }
+
+   fn reverse(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("reverse");
+      if ops != 1 {
+         throw!(ArityError, "reverse only takes one value (array/string)");
+      }
+      match Environment::take_args(stack, ops).pop().unwrap() {
+         String(ast) => Ok(String(StringAst::new(ast.string.as_slice().chars().rev().collect()))),
+         other => {
+            let mut items = try!(Environment::array_items("reverse", other));
+            items.reverse();
+            Ok(Array(ArrayAst::new(items)))
+         }
+      }
+   }
+
+   // (sort arr) orders numbers or strings the way < does; (sort arr less?) uses less? instead.
+   // Equal items keep their order either way
+   fn sort(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("sort");
+      if ops < 1 || ops > 2 {
+         throw!(ArityError, "sort takes an array and an optional comparison function");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let less = if ops == 2 { args.pop() } else { None };
+      let mut items = try!(Environment::array_items("sort", args.pop().unwrap()));
+      // sort_by cannot stop part way, so the first error is kept and the rest of the comparisons
+      // are skipped
+      let mut failure = None;
+      items.sort_by(|left, right| {
+         if failure.is_some() {
+            return Equal;
+         }
+         let result = match less {
+            Some(ref less) => Environment::call_less(env.clone(), stack, less, left, right),
+            None => Environment::compare_values("sort", left, right)
+         };
+         match result {
+            Ok(ord) => ord,
+            Err(f) => {
+               failure = Some(f);
+               Equal
+            }
+         }
+      });
+      match failure {
+         Some(f) => Err(f),
+         None => Ok(Array(ArrayAst::new(items)))
+      }
+   }
+
+   // both ways round, so that items less? considers equal are left where they are
+   fn call_less(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, less: &ExprAst, left: &ExprAst, right: &ExprAst) -> InterpResult<Ordering> {
+      if Environment::truthy(&try!(Interpreter::call_value(env.clone(), stack, less, vec!(left.clone(), right.clone())))) {
+         Ok(Less)
+      } else if Environment::truthy(&try!(Interpreter::call_value(env, stack, less, vec!(right.clone(), left.clone())))) {
+         Ok(Greater)
+      } else {
+         Ok(Equal)
+      }
+   }
+
+   fn first(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("first");
+      if ops != 1 {
+         throw!(ArityError, "first only takes one value (array)");
+      }
+      let mut items = try!(Environment::array_items("first", Environment::take_args(stack, ops).pop().unwrap()));
+      if items.len() == 0 {
+         Ok(Nil(NilAst::new()))
+      } else {
+         Ok(items.swap_remove(0).unwrap())
+      }
+   }
+
+   fn last(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("last");
+      if ops != 1 {
+         throw!(ArityError, "last only takes one value (array)");
+      }
+      let mut items = try!(Environment::array_items("last", Environment::take_args(stack, ops).pop().unwrap()));
+      Ok(match items.pop() {
+         Some(item) => item,
+         None => Nil(NilAst::new())
+      })
+   }
+
+   fn rest(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("rest");
+      if ops != 1 {
+         throw!(ArityError, "rest only takes one value (array)");
+      }
+      let items = try!(Environment::array_items("rest", Environment::take_args(stack, ops).pop().unwrap()));
+      Ok(Array(ArrayAst::new(items.move_iter().skip(1).collect())))
+   }
+
+   fn zip(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("zip");
+      if ops == 0 {
+         throw!(ArityError, "zip needs at least one array");
+      }
+      let mut arrs = vec!();
+      for arg in Environment::take_args(stack, ops).move_iter() {
+         arrs.push(try!(Environment::array_items("zip", arg)));
+      }
+      let len = arrs.iter().map(|items| items.len()).min().unwrap();
+      let result = range(0, len).map(|i| Array(ArrayAst::new(arrs.iter().map(|items| items[i].clone()).collect()))).collect();
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   // a negative or nil depth flattens all the way down
+   fn flatten(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("flatten");
+      if ops < 1 || ops > 2 {
+         throw!(ArityError, "flatten takes an array and an optional depth");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let depth =
+         if ops == 2 {
+            match args.pop().unwrap() {
+               Integer(ast) => if ast.value < 0 { None } else { Some(ast.value as uint) },
+               Nil(_) => None,
+               other => throw!(TypeError, "flatten expected an integer or nil depth but found {}", Environment::type_name(&other))
+            }
+         } else {
+            Some(1)
+         };
+      let mut result = vec!();
+      Environment::flatten_into(try!(Environment::array_items("flatten", args.pop().unwrap())), depth, &mut result);
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   fn flatten_into(items: Vec<ExprAst>, depth: Option<uint>, result: &mut Vec<ExprAst>) {
+      for item in items.move_iter() {
+         match item {
+            Array(ast) => {
+               if depth == Some(0) {
+                  result.push(Array(ast));
+               } else {
+                  Environment::flatten_into(ast.items.borrow().clone(), depth.map(|depth| depth - 1), result);
+               }
+            }
+            other => result.push(other)
+         }
+      }
+   }
+
+   fn take(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("take");
+      let (count, items) = try!(Environment::count_and_items("take", stack, ops));
+      Ok(Array(ArrayAst::new(items.move_iter().take(count).collect())))
+   }
+
+   fn drop(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("drop");
+      let (count, items) = try!(Environment::count_and_items("drop", stack, ops));
+      Ok(Array(ArrayAst::new(items.move_iter().skip(count).collect())))
+   }
+
+   fn count_and_items(name: &str, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<(uint, Vec<ExprAst>)> {
+      if ops != 2 {
+         throw!(ArityError, "{} only takes two values (count and array)", name);
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let items = try!(Environment::array_items(name, args.pop().unwrap()));
+      let count = try!(Environment::expect_integer(name, &args[0]));
+      if count < 0 {
+         throw!(ValueError, "{} count must not be negative", name);
+      }
+      Ok((count as uint, items))
+   }
+
+   // returns [matching non-matching]
+   fn partition(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("partition");
+      if ops != 2 {
+         throw!(ArityError, "partition only takes two values (predicate and array)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let items = try!(Environment::array_items("partition", args.pop().unwrap()));
+      let mut matching = vec!();
+      let mut rest = vec!();
+      for item in items.move_iter() {
+         if try!(Environment::test_predicate(env.clone(), stack, &args[0], item.clone())) {
+            matching.push(item);
+         } else {
+            rest.push(item);
+         }
+      }
+      Ok(Array(ArrayAst::new(vec!(Array(ArrayAst::new(matching)), Array(ArrayAst::new(rest))))))
+   }
+
+   // keeps the first occurrence of each value
+   fn unique(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("unique");
+      if ops != 1 {
+         throw!(ArityError, "unique only takes one value (array)");
+      }
+      let items = try!(Environment::array_items("unique", Environment::take_args(stack, ops).pop().unwrap()));
+      let mut result: Vec<ExprAst> = vec!();
+      for item in items.move_iter() {
+         if !result.iter().any(|seen| Environment::values_equal(seen, &item)) {
+            result.push(item);
+         }
+      }
+      Ok(Array(ArrayAst::new(result)))
+   }
+
+   // either (count pred arr) or (count arr value)
+   fn count(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("count");
+      if ops != 2 {
+         throw!(ArityError, "count takes a predicate and an array, or an array and a value");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let second = args.pop().unwrap();
+      let first = args.pop().unwrap();
+      let count = match first {
+         Array(ast) => ast.items.borrow().iter().filter(|item| Environment::values_equal(*item, &second)).count(),
+         pred => {
+            let mut count = 0u;
+            for item in try!(Environment::array_items("count", second)).move_iter() {
+               if try!(Environment::test_predicate(env.clone(), stack, &pred, item)) {
+                  count += 1;
+               }
+            }
+            count
+         }
+      };
+      Ok(Integer(IntegerAst::new(count as i64)))
+   }
+
+   fn sum(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("sum");
+      if ops != 1 {
+         throw!(ArityError, "sum only takes one value (array)");
+      }
+      let items = try!(Environment::array_items("sum", Environment::take_args(stack, ops).pop().unwrap()));
+      Environment::accumulate("sum", items.as_slice(), 0, |acc, val| acc + val, |acc, val| acc + val)
+   }
+
+   fn product(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("product");
+      if ops != 1 {
+         throw!(ArityError, "product only takes one value (array)");
+      }
+      let items = try!(Environment::array_items("product", Environment::take_args(stack, ops).pop().unwrap()));
+      Environment::accumulate("product", items.as_slice(), 1, |acc, val| acc * val, |acc, val| acc * val)
+   }
+
+   // the average is always a float, and averaging nothing is an error
+   fn avg(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("avg");
+      if ops != 1 {
+         throw!(ArityError, "avg only takes one value (array)");
+      }
+      let items = try!(Environment::array_items("avg", Environment::take_args(stack, ops).pop().unwrap()));
+      if items.len() == 0 {
+         throw!(ValueError, "avg of an empty array is undefined");
+      }
+      let total = match try!(Environment::accumulate("avg", items.as_slice(), 0, |acc, val| acc + val, |acc, val| acc + val)) {
+         Integer(ast) => ast.value as f64,
+         Float(ast) => ast.value,
+         _ => unreachable!()
+      };
+      Ok(Float(FloatAst::new(total / items.len() as f64)))
+   }
+
+   // functions are called with each index to produce the elements, anything else is copied
+   fn make_array(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("make-array");
+      if ops < 1 || ops > 2 {
+         throw!(ArityError, "make-array takes a length and an optional fill value or function");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let fill = if ops == 2 { args.pop().unwrap() } else { Nil(NilAst::new()) };
+      let len = try!(Environment::expect_integer("make-array", &args[0]));
+      if len < 0 {
+         throw!(ValueError, "make-array length must not be negative");
+      }
+      let items = match fill {
+         super::ast::Code(_) | Builtin(_) | Host(_) => {
+            let mut items = Vec::with_capacity(len as uint);
+            for idx in range(0, len) {
+               items.push(try!(Interpreter::call_value(env.clone(), stack, &fill, vec!(Integer(IntegerAst::new(idx))))));
+            }
+            items
+         }
+         _ => Vec::from_fn(len as uint, |_| Environment::deep_copy(&fill))
+      };
+      Ok(Array(ArrayAst::new(items)))
+   }
+
+   fn copy(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("copy");
+      if ops != 1 {
+         throw!(ArityError, "copy only takes one value");
+      }
+      Ok(Environment::deep_copy(&Environment::take_args(stack, ops)[0]))
+   }
+
+   // arrays are shared between bindings, so this is the only way to get an independent one
+   pub fn deep_copy(value: &ExprAst) -> ExprAst {
+      Environment::copy_nested(value, &mut collections::HashMap::new(), &mut collections::HashMap::new())
+   }
+
+   // the copies made so far, by the address of what they copy, so that an array or dict reached
+   // twice is copied once and one that contains itself ends up containing its copy
+   fn copy_nested(value: &ExprAst, arrays: &mut collections::HashMap<uint, ArrayAst>, dicts: &mut collections::HashMap<uint, DictAst>) -> ExprAst {
+      match *value {
+         Array(ref ast) => {
+            let key = &*ast.items as *const RefCell<Vec<ExprAst>> as uint;
+            match arrays.find(&key) {
+               Some(copy) => return Array(copy.clone()),
+               None => {}
+            }
+            let copy = ArrayAst::new(vec!());
+            arrays.insert(key, copy.clone());
+            let items: Vec<ExprAst> = ast.items.borrow().iter().map(|item| Environment::copy_nested(item, arrays, dicts)).collect();
+            *copy.items.borrow_mut() = items;
+            Array(copy)
+         }
+         Dict(ref ast) => {
+            let key = &*ast.entries as *const RefCell<collections::HashMap<String, ExprAst>> as uint;
+            match dicts.find(&key) {
+               Some(copy) => return Dict(copy.clone()),
+               None => {}
+            }
+            let copy = DictAst::new(collections::HashMap::new());
+            dicts.insert(key, copy.clone());
+            for (name, value) in ast.entries.borrow().iter() {
+               let value = Environment::copy_nested(value, arrays, dicts);
+               copy.entries.borrow_mut().insert(name.clone(), value);
+            }
+            Dict(copy)
+         }
+         Struct(ref ast) => Struct(StructAst::new(ast.name.clone(), ast.fields.clone(),
+                                                   ast.values.iter().map(|value| Environment::copy_nested(value, arrays, dicts)).collect())),
+         ref other => other.clone()
+      }
+   }
+
+   fn test_predicate(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, pred: &ExprAst, item: ExprAst) -> InterpResult<bool> {
+      Ok(Environment::truthy(&try!(Interpreter::call_value(env, stack, pred, vec!(item)))))
+   }
+
+   // what = means: numbers by value whether integer or float, strings, symbols and booleans by
+   // content, nil only to nil, arrays, lists, dicts and structs item by item, and functions only
+   // when they are the same one
+   pub fn values_equal(left: &ExprAst, right: &ExprAst) -> bool {
+      Environment::equal_nested(left, right, &mut vec!())
+   }
+
+   // open holds the pairs of arrays and dicts being compared further out; meeting one of them
+   // again means the two contain themselves in the same way, so that part of them is equal
+   fn equal_nested(left: &ExprAst, right: &ExprAst, open: &mut Vec<(uint, uint)>) -> bool {
+      match (left, right) {
+         (&Integer(ref left), &Integer(ref right)) => left.value == right.value,
+         (&Integer(_), &Float(_)) | (&Float(_), &Integer(_)) | (&Float(_), &Float(_)) => left.as_f64() == right.as_f64(),
+         (&String(ref left), &String(ref right)) => left.string == right.string,
+         (&Symbol(ref left), &Symbol(ref right)) => left.value == right.value,
+         (&Boolean(ref left), &Boolean(ref right)) => left.value == right.value,
+         (&Nil(_), &Nil(_)) => true,
+         (&Array(ref left), &Array(ref right)) => {
+            let left_id = &*left.items as *const RefCell<Vec<ExprAst>> as uint;
+            let right_id = &*right.items as *const RefCell<Vec<ExprAst>> as uint;
+            if left_id == right_id || open.contains(&(left_id, right_id)) {
+               return true;
+            }
+            let left = left.items.borrow();
+            let right = right.items.borrow();
+            open.push((left_id, right_id));
+            let equal = left.len() == right.len() && left.iter().zip(right.iter()).all(|(left, right)| Environment::equal_nested(left, right, open));
+            open.pop();
+            equal
+         }
+         (&List(ref left), &List(ref right)) => {
+            let mut left = left.iter();
+            let mut right = right.iter();
+            loop {
+               match (left.next(), right.next()) {
+                  (Some(left), Some(right)) => if !Environment::equal_nested(left, right, open) {
+                     return false;
+                  },
+                  (None, None) => return true,
+                  _ => return false
+               }
+            }
+         }
+         (&Dict(ref left), &Dict(ref right)) => {
+            let left_id = &*left.entries as *const RefCell<collections::HashMap<String, ExprAst>> as uint;
+            let right_id = &*right.entries as *const RefCell<collections::HashMap<String, ExprAst>> as uint;
+            if left_id == right_id || open.contains(&(left_id, right_id)) {
+               return true;
+            }
+            let left = left.entries.borrow();
+            let right = right.entries.borrow();
+            open.push((left_id, right_id));
+            let equal = left.len() == right.len() && left.iter().all(|(key, value)| match right.find(key) {
+               Some(other) => Environment::equal_nested(value, other, open),
+               None => false
+            });
+            open.pop();
+            equal
+         }
+         (&Struct(ref left), &Struct(ref right)) => {
+            left.name == right.name && left.values.iter().zip(right.values.iter()).all(|(left, right)| Environment::equal_nested(left, right, open))
+         }
+         (&super::ast::Code(ref left), &super::ast::Code(ref right)) => {
+            &*left.code as *const Vec<ExprAst> == &*right.code as *const Vec<ExprAst> &&
+               &*left.env as *const RefCell<Environment> == &*right.env as *const RefCell<Environment>
+         }
+         (&Builtin(ref left), &Builtin(ref right)) => left.func == right.func,
+         // quoted code and anything else is compared as written
+         _ => left == right
+      }
+   }
+
+   // the one place that decides what counts as true: everything except nil and false
+   pub fn truthy(value: &ExprAst) -> bool {
+      match *value {
+         Nil(_) => false,
+         Boolean(ref ast) => ast.value,
+         _ => true
+      }
+   }
+
+   // evaluates an operand that a special form received unevaluated
+   fn eval_operand(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, node: &ExprAst) -> InterpResult<ExprAst> {
+      try!(Interpreter::execute_node(env, stack, node));
+      Ok(stack.pop().unwrap())
+   }
+
+   // nil is accepted as the empty list so that lists can be built up from nothing
+   fn cons(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("cons");
+      if ops != 2 {
+         throw!(ArityError, "cons only takes two values (value and list)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let tail = match args.pop().unwrap() {
+         List(ast) => ast,
+         Nil(_) => ListAst::empty(),
+         other => throw!(TypeError, "cons expected a list but found {}", Environment::type_name(&other))
+      };
+      Ok(List(ListAst::cons(args.pop().unwrap(), tail)))
+   }
+
+   fn head(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("head");
+      if ops != 1 {
+         throw!(ArityError, "head only takes one value (list)");
+      }
+      match try!(Environment::list_cell("head", Environment::take_args(stack, ops).pop().unwrap())) {
+         Some(cell) => Ok(cell.value.clone()),
+         None => throw!(ValueError, "head of an empty list")
+      }
+   }
+
+   fn tail(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("tail");
+      if ops != 1 {
+         throw!(ArityError, "tail only takes one value (list)");
+      }
+      match try!(Environment::list_cell("tail", Environment::take_args(stack, ops).pop().unwrap())) {
+         Some(cell) => Ok(List(cell.next.clone())),
+         None => throw!(ValueError, "tail of an empty list")
+      }
+   }
+
+   fn list(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("list");
+      Ok(List(ListAst::new(Environment::take_args(stack, ops))))
+   }
+
+   fn list_cell(name: &str, value: ExprAst) -> InterpResult<Option<Rc<ConsCell>>> {
+      match value {
+         List(ast) => Ok(ast.head),
+         other => throw!(TypeError, "{} expected a list but found {}", name, Environment::type_name(&other))
+      }
+   }
+
+   // (dict key value key value ...)
+   fn dict(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("dict");
+      if ops % 2 != 0 {
+         throw!(ArityError, "dict takes alternating keys and values");
+      }
+      let mut entries = collections::HashMap::new();
+      let mut args = Environment::take_args(stack, ops).move_iter();
+      loop {
+         let key = match args.next() {
+            Some(key) => try!(Environment::dict_key("dict", &key)),
+            None => break
+         };
+         entries.insert(key, args.next().unwrap());
+      }
+      Ok(Dict(DictAst::new(entries)))
+   }
+
+   // a missing key gives the default if there is one and nil otherwise
+   fn dget(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("dget");
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "dget takes a dict, a key, and an optional default");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let default = if ops == 3 { args.pop().unwrap() } else { Nil(NilAst::new()) };
+      let key = try!(Environment::dict_key("dget", &args.pop().unwrap()));
+      let dict = try!(Environment::expect_dict("dget", args.pop().unwrap()));
+      let entries = dict.entries.borrow();
+      Ok(match entries.find(&key) {
+         Some(value) => value.clone(),
+         None => default
+      })
+   }
+
+   fn dset(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("dset");
+      if ops != 3 {
+         throw!(ArityError, "dset only takes three values (dict, key, value)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let value = args.pop().unwrap();
+      let key = try!(Environment::dict_key("dset", &args.pop().unwrap()));
+      let dict = try!(Environment::expect_dict("dset", args.pop().unwrap()));
+      dict.entries.borrow_mut().insert(key, value);
+      Ok(Nil(NilAst::new()))
+   }
+
+   // returns the removed value, or nil if the key was not there
+   fn dremove(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("dremove");
+      if ops != 2 {
+         throw!(ArityError, "dremove only takes two values (dict and key)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let key = try!(Environment::dict_key("dremove", &args.pop().unwrap()));
+      let dict = try!(Environment::expect_dict("dremove", args.pop().unwrap()));
+      let value = dict.entries.borrow_mut().pop(&key);
+      Ok(match value {
+         Some(value) => value,
+         None => Nil(NilAst::new())
+      })
+   }
+
+   fn keys(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("keys");
+      if ops != 1 {
+         throw!(ArityError, "keys only takes one value (dict)");
+      }
+      let dict = try!(Environment::expect_dict("keys", Environment::take_args(stack, ops).pop().unwrap()));
+      Ok(Array(ArrayAst::new(dict.sorted_keys().move_iter().map(|key| String(StringAst::new(key))).collect())))
+   }
+
+   // in the same order as keys
+   fn values(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("values");
+      if ops != 1 {
+         throw!(ArityError, "values only takes one value (dict)");
+      }
+      let dict = try!(Environment::expect_dict("values", Environment::take_args(stack, ops).pop().unwrap()));
+      let entries = dict.entries.borrow();
+      let values = dict.sorted_keys().iter().map(|key| entries.find(key).unwrap().clone()).collect();
+      Ok(Array(ArrayAst::new(values)))
+   }
+
+   fn has_key(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("has-key?");
+      if ops != 2 {
+         throw!(ArityError, "has-key? only takes two values (dict and key)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let key = try!(Environment::dict_key("has-key?", &args.pop().unwrap()));
+      let dict = try!(Environment::expect_dict("has-key?", args.pop().unwrap()));
+      let found = dict.entries.borrow().contains_key(&key);
+      Ok(Boolean(BooleanAst::new(found)))
+   }
+
+   // later dicts take precedence over earlier ones
+   fn merge(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("merge");
+      let mut entries = collections::HashMap::new();
+      for arg in Environment::take_args(stack, ops).move_iter() {
+         let dict = try!(Environment::expect_dict("merge", arg));
+         for (key, value) in dict.entries.borrow().iter() {
+            entries.insert(key.clone(), value.clone());
+         }
+      }
+      Ok(Dict(DictAst::new(entries)))
+   }
+
+   // f is called with each key and value in sorted key order
+   fn each_pair(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("each-pair");
+      if ops != 2 {
+         throw!(ArityError, "each-pair only takes two values (dict and function)");
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let func = args.pop().unwrap();
+      for pair in Environment::dict_pairs(try!(Environment::expect_dict("each-pair", args.pop().unwrap()))).move_iter() {
+         let (key, value) = pair;
+         try!(Interpreter::call_value(env.clone(), stack, &func, vec!(key, value)));
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   fn pairs(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("pairs");
+      if ops != 1 {
+         throw!(ArityError, "pairs only takes one value (dict)");
+      }
+      let dict = try!(Environment::expect_dict("pairs", Environment::take_args(stack, ops).pop().unwrap()));
+      let pairs = Environment::dict_pairs(dict).move_iter().map(|(key, value)| Array(ArrayAst::new(vec!(key, value)))).collect();
+      Ok(Array(ArrayAst::new(pairs)))
+   }
+
+   // a snapshot of the entries, so that f can modify the dict while each-pair is running
+   fn dict_pairs(dict: DictAst) -> Vec<(ExprAst, ExprAst)> {
+      let entries = dict.entries.borrow();
+      let pairs = dict.sorted_keys().move_iter().map(|key| {
+         let value = entries.find(&key).unwrap().clone();
+         (String(StringAst::new(key)), value)
+      }).collect();
+      pairs
+   }
+
+   fn dict_key(name: &str, value: &ExprAst) -> InterpResult<String> {
+      match *value {
+         String(ref ast) => Ok(ast.string.clone()),
+         Symbol(ref ast) => Ok(ast.value.to_string()),
+         ref other => throw!(TypeError, "{} expected a string or symbol key but found {}", name, Environment::type_name(other))
+      }
+   }
+
+   fn expect_dict(name: &str, value: ExprAst) -> InterpResult<DictAst> {
+      match value {
+         Dict(ast) => Ok(ast),
+         other => throw!(TypeError, "{} expected a dict but found {}", name, Environment::type_name(&other))
+      }
+   }
+
+   fn range(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("range");
+      if ops < 1 || ops > 3 {
+         throw!(ArityError, "range takes an end, a start and an end, or a start, an end, and a step");
+      }
+      let args = Environment::take_args(stack, ops);
+      let (start, end) =
+         if ops == 1 {
+            (0, try!(Environment::expect_integer("range", &args[0])))
+         } else {
+            (try!(Environment::expect_integer("range", &args[0])), try!(Environment::expect_integer("range", &args[1])))
+         };
+      let step = if ops == 3 { try!(Environment::expect_integer("range", &args[2])) } else { 1 };
+      if step == 0 {
+         throw!(ValueError, "range step cannot be zero");
+      }
+      let count =
+         if (step > 0 && start < end) || (step < 0 && start > end) {
+            ((end - start).abs() + step.abs() - 1) / step.abs()
+         } else {
+            0
+         };
+      let mut items = Vec::with_capacity(count as uint);
+      let mut current = start;
+      for _ in range(0, count) {
+         items.push(Integer(IntegerAst::new(current)));
+         current += step;
+      }
+      Ok(Array(ArrayAst::new(items)))
+   }
+
+   // hands back the shared array itself, so that mutating it is visible through every binding
+   fn expect_array(name: &str, value: ExprAst) -> InterpResult<ArrayAst> {
+      match value {
+         Array(ast) => Ok(ast),
+         other => throw!(TypeError, "{} expected an array but found {}", name, Environment::type_name(&other))
+      }
+   }
+
+   fn len(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("len");
+      if ops != 1 {
+         throw!(ArityError, "len only takes one value (array/list/dict/string)");
+      }
+      let len = match stack.pop().unwrap() {
+         Array(ast) => ast.items.borrow().len(),
+         List(ast) => ast.iter().count(),
+         Dict(ast) => ast.entries.borrow().len(),
+         String(ast) => ast.string.as_slice().char_len(),
+         other => throw!(TypeError, "len cannot take the length of a value of type {}", Environment::type_name(&other))
+      };
+      Ok(Integer(IntegerAst::new(len as i64)))
+   }
+
+   fn equal(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("equal");
+      let mut ops = ops;
+      if ops < 2 {
+         throw!(ArityError, "= needs at least two operands");
+      }
+      let cmpast = stack.pop().unwrap();
+      ops -= 1;
+      while ops > 0 {
+         if !Environment::values_equal(&stack.pop().unwrap(), &cmpast) {
+            return Ok(Boolean(BooleanAst::new(false)));
+         }
+         ops -= 1;
+      }
+      Ok(Boolean(BooleanAst::new(true)))
+   }
+
+   fn less(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("less");
+      Environment::compare("<", stack, ops, |ord| ord == Less)
+   }
+
+   fn greater(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("greater");
+      Environment::compare(">", stack, ops, |ord| ord == Greater)
+   }
+
+   fn less_equal(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("less_equal");
+      Environment::compare("<=", stack, ops, |ord| ord != Greater)
+   }
+
+   fn greater_equal(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("greater_equal");
+      Environment::compare(">=", stack, ops, |ord| ord != Less)
+   }
+
+   // chained like =, so (< 1 2 3) checks every adjacent pair
+   fn compare(name: &str, stack: &mut Vec<ExprAst>, ops: uint, test: |Ordering| -> bool) -> InterpResult<ExprAst> {
+      if ops < 2 {
+         throw!(ArityError, "{} needs at least two operands", name);
+      }
+      let args = Environment::take_args(stack, ops);
+      for pair in args.as_slice().windows(2) {
+         if !test(try!(Environment::compare_values(name, &pair[0], &pair[1]))) {
+            return Ok(Boolean(BooleanAst::new(false)));
+         }
+      }
+      Ok(Boolean(BooleanAst::new(true)))
+   }
+
+   fn compare_values(name: &str, left: &ExprAst, right: &ExprAst) -> InterpResult<Ordering> {
+      match (left, right) {
+         (&String(ref left), &String(ref right)) => Ok(left.string.cmp(&right.string)),
+         (&Integer(ref left), &Integer(ref right)) => Ok(left.value.cmp(&right.value)),
+         _ => match (Environment::number_value(left), Environment::number_value(right)) {
+            (Some(left), Some(right)) => match left.partial_cmp(&right) {
+               Some(ord) => Ok(ord),
+               None => throw!(ValueError, "{} cannot compare NaN", name)
+            },
+            _ => throw!(TypeError, "{} cannot compare {} with {}", name, Environment::type_name(left), Environment::type_name(right))
+         }
+      }
+   }
+
+   fn number_value(value: &ExprAst) -> Option<f64> {
+      match *value {
+         Integer(ref ast) => Some(ast.value as f64),
+         Float(ref ast) => Some(ast.value),
+         _ => None
+      }
+   }
+
+   // only the condition has been evaluated by execute_node, the branches arrive unevaluated
+   pub fn ifexpr(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("if");
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "if takes a condition, a branch, and an optional else branch but was given {} operands", ops);
+      }
+      let mut args = Environment::take_args(stack, ops);
+      let onfalse = if ops == 3 { args.pop() } else { None };
+      let ontrue = args.pop().unwrap();
+      let cond = Environment::truthy(&args.pop().unwrap());
+      let branch = if cond { Some(ontrue) } else { onfalse };
+      match branch {
+         Some(branch) => {
+            try!(Interpreter::execute_node(env, stack, &branch));
+            Ok(stack.pop().unwrap())
+         }
+         // there is no else branch to give a value
+         None => Ok(Nil(NilAst::new()))
+      }
+   }
+
+   // (while cond body...) gives the value of the last body evaluated, or nil if there was none
+   pub fn whileexpr(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("while");
+      if ops == 0 {
+         throw!(ArityError, "while needs a condition");
+      }
+      let args = Environment::take_args(stack, ops);
+      let mut result = Nil(NilAst::new());
+      while Environment::truthy(&try!(Environment::eval_operand(env.clone(), stack, &args[0]))) {
+         for node in args.slice_from(1).iter() {
+            result = try!(Environment::eval_operand(env.clone(), stack, node));
+         }
+      }
+      Ok(result)
+   }
+
+   // (case value key result ... default): keys are evaluated in turn until one equals value, and
+   // then only its result is; without a match it is the default if there is one, or nil
+   fn case(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("case");
+      if ops == 0 {
+         throw!(ArityError, "case needs a value to match");
+      }
+      let args = Environment::take_args(stack, ops);
+      let value = try!(Environment::eval_operand(env.clone(), stack, &args[0]));
+      for clause in args.slice_from(1).chunks(2) {
+         if clause.len() == 1 {
+            return Environment::eval_operand(env, stack, &clause[0]);
+         }
+         let key = try!(Environment::eval_operand(env.clone(), stack, &clause[0]));
+         if Environment::values_equal(&key, &value) {
+            return Environment::eval_operand(env, stack, &clause[1]);
+         }
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   // (quote x) gives x as data instead of evaluating it: names become symbols, and calls become
+   // lists of their operator and operands
+   fn quote(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("quote");
+      if ops != 1 {
+         throw!(ArityError, "quote only takes one value");
+      }
+      Ok(Environment::quoted(Environment::take_args(stack, ops).pop().unwrap(), false))
+   }
+
+   // a symbol literal becomes (quote name), so that unquoted gives back code that makes the
+   // symbol again, and a quoted list is left to become data when it is evaluated. What read
+   // gives back and the items of a quoted list are data already, so there a symbol stays as it
+   // is and a list is turned into data too
+   fn quoted(node: ExprAst, data: bool) -> ExprAst {
+      match node {
+         Ident(ast) => Symbol(SymbolAst::new(ast.value)),
+         Symbol(ast) if !data => List(ListAst::new(vec!(Symbol(SymbolAst::new(Name::new("quote"))), Symbol(ast)))),
+         List(ast) if data => List(ListAst::new(ast.iter().map(|item| Environment::quoted(item.clone(), true)).collect())),
+         Sexpr(ast) => {
+            let mut items = vec!(Symbol(SymbolAst::new(ast.op.value)));
+            items.extend(ast.operands.move_iter().map(|operand| Environment::quoted(operand, data)));
+            List(ListAst::new(items))
+         }
+         // a new array, since the one in the code is shared by every evaluation
+         Array(ast) => Array(ArrayAst::new(ast.items.borrow().iter().map(|item| Environment::quoted(item.clone(), data)).collect())),
+         other => other
+      }
+   }
+
+   // what a quoted list literal evaluates to: the same items, with names as symbols and calls as
+   // lists, the way quote gives them
+   pub fn list_value(ast: &ListAst) -> ExprAst {
+      Environment::quoted(List(ast.clone()), true).with_span(ast.span.clone())
+   }
+
+   // the code a value stands for, undoing quoted: a list that starts with a symbol is a form
+   // and a symbol is a name, while any other list is left as a quoted list
+   fn unquoted(value: ExprAst) -> InterpResult<ExprAst> {
+      Ok(match value {
+         Symbol(ast) => Ident(IdentAst::new(ast.value)),
+         List(ast) => {
+            let items: Vec<ExprAst> = ast.iter().map(|item| item.clone()).collect();
+            let op = match items.as_slice().head() {
+               Some(&Symbol(ref sym)) => IdentAst::new(sym.value.clone()),
+               _ => return Ok(List(ast))
+            };
+            let mut operands = vec!();
+            for item in items.move_iter().skip(1) {
+               operands.push(try!(Environment::unquoted(item)));
+            }
+            Sexpr(SexprAst::new(op, operands))
+         }
+         Array(ast) => {
+            let mut items = vec!();
+            for item in ast.items.borrow().iter() {
+               items.push(try!(Environment::unquoted(item.clone())));
+            }
+            Array(ArrayAst::new(items))
+         }
+         other => other
+      })
+   }
+
+   fn when(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("when");
+      if ops == 0 {
+         throw!(ArityError, "when needs a condition");
+      }
+      let args = Environment::take_args(stack, ops);
+      let mut result = Nil(NilAst::new());
+      if Environment::truthy(&try!(Environment::eval_operand(env.clone(), stack, &args[0]))) {
+         for node in args.slice_from(1).iter() {
+            result = try!(Environment::eval_operand(env.clone(), stack, node));
+         }
+      }
+      Ok(result)
+   }
+
+   // and/or stop at the first operand that decides the result and give back that operand's value
+   fn and(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("and");
+      Environment::short_circuit(env, stack, ops, false, Boolean(BooleanAst::new(true)))
+   }
+
+   fn or(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("or");
+      Environment::short_circuit(env, stack, ops, true, Boolean(BooleanAst::new(false)))
+   }
+
+   fn short_circuit(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint, stop: bool, empty: ExprAst) -> InterpResult<ExprAst> {
+      let mut result = empty;
+      for node in Environment::take_args(stack, ops).iter() {
+         result = try!(Environment::eval_operand(env.clone(), stack, node));
+         if Environment::truthy(&result) == stop {
+            break;
+         }
+      }
+      Ok(result)
+   }
+
+   // (import "a" "b") binds everything a module defines, (import "a" as m) binds it as m/name
+   // and (import "a" [x y]) binds only the names listed
+   fn importexpr(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      if ops == 0 {
+         throw!(ArityError, "import requires at least one operand");
+      }
+      let args = Environment::take_args(stack, ops);
+      let mut idx = 0;
+      while idx < args.len() {
+         let name = match args[idx] {
+            String(ref ast) => ast.string.clone(),
+            ref other => throw!(TypeError, "import expected a string path but found {}", Environment::type_name(other))
+         };
+         idx += 1;
+         let values = try!(Environment::import_module(env.clone(), name.as_slice()));
+         match args.as_slice().get(idx) {
+            Some(&Ident(ref ast)) if ast.value.as_slice() == "as" => {
+               let prefix = match args.as_slice().get(idx + 1) {
+                  Some(&Ident(ref ast)) => ast.value.clone(),
+                  Some(other) => throw!(TypeError, "import expected a name after as but found {}", Environment::type_name(other)),
+                  None => throw!(ArityError, "import expected a name after as")
+               };
+               idx += 2;
+               for (key, slot) in values.move_iter() {
+                  try!(Environment::bind_import(env.clone(), name.as_slice(), Name::new(format!("{}/{}", prefix, key).as_slice()), slot));
+               }
+            }
+            Some(&Array(ref ast)) => {
+               idx += 1;
+               for item in ast.items.borrow().iter() {
+                  let key = match *item {
+                     Ident(ref ast) => ast.value.clone(),
+                     ref other => throw!(TypeError, "import expected names to select but found {}", Environment::type_name(other))
+                  };
+                  let slot = match values.find(&key) {
+                     Some(slot) => slot.clone(),
+                     None => throw!(ImportError, "module {} does not define {}", name, key)
+                  };
+                  try!(Environment::bind_import(env.clone(), name.as_slice(), key, slot));
+               }
+            }
+            _ => {
+               for (key, slot) in values.move_iter() {
+                  try!(Environment::bind_import(env.clone(), name.as_slice(), key, slot));
+               }
+            }
+         }
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   // the top-level bindings of a module, running it first if nothing has imported it yet
+   fn import_module(env: Rc<RefCell<Environment>>, name: &str) -> InterpResult<collections::HashMap<Name, Rc<RefCell<EnvValue>>>> {
+      let bare = if name.starts_with("./") || name.starts_with("../") {
+         Path::new(match env.clone().borrow().find(&Name::new("FILE")).unwrap() {
+            Value(String(ast)) => ast.string,
+            _ => throw!(ImportError, "cannot import {} relative to FILE because FILE is not a string", name)
+         }).dir_path().join(Path::new(name))
+      } else {
+         try!(Environment::find_module(env.clone(), name))
+      };
+      let path = Environment::module_file(&bare, name);
+      if path.is_dir() || (!path.exists() && bare.is_dir()) {
+         throw!(ImportError, "cannot import {}: it is a directory", bare.display());
+      }
+      let key = os::make_absolute(&path).display().to_string();
+      let cached = env.borrow().context.borrow().modules.find(&key).map(|values| values.clone());
+      match cached {
+         Some(values) => Ok(values),
+         None => {
+            try!(Environment::check_import_cycle(env.clone(), &key));
+            env.borrow().context.borrow_mut().loading.push(key.clone());
+            let result = Environment::load_module(env.clone(), &path, &key);
+            env.borrow().context.borrow_mut().loading.pop();
+            let values = try!(result);
+            env.borrow().context.borrow_mut().modules.insert(key, values.clone());
+            Ok(values)
+         }
+      }
+   }
+
+   // the slot is shared, so every importer sees assignments to module bindings; importing the
+   // same binding twice is harmless, but replacing a different one with the same name is not
+   fn bind_import(env: Rc<RefCell<Environment>>, module: &str, key: Name, slot: Rc<RefCell<EnvValue>>) -> InterpResult<()> {
+      let collides = match env.borrow().values.find(&key) {
+         Some(existing) => (&**existing as *const RefCell<EnvValue>) != (&*slot as *const RefCell<EnvValue>),
+         None => false
+      };
+      if collides {
+         throw!(ImportError, "importing {} would replace the existing binding {}", module, key);
+      }
+      env.borrow_mut().insert_slot(key, slot);
+      Ok(())
+   }
+
+   fn exit(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("exit");
+      let status = match ops {
+         0 => 0,
+         1 => try!(Environment::expect_integer("exit", &Environment::take_args(stack, ops)[0])),
+         _ => throw!(ArityError, "exit takes an optional status")
+      };
+      throw!(Exit(status as int), "exit with status {}", status)
+   }
+
+   // runs a program directly rather than through a shell and returns a dict with its status,
+   // stdout and stderr; status is nil and signal is set if the program was killed
+   fn exec(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("exec");
+      let output = try!(Environment::run_process("exec", env, stack, ops));
+      let mut entries = collections::HashMap::new();
+      let (status, signal) = match output.status {
+         process::ExitStatus(code) => (Integer(IntegerAst::new(code as i64)), Nil(NilAst::new())),
+         process::ExitSignal(code) => (Nil(NilAst::new()), Integer(IntegerAst::new(code as i64)))
+      };
+      entries.insert("status".to_string(), status);
+      entries.insert("signal".to_string(), signal);
+      entries.insert("stdout".to_string(), String(StringAst::new(String::from_utf8_lossy(output.output.as_slice()).into_string())));
+      entries.insert("stderr".to_string(), String(StringAst::new(String::from_utf8_lossy(output.error.as_slice()).into_string())));
+      Ok(Dict(DictAst::new(entries)))
+   }
+
+   // like exec, but the program's output goes to the script's own output and only the exit
+   // status comes back (-1 if the program was killed by a signal)
+   fn system(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("system");
+      let output = try!(Environment::run_process("system", env.clone(), stack, ops));
+      let (destination, error_destination) = {
+         let context = env.borrow().context.clone();
+         let context = context.borrow();
+         (context.output.clone(), context.error_output.clone())
+      };
+      try!(Environment::write_output(destination, String::from_utf8_lossy(output.output.as_slice()).as_slice()));
+      try!(Environment::write_output(error_destination, String::from_utf8_lossy(output.error.as_slice()).as_slice()));
+      Ok(Integer(IntegerAst::new(match output.status {
+         process::ExitStatus(code) => code as i64,
+         process::ExitSignal(_) => -1
+      })))
+   }
+
+   // (name "program" [args...]), waiting for the program to finish
+   fn run_process(name: &str, env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<process::ProcessOutput> {
+      if !env.borrow().context.borrow().exec_allowed {
+         throw!(IoError, "{} cannot be used because process execution is disabled", name);
+      }
+      if ops < 1 || ops > 2 {
+         throw!(ArityError, "{} takes a program and an optional array of arguments", name);
+      }
+      let args = Environment::take_args(stack, ops);
+      let program = try!(Environment::expect_string(name, &args[0]));
+      let mut argv = vec!();
+      if ops == 2 {
+         for arg in try!(Environment::array_items(name, args[1].clone())).iter() {
+            argv.push(try!(Environment::expect_string(name, arg)));
+         }
+      }
+      match process::Command::new(program.as_slice()).args(argv.as_slice()).output() {
+         Ok(output) => Ok(output),
+         Err(f) => throw!(IoError, "{} cannot run {}: {}", name, program, Environment::describe_io_error(&f))
+      }
+   }
+
+   fn now(env: Rc<RefCell<Environment>>, _: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("now");
+      if ops != 0 {
+         throw!(ArityError, "now does not take any values");
+      }
+      let source = env.borrow().context.borrow().clock.clone();
+      Ok(Integer(IntegerAst::new(source.clock.now())))
+   }
+
+   fn clock(env: Rc<RefCell<Environment>>, _: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("clock");
+      if ops != 0 {
+         throw!(ArityError, "clock does not take any values");
+      }
+      let source = env.borrow().context.borrow().clock.clone();
+      Ok(Float(FloatAst::new(source.clock.monotonic())))
+   }
+
+   fn sleep(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("sleep");
+      if ops != 1 {
+         throw!(ArityError, "sleep only takes one value (milliseconds)");
+      }
+      let ms = try!(Environment::expect_integer("sleep", &Environment::take_args(stack, ops)[0]));
+      if ms < 0 {
+         throw!(ValueError, "sleep cannot wait for a negative time ({} ms)", ms);
+      }
+      let (source, deadline) = {
+         let context = env.borrow().context.clone();
+         let context = context.borrow();
+         (context.clock.clone(), context.deadline)
+      };
+      // never sleep past the deadline, since nothing could stop the script until the sleep ends
+      match deadline {
+         Some(deadline) => {
+            let left = ((deadline - source.clock.monotonic()) * 1000.0).max(0.0) as i64;
+            if ms > left {
+               source.clock.sleep(left as u64);
+               throw!(LimitError, "execution deadline passed");
+            }
+         }
+         None => {}
+      }
+      source.clock.sleep(ms as u64);
+      Ok(Nil(NilAst::new()))
+   }
+
+   // nil when the variable is unset or its value is not valid unicode
+   fn getenv(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("getenv");
+      if ops != 1 {
+         throw!(ArityError, "getenv only takes one value (name)");
+      }
+      let name = try!(Environment::expect_string("getenv", &Environment::take_args(stack, ops)[0]));
+      let value = match env.borrow().context.borrow().env_vars {
+         Some(ref vars) => vars.find(&name).map(|value| value.clone()),
+         None => os::getenv_as_bytes(name.as_slice()).and_then(|bytes| String::from_utf8(bytes).ok())
+      };
+      Ok(match value {
+         Some(value) => String(StringAst::new(value)),
+         None => Nil(NilAst::new())
+      })
+   }
+
+   fn setenv(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("setenv");
+      if ops != 2 {
+         throw!(ArityError, "setenv only takes two values (name and value)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let name = try!(Environment::expect_string("setenv", &args[0]));
+      let value = try!(Environment::expect_string("setenv", &args[1]));
+      if name.len() == 0 || name.as_slice().contains_char('=') || name.as_slice().contains_char('\0') || value.as_slice().contains_char('\0') {
+         throw!(ValueError, "setenv cannot set {} because the name is empty or contains = or a NUL byte, or the value contains a NUL byte", name);
+      }
+      let context = env.borrow().context.clone();
+      match context.borrow_mut().env_vars {
+         Some(ref mut vars) => { vars.insert(name, value.clone()); }
+         None => os::setenv(name.as_slice(), value.as_slice())
+      }
+      Ok(String(StringAst::new(value)))
+   }
+
+   fn read_file(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("read-file");
+      if ops != 1 {
+         throw!(ArityError, "read-file only takes one value (path)");
+      }
+      let path = try!(Environment::script_path("read-file", env, &Environment::take_args(stack, ops)[0]));
+      let bytes = match io::File::open(&path).read_to_end() {
+         Ok(bytes) => bytes,
+         Err(f) => throw!(IoError, "cannot read {}: {}", path.display(), Environment::describe_io_error(&f))
+      };
+      match String::from_utf8(bytes) {
+         Ok(string) => Ok(String(StringAst::new(string))),
+         Err(_) => throw!(ValueError, "cannot read {}: the file is not valid UTF-8", path.display())
+      }
+   }
+
+   fn write_file(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("write-file");
+      Environment::write_to_file("write-file", io::Truncate, env, stack, ops)
+   }
+
+   fn append_file(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("append-file");
+      Environment::write_to_file("append-file", io::Append, env, stack, ops)
+   }
+
+   fn write_to_file(name: &str, mode: io::FileMode, env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      if ops != 2 {
+         throw!(ArityError, "{} only takes two values (path and string)", name);
+      }
+      let args = Environment::take_args(stack, ops);
+      let path = try!(Environment::script_path(name, env, &args[0]));
+      let contents = try!(Environment::expect_string(name, &args[1]));
+      let result = io::File::open_mode(&path, mode, io::Write).and_then(|mut file| file.write_str(contents.as_slice()));
+      match result {
+         Ok(()) => Ok(Boolean(BooleanAst::new(true))),
+         Err(f) => throw!(IoError, "cannot write {}: {}", path.display(), Environment::describe_io_error(&f))
+      }
+   }
+
+   fn file_exists(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("file-exists?");
+      if ops != 1 {
+         throw!(ArityError, "file-exists? only takes one value (path)");
+      }
+      let path = try!(Environment::script_path("file-exists?", env, &Environment::take_args(stack, ops)[0]));
+      Ok(Boolean(BooleanAst::new(path.exists())))
+   }
+
+   // the next line of input without its line ending, or nil once the input is exhausted
+   fn read_line(env: Rc<RefCell<Environment>>, _: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("read-line");
+      if ops != 0 {
+         throw!(ArityError, "read-line does not take any values");
+      }
+      let input = env.borrow().context.borrow().input.clone();
+      let result = input.reader.borrow_mut().read_line();
+      match result {
+         Ok(mut line) => {
+            if line.as_slice().ends_with("\n") {
+               line.pop_char();
+               if line.as_slice().ends_with("\r") {
+                  line.pop_char();
+               }
+            }
+            Ok(String(StringAst::new(line)))
+         }
+         Err(ref f) if f.kind == io::EndOfFile => Ok(Nil(NilAst::new())),
+         Err(f) => throw!(IoError, "read-line failed: {}", Environment::describe_io_error(&f))
+      }
+   }
+
+   fn read_all(env: Rc<RefCell<Environment>>, _: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("read-all");
+      if ops != 0 {
+         throw!(ArityError, "read-all does not take any values");
+      }
+      let input = env.borrow().context.borrow().input.clone();
+      let result = input.reader.borrow_mut().read_to_end();
+      let bytes = match result {
+         Ok(bytes) => bytes,
+         Err(f) => throw!(IoError, "read-all failed: {}", Environment::describe_io_error(&f))
+      };
+      match String::from_utf8(bytes) {
+         Ok(string) => Ok(String(StringAst::new(string))),
+         Err(_) => throw!(ValueError, "read-all read input that is not valid UTF-8")
+      }
+   }
+
+   // relative paths are relative to the running script, like the ./ paths given to import
+   fn script_path(name: &str, env: Rc<RefCell<Environment>>, value: &ExprAst) -> InterpResult<Path> {
+      if !env.borrow().context.borrow().fs_allowed {
+         throw!(IoError, "{} cannot be used because filesystem access is disabled", name);
+      }
+      let path = Path::new(try!(Environment::expect_string(name, value)).as_slice());
+      if path.is_absolute() {
+         return Ok(path);
+      }
+      match env.borrow().find(&Name::new("FILE")) {
+         Some(Value(String(ref ast))) if ast.string.len() > 0 => Ok(Path::new(ast.string.as_slice()).dir_path().join(path)),
+         _ => Ok(os::make_absolute(&path))
+      }
+   }
+
+   // every value has a type name, which for a struct is the name it was defined with
+   fn type_obj(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("type");
+      if ops != 1 {
+         throw!(ArityError, "type only takes one object");
+      }
+      let value = stack.pop().unwrap();
+      Ok(match value {
+         Struct(ast) => Symbol(SymbolAst::new(ast.name)),
+         other => Symbol(SymbolAst::new(Name::new(Environment::type_name(&other))))
+      })
+   }
+
+   fn is_integer(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("integer?");
+      Environment::type_predicate("integer?", stack, ops, |value| match *value { Integer(_) => true, _ => false })
+   }
+
+   fn is_float(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("float?");
+      Environment::type_predicate("float?", stack, ops, |value| match *value { Float(_) => true, _ => false })
+   }
+
+   fn is_number(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("number?");
+      Environment::type_predicate("number?", stack, ops, |value| match *value { Integer(_) | Float(_) => true, _ => false })
+   }
+
+   fn is_string(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("string?");
+      Environment::type_predicate("string?", stack, ops, |value| match *value { String(_) => true, _ => false })
+   }
+
+   fn is_symbol(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("symbol?");
+      Environment::type_predicate("symbol?", stack, ops, |value| match *value { Symbol(_) => true, _ => false })
+   }
+
+   fn is_array(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("array?");
+      Environment::type_predicate("array?", stack, ops, |value| match *value { Array(_) => true, _ => false })
+   }
+
+   fn is_list(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("list?");
+      Environment::type_predicate("list?", stack, ops, |value| match *value { List(_) => true, _ => false })
+   }
+
+   fn is_dict(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("dict?");
+      Environment::type_predicate("dict?", stack, ops, |value| match *value { Dict(_) => true, _ => false })
+   }
+
+   fn is_boolean(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("boolean?");
+      Environment::type_predicate("boolean?", stack, ops, |value| match *value { Boolean(_) => true, _ => false })
+   }
+
+   fn is_nil(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("nil?");
+      Environment::type_predicate("nil?", stack, ops, |value| match *value { Nil(_) => true, _ => false })
+   }
+
+   fn is_fn(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("fn?");
+      Environment::type_predicate("fn?", stack, ops, |value| match *value { super::ast::Code(_) | Builtin(_) | Host(_) => true, _ => false })
+   }
+
+   fn type_predicate(name: &str, stack: &mut Vec<ExprAst>, ops: uint, test: |&ExprAst| -> bool) -> InterpResult<ExprAst> {
+      if ops != 1 {
+         throw!(ArityError, "{} only takes one value", name);
+      }
+      Ok(Boolean(BooleanAst::new(test(&Environment::take_args(stack, ops)[0]))))
+   }
+
+   // (export a b) limits what importers of the current module can see; a module that never
+   // exports anything makes all of its bindings visible
+   fn export(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("export");
+      let mut names = vec!();
+      for arg in Environment::take_args(stack, ops).move_iter() {
+         match arg {
+            Ident(ast) => names.push(ast.value),
+            other => throw!(TypeError, "export expected a name but found {}", Environment::type_name(&other))
+         }
+      }
+      // the module's own scope is the one just below the builtins
+      let top_level = match env.borrow().parent {
+         Some(ref parent) => parent.borrow().parent.is_none(),
+         None => false
+      };
+      if !top_level {
+         throw!(ValueError, "export can only be used at the top level of a module");
+      }
+      // the script that was run rather than imported has nobody to export to
+      let context = env.borrow().context.clone();
+      let module = match context.borrow().loading.last() {
+         Some(module) => module.clone(),
+         None => return Ok(Nil(NilAst::new()))
+      };
+      let mut context = context.borrow_mut();
+      if !context.exports.contains_key(&module) {
+         context.exports.insert(module.clone(), vec!());
+      }
+      context.exports.get_mut(&module).push_all(names.as_slice());
+      Ok(Nil(NilAst::new()))
+   }
+
+   // runs a module once and hands back the bindings it exports, minus FILE which belongs to
+   // the module and must not replace the importer's
+   fn load_module(env: Rc<RefCell<Environment>>, path: &Path, key: &String) -> InterpResult<collections::HashMap<Name, Rc<RefCell<EnvValue>>>> {
+      let bytes = match io::File::open(path).read_to_end() {
+         Ok(bytes) => bytes,
+         Err(f) => throw!(IoError, "cannot import {}: {}", path.display(), Environment::describe_io_error(&f))
+      };
+      let code = match String::from_utf8(bytes) {
+         Ok(code) => code,
+         Err(_) => throw!(ImportError, "cannot import {}: the file is not valid UTF-8", path.display())
+      };
+      let file = match path.as_str() {
+         Some(file) => file.to_string(),
+         None => throw!(ImportError, "cannot import {}: the path is not valid UTF-8", path.display())
+      };
+      let mut interp = Interpreter::with_context(env.borrow().context.clone());
+      interp.load_code(code);
+      interp.set_file(file);
+      let result = interp.eval();
+      let names = env.borrow().context.borrow_mut().exports.pop(key);
+      try!(result);
+      let mut values = (*interp.env).clone().unwrap().values;
+      values.remove(&Name::new("FILE"));
+      let names = match names {
+         Some(names) => names,
+         None => return Ok(values)
+      };
+      let mut exported = collections::HashMap::new();
+      for name in names.move_iter() {
+         match values.find(&name) {
+            Some(slot) => { exported.insert(name.clone(), slot.clone()); }
+            None => throw!(ImportError, "{} exports {} but never defines it", path.display(), name)
+         }
+      }
+      Ok(exported)
+   }
+
+   // a module that is imported again before it has finished loading would recurse forever
+   fn check_import_cycle(env: Rc<RefCell<Environment>>, key: &String) -> InterpResult<()> {
+      let context = env.borrow().context.clone();
+      let loading = context.borrow().loading.clone();
+      match loading.iter().position(|module| module == key) {
+         Some(start) => {
+            let mut cycle: Vec<String> = loading.slice_from(start).iter().map(|module| Path::new(module.as_slice()).filename_display().to_string()).collect();
+            cycle.push(Path::new(key.as_slice()).filename_display().to_string());
+            throw!(ImportError, "circular import: {}", cycle.connect(" \u2192 "))
+         }
+         None => Ok(())
+      }
+   }
+
+   // the file an import refers to, adding .irl unless the name already has it
+   fn module_file(bare: &Path, name: &str) -> Path {
+      let mut path = bare.clone();
+      if !name.ends_with(".irl") {
+         path.set_extension("irl");
+      }
+      path
+   }
+
+   fn find_module(env: Rc<RefCell<Environment>>, name: &str) -> InterpResult<Path> {
+      let dirs = env.borrow().context.borrow().module_search_path();
+      for dir in dirs.iter() {
+         let bare = dir.join(Path::new(name));
+         let path = Environment::module_file(&bare, name);
+         if path.exists() && !path.is_dir() {
+            return Ok(bare);
+         }
+      }
+      let searched: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+      throw!(ImportError, "cannot find module {}, searched: {}", name, searched.connect(", "))
+   }
+
+   fn describe_io_error(err: &io::IoError) -> String {
+      let kind = match err.kind {
+         io::FileNotFound => "file not found",
+         io::PermissionDenied => "permission denied",
+         io::MismatchedFileTypeForOperation => "not a regular file",
+         _ => err.desc
+      };
+      match err.detail {
+         Some(ref detail) => format!("{} ({})", kind, detail),
+         None => kind.to_string()
+      }
+   }
+
+   pub fn type_name(value: &ExprAst) -> &'static str {
+      match *value {
+         Integer(_) => "integer",
+         Float(_) => "float",
+         Array(_) => "array",
+         List(_) => "list",
+         String(_) => "string",
          Symbol(_) => "symbol",
          super::ast::Code(_) => "code",
+         // scripts cannot tell functions registered by the embedder apart from the real builtins
+         Builtin(_) | Host(_) => "builtin",
          Boolean(_) => "boolean",
          Nil(_) => "nil",
-         _ => fail!() // XXX: fix
-      }.to_string()))
+         Root(_) => "root",
+         Sexpr(_) => "sexpr",
+         Pointer(_) => "pointer",
+         Ident(_) => "ident",
+         Dict(_) => "dict",
+         Struct(_) => "struct"
+      }
+   }
+
+   // NOTE: the optimizer drops calls to assert, so this only runs in Debug mode
+   fn assert(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("assert");
+      if ops < 1 || ops > 2 {
+         throw!(ArityError, "assert takes a condition and an optional message");
+      }
+      let cond = match Environment::take_arg(stack, ops) {
+         Boolean(ast) => ast.value,
+         _ => throw!(TypeError, "assert condition must be a boolean")
+      };
+      let msg =
+         if ops == 2 {
+            match stack.pop().unwrap() {
+               String(ast) => ast.string,
+               _ => throw!(TypeError, "assert message must be a string")
+            }
+         } else {
+            "assertion failed".to_string()
+         };
+      if !cond {
+         throw!(AssertionError, "assertion failed: {}", msg);
+      }
+      Ok(Nil(NilAst::new()))
+   }
+
+   fn substr(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("substr");
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "substr takes a string, a start index, and an optional end index");
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string("substr", &args[0]));
+      let slice = string.as_slice();
+      let strlen = slice.char_len();
+      let start = try!(Environment::char_index("substr", &args[1], strlen));
+      let end =
+         if ops == 3 {
+            try!(Environment::char_index("substr", &args[2], strlen))
+         } else {
+            strlen
+         };
+      // an empty range (including start > end) just yields an empty string
+      if start >= end {
+         Ok(String(StringAst::new("".to_string())))
+      } else {
+         Ok(String(StringAst::new(slice.slice_chars(start, end).to_string())))
+      }
+   }
+
+   fn split(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("split");
+      if ops != 2 {
+         throw!(ArityError, "split only takes two values (string and separator)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string("split", &args[0]));
+      let sep = try!(Environment::expect_string("split", &args[1]));
+      let items =
+         if sep.len() == 0 {
+            Environment::split_chars(string.as_slice())
+         } else {
+            string.as_slice().split_str(sep.as_slice()).map(|part| String(StringAst::new(part.to_string()))).collect()
+         };
+      Ok(Array(ArrayAst::new(items)))
+   }
+
+   fn join(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("join");
+      if ops != 2 {
+         throw!(ArityError, "join only takes two values (array and separator)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let arr = try!(Environment::array_items("join", args[0].clone()));
+      let sep = try!(Environment::expect_string("join", &args[1]));
+      let mut items = vec!();
+      for item in arr.iter() {
+         items.push(try!(Environment::format_value(item)));
+      }
+      Ok(String(StringAst::new(items.connect(sep.as_slice()))))
+   }
+
+   fn upper(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("upper");
+      Environment::map_string("upper", stack, ops, |string| string.chars().map(|ch| ch.to_uppercase()).collect())
+   }
+
+   fn lower(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("lower");
+      Environment::map_string("lower", stack, ops, |string| string.chars().map(|ch| ch.to_lowercase()).collect())
+   }
+
+   fn trim(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("trim");
+      Environment::map_string("trim", stack, ops, |string| string.trim().to_string())
+   }
+
+   fn trim_left(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("trim-left");
+      Environment::map_string("trim-left", stack, ops, |string| string.trim_left().to_string())
+   }
+
+   fn trim_right(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("trim-right");
+      Environment::map_string("trim-right", stack, ops, |string| string.trim_right().to_string())
+   }
+
+   fn contains(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("contains?");
+      if ops != 2 {
+         throw!(ArityError, "contains? only takes two values (array and value, or string and substring)");
+      }
+      let args = Environment::take_args(stack, ops);
+      match args[0] {
+         Array(ref arr) => return Ok(Boolean(BooleanAst::new(arr.items.borrow().iter().any(|item| Environment::values_equal(item, &args[1]))))),
+         _ => {}
+      }
+      let string = try!(Environment::expect_string("contains?", &args[0]));
+      let sub = try!(Environment::expect_string("contains?", &args[1]));
+      Ok(Boolean(BooleanAst::new(string.as_slice().contains(sub.as_slice()))))
+   }
+
+   // indices are in characters (like substr) rather than bytes
+   fn index_of(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("index-of");
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "index-of takes an array or string, the value to find, and an optional start index");
+      }
+      let args = Environment::take_args(stack, ops);
+      match args[0] {
+         Array(ref arr) => {
+            let items = arr.items.borrow();
+            let start =
+               if ops == 3 {
+                  try!(Environment::normalize_index("index-of", try!(Environment::expect_integer("index-of", &args[2])), items.len(), true))
+               } else {
+                  0
+               };
+            return Ok(Integer(IntegerAst::new(match items.slice_from(start).iter().position(|item| Environment::values_equal(item, &args[1])) {
+               Some(idx) => (start + idx) as i64,
+               None => -1
+            })));
+         }
+         _ => {}
+      }
+      let string = try!(Environment::expect_string("index-of", &args[0]));
+      let sub = try!(Environment::expect_string("index-of", &args[1]));
+      let slice = string.as_slice();
+      let strlen = slice.char_len();
+      let start =
+         if ops == 3 {
+            try!(Environment::char_index("index-of", &args[2], strlen))
+         } else {
+            0
+         };
+      let offset = match slice.char_indices().nth(start) {
+         Some((byte, _)) => byte,
+         None => slice.len()
+      };
+      Ok(Integer(IntegerAst::new(match slice.slice_from(offset).find_str(sub.as_slice()) {
+         Some(byte) => (start + slice.slice(offset, offset + byte).char_len()) as i64,
+         None => -1
+      })))
+   }
+
+   fn starts_with(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("starts-with?");
+      if ops != 2 {
+         throw!(ArityError, "starts-with? only takes two values (string and prefix)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string("starts-with?", &args[0]));
+      let prefix = try!(Environment::expect_string("starts-with?", &args[1]));
+      Ok(Boolean(BooleanAst::new(string.as_slice().starts_with(prefix.as_slice()))))
+   }
+
+   fn ends_with(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("ends-with?");
+      if ops != 2 {
+         throw!(ArityError, "ends-with? only takes two values (string and suffix)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string("ends-with?", &args[0]));
+      let suffix = try!(Environment::expect_string("ends-with?", &args[1]));
+      Ok(Boolean(BooleanAst::new(string.as_slice().ends_with(suffix.as_slice()))))
+   }
+
+   fn replace_str(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("replace");
+      if ops < 3 || ops > 4 {
+         throw!(ArityError, "replace takes a string, a search string, a replacement, and an optional limit");
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string("replace", &args[0]));
+      let from = try!(Environment::expect_string("replace", &args[1]));
+      let to = try!(Environment::expect_string("replace", &args[2]));
+      let limit =
+         if ops == 4 {
+            let limit = try!(Environment::expect_integer("replace", &args[3]));
+            if limit < 0 {
+               throw!(ValueError, "replace limit must not be negative");
+            }
+            Some(limit as uint)
+         } else {
+            None
+         };
+      if from.len() == 0 {
+         throw!(ValueError, "replace cannot search for an empty string");
+      }
+      let mut result = String::new();
+      let mut rest = string.as_slice();
+      let mut count = 0u;
+      while !limit.map_or(false, |limit| count >= limit) {
+         match rest.find_str(from.as_slice()) {
+            Some(idx) => {
+               result.push_str(rest.slice_to(idx));
+               result.push_str(to.as_slice());
+               rest = rest.slice_from(idx + from.len());
+               count += 1;
+            }
+            None => break
+         }
+      }
+      result.push_str(rest);
+      Ok(String(StringAst::new(result)))
+   }
+
+   // the pattern compiled, or taken from the cache if it has been used before
+   fn regex(env: &Rc<RefCell<Environment>>, name: &str, pattern: &ExprAst) -> InterpResult<Regex> {
+      let pattern = try!(Environment::expect_string(name, pattern));
+      let cache = env.borrow().context.borrow().regexes.clone();
+      let mut patterns = cache.patterns.borrow_mut();
+      match patterns.find(&pattern) {
+         Some(re) => return Ok(re.clone()),
+         None => {}
+      }
+      match Regex::new(pattern.as_slice()) {
+         Ok(re) => {
+            patterns.insert(pattern, re.clone());
+            Ok(re)
+         }
+         Err(f) => throw!(ValueError, "{} could not compile {}: {}", name, pattern, f)
+      }
+   }
+
+   // the whole match and then each group, where a group that took no part in the match is nil;
+   // nil if there is no match at all
+   fn re_match(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("re-match");
+      if ops != 2 {
+         throw!(ArityError, "re-match only takes two values (pattern and string)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let re = try!(Environment::regex(&env, "re-match", &args[0]));
+      let text = try!(Environment::expect_string("re-match", &args[1]));
+      Ok(match re.captures(text.as_slice()) {
+         Some(caps) => {
+            let groups = range(0, caps.len()).map(|i| match caps.pos(i) {
+               Some((start, end)) => String(StringAst::new(text.as_slice().slice(start, end).to_string())),
+               None => Nil(NilAst::new())
+            }).collect();
+            Array(ArrayAst::new(groups))
+         }
+         None => Nil(NilAst::new())
+      })
+   }
+
+   fn re_find_all(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("re-find-all");
+      if ops != 2 {
+         throw!(ArityError, "re-find-all only takes two values (pattern and string)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let re = try!(Environment::regex(&env, "re-find-all", &args[0]));
+      let text = try!(Environment::expect_string("re-find-all", &args[1]));
+      let found = re.find_iter(text.as_slice()).map(|(start, end)| String(StringAst::new(text.as_slice().slice(start, end).to_string()))).collect();
+      Ok(Array(ArrayAst::new(found)))
+   }
+
+   // $1 or ${1} in the replacement stands for that group of the match, and $$ for a $
+   fn re_replace(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("re-replace");
+      if ops != 3 {
+         throw!(ArityError, "re-replace only takes three values (pattern, string and replacement)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let re = try!(Environment::regex(&env, "re-replace", &args[0]));
+      let text = try!(Environment::expect_string("re-replace", &args[1]));
+      let replacement = try!(Environment::expect_string("re-replace", &args[2]));
+      Ok(String(StringAst::new(re.replace_all(text.as_slice(), replacement.as_slice()))))
+   }
+
+   fn re_split(env: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("re-split");
+      if ops != 2 {
+         throw!(ArityError, "re-split only takes two values (pattern and string)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let re = try!(Environment::regex(&env, "re-split", &args[0]));
+      let text = try!(Environment::expect_string("re-split", &args[1]));
+      let pieces = re.split(text.as_slice()).map(|piece| String(StringAst::new(piece.to_string()))).collect();
+      Ok(Array(ArrayAst::new(pieces)))
+   }
+
+   fn json_parse(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("json-parse");
+      if ops != 1 {
+         throw!(ArityError, "json-parse only takes one string");
+      }
+      let text = try!(Environment::expect_string("json-parse", &stack.pop().unwrap()));
+      json::parse(text.as_slice())
+   }
+
+   // only nil, booleans, numbers, strings, arrays and dicts have a JSON form
+   fn json_encode(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("json-encode");
+      if ops < 1 || ops > 2 {
+         throw!(ArityError, "json-encode takes a value and an optional pretty flag");
+      }
+      let args = Environment::take_args(stack, ops);
+      let pretty =
+         if ops == 2 {
+            match args[1] {
+               Boolean(ref ast) => ast.value,
+               _ => throw!(TypeError, "json-encode pretty flag must be a boolean")
+            }
+         } else {
+            false
+         };
+      Ok(String(StringAst::new(try!(json::encode(&args[0], pretty)))))
+   }
+
+   fn chars(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("chars");
+      if ops != 1 {
+         throw!(ArityError, "chars only takes one value (string)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string("chars", &args[0]));
+      Ok(Array(ArrayAst::new(Environment::split_chars(string.as_slice()))))
+   }
+
+   fn string_from_chars(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("string-from-chars");
+      if ops != 1 {
+         throw!(ArityError, "string-from-chars only takes one value (array of characters)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let items = try!(Environment::array_items("string-from-chars", args[0].clone()));
+      let mut result = String::new();
+      for item in items.iter() {
+         let ch = try!(Environment::expect_string("string-from-chars", item));
+         if ch.as_slice().char_len() != 1 {
+            throw!(TypeError, "string-from-chars expected a single character but found \"{}\"", ch);
+         }
+         result.push_str(ch.as_slice());
+      }
+      Ok(String(StringAst::new(result)))
+   }
+
+   fn repeat(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("repeat");
+      if ops != 2 {
+         throw!(ArityError, "repeat only takes two values (string and count)");
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string("repeat", &args[0]));
+      let count = try!(Environment::expect_integer("repeat", &args[1]));
+      let mut result = String::new();
+      for _ in range(0, count) {
+         result.push_str(string.as_slice());
+      }
+      Ok(String(StringAst::new(result)))
+   }
+
+   fn pad_left(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("pad-left");
+      Environment::pad("pad-left", stack, ops, true)
+   }
+
+   fn pad_right(_: Rc<RefCell<Environment>>, stack: &mut Vec<ExprAst>, ops: uint) -> InterpResult<ExprAst> {
+      debug!("pad-right");
+      Environment::pad("pad-right", stack, ops, false)
+   }
+
+   fn pad(name: &str, stack: &mut Vec<ExprAst>, ops: uint, left: bool) -> InterpResult<ExprAst> {
+      if ops < 2 || ops > 3 {
+         throw!(ArityError, "{} takes a string, a width, and an optional pad character", name);
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string(name, &args[0]));
+      let width = try!(Environment::expect_integer(name, &args[1]));
+      let padding =
+         if ops == 3 {
+            let padding = try!(Environment::expect_string(name, &args[2]));
+            if padding.as_slice().char_len() != 1 {
+               throw!(TypeError, "{} pad character must be a single character but found \"{}\"", name, padding);
+            }
+            padding.as_slice().char_at(0)
+         } else {
+            ' '
+         };
+      let strlen = string.as_slice().char_len() as i64;
+      if width <= strlen {
+         return Ok(String(StringAst::new(string)));
+      }
+      let fill = String::from_char((width - strlen) as uint, padding);
+      Ok(String(StringAst::new(if left { fill.append(string.as_slice()) } else { string.append(fill.as_slice()) })))
+   }
+
+   // each character becomes a one-character string
+   fn split_chars(string: &str) -> Vec<ExprAst> {
+      string.chars().map(|ch| String(StringAst::new(String::from_char(1, ch)))).collect()
+   }
+
+   fn map_string(name: &str, stack: &mut Vec<ExprAst>, ops: uint, func: |&str| -> String) -> InterpResult<ExprAst> {
+      if ops != 1 {
+         throw!(ArityError, "{} only takes one value (string)", name);
+      }
+      let args = Environment::take_args(stack, ops);
+      let string = try!(Environment::expect_string(name, &args[0]));
+      Ok(String(StringAst::new(func(string.as_slice()))))
+   }
+
+   // the offset of element idx in a sequence of length len, counting from the back if idx is
+   // negative; with end_ok, len itself is allowed too, for inserting at the end
+   fn normalize_index(name: &str, idx: i64, len: uint, end_ok: bool) -> InterpResult<uint> {
+      // the length of anything in memory fits in an i64, so none of this can overflow
+      let len = len as i64;
+      let resolved = if idx < 0 { len + idx } else { idx };
+      let limit = if end_ok { len } else { len - 1 };
+      if resolved < 0 || resolved > limit {
+         throw!(IndexError, "{}: index {} is out of bounds for an array of length {}", name, idx, len);
+      }
+      Ok(resolved as uint)
+   }
+
+   // normalize_index for a position in a string of len characters, where the end is allowed
+   fn char_index(name: &str, value: &ExprAst, len: uint) -> InterpResult<uint> {
+      let idx = try!(Environment::expect_integer(name, value));
+      match Environment::normalize_index(name, idx, len, true) {
+         Ok(resolved) => Ok(resolved),
+         Err(_) => throw!(IndexError, "{}: index {} is out of bounds for a string of length {}", name, idx, len)
+      }
+   }
+
+   // removes the top ops values from the stack, returning them in the order they were pushed
+   pub fn take_args(stack: &mut Vec<ExprAst>, ops: uint) -> Vec<ExprAst> {
+      // popping and reversing moves each argument once, where removing from the middle of the
+      // stack would shift everything above it every time
+      let mut args = Vec::with_capacity(ops);
+      for _ in range(0, ops) {
+         args.push(stack.pop().unwrap());
+      }
+      args.reverse();
+      args
+   }
+
+   // removes the argument that is `back` places from the top of the stack
+   fn take_arg(stack: &mut Vec<ExprAst>, back: uint) -> ExprAst {
+      let idx = stack.len() - back;
+      stack.remove(idx).unwrap()
+   }
+
+   // returns a snapshot of the items, so callers can run code that mutates the array meanwhile
+   fn array_items(name: &str, value: ExprAst) -> InterpResult<Vec<ExprAst>> {
+      match value {
+         Array(ast) => Ok(ast.items.borrow().clone()),
+         other => throw!(TypeError, "{} expected an array but found {}", name, Environment::type_name(&other))
+      }
+   }
+
+   fn expect_integer(name: &str, value: &ExprAst) -> InterpResult<i64> {
+      match *value {
+         Integer(ref ast) => Ok(ast.value),
+         ref other => throw!(TypeError, "{} expected an integer but found {}", name, Environment::type_name(other))
+      }
+   }
+
+   fn expect_string(name: &str, value: &ExprAst) -> InterpResult<String> {
+      match *value {
+         String(ref ast) => Ok(ast.string.clone()),
+         ref other => throw!(TypeError, "{} expected a string but found {}", name, Environment::type_name(other))
+      }
+   }
+}
+
+#[cfg(test)]
+mod test {
+   use std::cell::Cell;
+   use std::collections::HashMap;
+   use std::io::MemReader;
+   use std::io::util::NullWriter;
+   use std::os;
+   use std::rc::Rc;
+
+   use ast::*;
+   use super::{Interpreter, InterpError, InterpResult, CallContext, HostFunction, Clock};
+   use super::{TypeError, ValueError, UnknownIdent, LimitError, ArityError, Exit, Bytecode, Debug};
+   use super::UNLIMITED;
+
+   // starts at a fixed time and only moves when something sleeps
+   struct FakeClock {
+      elapsed: Rc<Cell<u64>>
+   }
+
+   impl Clock for FakeClock {
+      fn now(&self) -> i64 {
+         1400000000000 + self.elapsed.get() as i64
+      }
+
+      fn monotonic(&self) -> f64 {
+         self.elapsed.get() as f64 / 1000.0
+      }
+
+      fn sleep(&self, ms: u64) {
+         self.elapsed.set(self.elapsed.get() + ms);
+      }
+   }
+
+   fn fake_clock(interp: &mut Interpreter) -> Rc<Cell<u64>> {
+      let elapsed = Rc::new(Cell::new(0));
+      interp.set_clock(box FakeClock { elapsed: elapsed.clone() } as Box<Clock>);
+      elapsed
+   }
+
+   fn host_sum(cx: &mut CallContext) -> InterpResult<ExprAst> {
+      let mut total = 0;
+      for arg in cx.args.iter() {
+         match arg.as_i64() {
+            Some(value) => total += value,
+            None => return Err(InterpError::new(TypeError, format!("{} only adds integers", cx.name)))
+         }
+      }
+      Ok(ExprAst::from_i64(total))
+   }
+
+   // a host function with state of its own
+   struct Counter {
+      calls: Rc<Cell<uint>>
+   }
+
+   impl HostFunction for Counter {
+      fn call(&self, _: &mut CallContext) -> InterpResult<ExprAst> {
+         self.calls.set(self.calls.get() + 1);
+         Ok(ExprAst::from_i64(self.calls.get() as i64))
+      }
+   }
+
+   #[test]
+   fn getenv_sees_variables_set_by_the_host() {
+      os::setenv("IRON_TEST_GETENV", "from rust");
+      os::unsetenv("IRON_TEST_GETENV_UNSET");
+      let mut interp = Interpreter::new();
+      let value = interp.eval_str("(getenv \"IRON_TEST_GETENV\")").unwrap();
+      assert_eq!(value.as_str(), Some("from rust"));
+      assert!(interp.eval_str("(getenv \"IRON_TEST_GETENV_UNSET\")").unwrap().is_nil());
+   }
+
+   #[test]
+   fn getenv_gives_nil_for_values_that_are_not_utf8() {
+      os::setenv("IRON_TEST_GETENV_BYTES", [0xffu8, 0xfe].as_slice());
+      let mut interp = Interpreter::new();
+      assert!(interp.eval_str("(getenv \"IRON_TEST_GETENV_BYTES\")").unwrap().is_nil());
+   }
+
+   #[test]
+   fn setenv_changes_the_process_environment() {
+      let mut interp = Interpreter::new();
+      interp.eval_str("(setenv \"IRON_TEST_SETENV\" \"from a script\")").unwrap();
+      assert_eq!(os::getenv("IRON_TEST_SETENV"), Some("from a script".to_string()));
+   }
+
+   #[test]
+   fn sandboxed_env_vars_leave_the_process_alone() {
+      os::setenv("IRON_TEST_SANDBOX", "outside");
+      let mut vars = HashMap::new();
+      vars.insert("IRON_TEST_SANDBOX".to_string(), "inside".to_string());
+      let mut interp = Interpreter::new();
+      interp.sandbox_env_vars(vars);
+      let value = interp.eval_str("(getenv \"IRON_TEST_SANDBOX\")").unwrap();
+      assert_eq!(value.as_str(), Some("inside"));
+      interp.eval_str("(setenv \"IRON_TEST_SANDBOX\" \"changed\")").unwrap();
+      let value = interp.eval_str("(getenv \"IRON_TEST_SANDBOX\")").unwrap();
+      assert_eq!(value.as_str(), Some("changed"));
+      assert_eq!(os::getenv("IRON_TEST_SANDBOX"), Some("outside".to_string()));
+   }
+
+   #[test]
+   fn read_line_and_read_all_use_the_input_given() {
+      let mut interp = Interpreter::new();
+      interp.set_input(box MemReader::new(b"first\r\nsecond\nthe rest\nof it".to_vec()) as Box<Reader>);
+      let value = interp.eval_str("(read-line)").unwrap();
+      assert_eq!(value.as_str(), Some("first"));
+      let value = interp.eval_str("(read-line)").unwrap();
+      assert_eq!(value.as_str(), Some("second"));
+      let value = interp.eval_str("(read-all)").unwrap();
+      assert_eq!(value.as_str(), Some("the rest\nof it"));
+      assert!(interp.eval_str("(read-line)").unwrap().is_nil());
+      let value = interp.eval_str("(read-all)").unwrap();
+      assert_eq!(value.as_str(), Some(""));
+   }
+
+   #[test]
+   fn read_line_gives_the_last_line_without_a_newline_then_nil() {
+      let mut interp = Interpreter::new();
+      interp.set_input(box MemReader::new(b"only".to_vec()) as Box<Reader>);
+      let value = interp.eval_str("(read-line)").unwrap();
+      assert_eq!(value.as_str(), Some("only"));
+      assert!(interp.eval_str("(read-line)").unwrap().is_nil());
+      assert!(interp.eval_str("(read-line)").unwrap().is_nil());
+   }
+
+   #[test]
+   fn print_and_println_write_to_the_captured_output() {
+      let mut interp = Interpreter::new();
+      interp.capture_output();
+      interp.eval_str("(print 1 \" \" 2) (println \"three\") (println [1 [2 3]] \" \" 1.5)").unwrap();
+      assert_eq!(interp.captured_output(), Some(b"1 2three\n[1 [2 3]] 1.5\n".to_vec()));
+   }
+
+   #[test]
+   fn captured_output_keeps_growing_across_evaluations() {
+      let mut interp = Interpreter::new();
+      interp.capture_output();
+      interp.eval_str("(define greet (fn [name] (println \"hello \" name)))").unwrap();
+      assert_eq!(interp.captured_output(), Some(vec!()));
+      interp.eval_str("(greet \"a\") (greet \"b\")").unwrap();
+      assert_eq!(interp.captured_output(), Some(b"hello a\nhello b\n".to_vec()));
+   }
+
+   #[test]
+   fn errors_go_to_the_error_output_rather_than_the_output() {
+      let mut interp = Interpreter::new();
+      interp.capture_output();
+      interp.capture_error_output();
+      interp.eval_str("(eprintln \"oops\") (println \"fine\")").unwrap();
+      assert_eq!(interp.captured_output(), Some(b"fine\n".to_vec()));
+      assert_eq!(interp.captured_error_output(), Some(b"oops\n".to_vec()));
+   }
+
+   #[test]
+   fn output_is_only_captured_when_asked_for() {
+      let mut interp = Interpreter::new();
+      assert_eq!(interp.captured_output(), None);
+      interp.capture_output();
+      interp.set_output(box NullWriter as Box<Writer>);
+      interp.eval_str("(println \"dropped\")").unwrap();
+      assert_eq!(interp.captured_output(), None);
+   }
+
+   #[test]
+   fn now_clock_and_sleep_use_the_clock_given() {
+      let mut interp = Interpreter::new();
+      let elapsed = fake_clock(&mut interp);
+      assert_eq!(interp.eval_str("(now)").unwrap().as_i64(), Some(1400000000000));
+      assert_eq!(interp.eval_str("(clock)").unwrap().as_f64(), Some(0.0));
+      interp.eval_str("(sleep 250)").unwrap();
+      assert_eq!(elapsed.get(), 250);
+      assert_eq!(interp.eval_str("(now)").unwrap().as_i64(), Some(1400000000250));
+      assert_eq!(interp.eval_str("(clock)").unwrap().as_f64(), Some(0.25));
+   }
+
+   #[test]
+   fn sleep_rejects_a_negative_time() {
+      let mut interp = Interpreter::new();
+      let elapsed = fake_clock(&mut interp);
+      match interp.eval_str("(sleep -1)") {
+         Err(f) => assert_eq!(f.kind, ValueError),
+         Ok(_) => fail!("(sleep -1) should have failed")
+      }
+      assert_eq!(elapsed.get(), 0);
+   }
+
+   #[test]
+   fn sleep_stops_at_the_deadline() {
+      let mut interp = Interpreter::new();
+      let elapsed = fake_clock(&mut interp);
+      interp.set_deadline(Some(100));
+      match interp.eval_str("(sleep 500)") {
+         Err(f) => assert_eq!(f.kind, LimitError),
+         Ok(_) => fail!("(sleep 500) should have passed the deadline")
+      }
+      assert_eq!(elapsed.get(), 100);
+   }
+
+   #[test]
+   fn registered_builtins_are_called_like_any_other() {
+      let mut interp = Interpreter::new();
+      interp.register_builtin("host-sum", box (host_sum as fn(&mut CallContext) -> InterpResult<ExprAst>) as Box<HostFunction>);
+      assert_eq!(interp.eval_str("(host-sum 1 2 3)").unwrap().as_i64(), Some(6));
+      assert_eq!(interp.eval_str("(host-sum)").unwrap().as_i64(), Some(0));
+      assert_eq!(interp.eval_str("(reduce host-sum 0 [4 5 6])").unwrap().as_i64(), Some(15));
+      assert_eq!(interp.eval_str("(fn? host-sum)").unwrap().as_bool(), Some(true));
+      match interp.eval_str("(host-sum 1 \"two\")") {
+         Err(f) => {
+            assert_eq!(f.kind, TypeError);
+            assert_eq!(f.message, "host-sum only adds integers".to_string());
+         }
+         Ok(_) => fail!("host-sum should have rejected a string")
+      }
+   }
+
+   #[test]
+   fn registered_builtins_keep_their_state_and_can_be_shadowed() {
+      let calls = Rc::new(Cell::new(0));
+      let mut interp = Interpreter::new();
+      interp.register_builtin("tick", box Counter { calls: calls.clone() } as Box<HostFunction>);
+      interp.eval_str("(tick) (tick)").unwrap();
+      assert_eq!(calls.get(), 2);
+      assert_eq!(interp.eval_str("(define tick (fn [] 0)) (tick)").unwrap().as_i64(), Some(0));
+      assert_eq!(calls.get(), 2);
+   }
+
+   #[test]
+   fn host_can_set_globals_and_call_script_functions() {
+      let mut interp = Interpreter::new();
+      interp.set_global("config", vec!(2i64, 3).to_expr());
+      interp.eval_str("(define handler (fn [x] (map (fn [c] (+ c x)) config)))").unwrap();
+      let result = interp.call("handler", vec!(ExprAst::from_i64(10))).unwrap();
+      assert_eq!(result.to::<Vec<i64>>(), Some(vec!(12, 13)));
+      // handler looks config up when it runs, so it sees the new value
+      interp.set_global("config", vec!(1i64).to_expr());
+      let result = interp.call("handler", vec!(ExprAst::from_i64(1))).unwrap();
+      assert_eq!(result.to::<Vec<i64>>(), Some(vec!(2)));
+   }
+
+   #[test]
+   fn get_global_sees_script_bindings_and_builtins() {
+      let mut interp = Interpreter::new();
+      assert!(interp.get_global("answer").is_none());
+      interp.eval_str("(define answer 41) (set! answer (+ answer 1))").unwrap();
+      assert_eq!(interp.get_global("answer").and_then(|value| value.as_i64()), Some(42));
+      assert!(interp.get_global("len").is_some());
+      let result = interp.call("len", vec!(vec!(1i64, 2, 3).to_expr())).unwrap();
+      assert_eq!(result.as_i64(), Some(3));
+   }
+
+   #[test]
+   fn calling_an_unknown_function_is_an_error() {
+      let mut interp = Interpreter::new();
+      match interp.call("missing", vec!()) {
+         Err(f) => assert_eq!(f.kind, UnknownIdent),
+         Ok(_) => fail!("calling missing should have failed")
+      }
+   }
+
+   #[test]
+   fn eval_str_keeps_definitions_between_calls() {
+      let mut interp = Interpreter::new();
+      assert_eq!(interp.eval_str("(define x 2)").unwrap().as_i64(), Some(2));
+      assert_eq!(interp.eval_str("(+ x 40)").unwrap().as_i64(), Some(42));
+   }
+
+   #[test]
+   fn eval_gives_back_the_value_of_the_last_form() {
+      let mut interp = Interpreter::new();
+      let value = interp.eval_str("1 (+ 1 1) \"three\"").unwrap();
+      assert_eq!(value.as_str(), Some("three"));
+      assert!(interp.eval_str("").unwrap().is_nil());
+      assert!(interp.eval_str("(if false 1)").unwrap().is_nil());
+      interp.set_backend(Bytecode);
+      assert_eq!(interp.eval_str("(define y 5) (+ y 1)").unwrap().as_i64(), Some(6));
+   }
+
+   #[test]
+   fn eval_keeps_what_ran_before_an_error() {
+      let mut interp = Interpreter::new();
+      assert!(interp.eval_str("(define before 1) (no-such-function) (define after 2)").is_err());
+      assert_eq!(interp.get_global("before").and_then(|value| value.as_i64()), Some(1));
+      assert!(interp.get_global("after").is_none());
+      assert_eq!(interp.eval_str("(+ before 1)").unwrap().as_i64(), Some(2));
+   }
+
+   #[test]
+   fn exit_is_an_error_to_eval_but_a_status_to_execute() {
+      let mut interp = Interpreter::new();
+      match interp.eval_str("(exit 3)") {
+         Err(f) => assert_eq!(f.kind, Exit(3)),
+         Ok(_) => fail!("exit should have unwound eval")
+      }
+      interp.load_code("(exit 4)".to_string());
+      assert_eq!(interp.execute().unwrap(), 4);
+   }
+
+   #[test]
+   fn restore_discards_what_happened_after_the_snapshot() {
+      let mut interp = Interpreter::new();
+      interp.eval_str("(define a 1) (define items [1 2])").unwrap();
+      let snapshot = interp.snapshot();
+      interp.eval_str("(define b 2) (set! a 5) (push items 3)").unwrap();
+      interp.restore(&snapshot);
+      assert_eq!(interp.get_global("a").and_then(|value| value.as_i64()), Some(1));
+      assert!(interp.get_global("b").is_none());
+      assert_eq!(interp.get_global("items").and_then(|value| value.to::<Vec<i64>>()), Some(vec!(1, 2)));
+      // the snapshot itself is untouched, so it can be used again
+      interp.eval_str("(set! a 6)").unwrap();
+      interp.restore(&snapshot);
+      assert_eq!(interp.get_global("a").and_then(|value| value.as_i64()), Some(1));
+   }
+
+   #[test]
+   fn closures_from_before_a_restore_keep_their_own_environment() {
+      let mut interp = Interpreter::new();
+      interp.eval_str("(define make (fn [] (define n 0) (fn [] (set! n (+ n 1)) n))) (define next (make)) (next)").unwrap();
+      let snapshot = interp.snapshot();
+      assert_eq!(interp.eval_str("(next)").unwrap().as_i64(), Some(2));
+      let old = interp.get_global("next").unwrap();
+      interp.restore(&snapshot);
+      // the restored next counts on from where the snapshot was taken
+      assert_eq!(interp.eval_str("(next)").unwrap().as_i64(), Some(2));
+      // but the copy the host held on to still uses the environment the restore threw away
+      let env = interp.env.clone();
+      assert_eq!(Interpreter::call_value(env, &mut vec!(), &old, vec!()).unwrap().as_i64(), Some(3));
+      assert_eq!(interp.eval_str("(next)").unwrap().as_i64(), Some(3));
+   }
+
+   #[test]
+   fn reset_keeps_host_builtins_and_arguments_only() {
+      let mut interp = Interpreter::new();
+      interp.register_builtin("host-sum", box (host_sum as fn(&mut CallContext) -> InterpResult<ExprAst>) as Box<HostFunction>);
+      interp.set_args(vec!("first".to_string()));
+      interp.eval_str("(define x 1) (define len 0)").unwrap();
+      interp.reset();
+      assert!(interp.get_global("x").is_none());
+      assert_eq!(interp.eval_str("(len [1 2])").unwrap().as_i64(), Some(2));
+      assert_eq!(interp.eval_str("(host-sum 1 2)").unwrap().as_i64(), Some(3));
+      assert_eq!(interp.eval_str("(get ARGV 0)").unwrap().as_str(), Some("first"));
+      interp.eval_str("(define x 2)").unwrap();
+   }
+
+   fn call_with_nils(interp: &mut Interpreter, name: &Name, count: uint) -> InterpResult<ExprAst> {
+      let operands: Vec<&str> = Vec::from_elem(count, " nil");
+      interp.eval_str(format!("({}{})", name, operands.concat()).as_slice())
+   }
+
+   fn is_arity_error(result: InterpResult<ExprAst>) -> bool {
+      match result {
+         Err(InterpError { kind: ArityError, .. }) => true,
+         _ => false
+      }
+   }
+
+   // --check goes by the arity each builtin is registered with, so that has to be where the
+   // builtin itself starts and stops throwing ArityError
+   #[test]
+   fn builtins_check_the_arity_they_are_registered_with() {
+      let mut interp = Interpreter::new();
+      // assert is only called at all in debug mode, and exec and system only check their
+      // operands once they are allowed to run
+      interp.set_mode(Debug);
+      interp.allow_exec(true);
+      interp.set_input(box MemReader::new(vec!()) as Box<Reader>);
+      interp.set_output(box NullWriter as Box<Writer>);
+      interp.set_error_output(box NullWriter as Box<Writer>);
+      let arities = interp.env.borrow().context.borrow().arities.clone();
+      assert!(arities.len() > 100);
+      for (name, &(min, max)) in arities.iter() {
+         if min > 0 {
+            assert!(is_arity_error(call_with_nils(&mut interp, name, min - 1)), "{} takes fewer than {} operands", name, min);
+         }
+         assert!(!is_arity_error(call_with_nils(&mut interp, name, min)), "{} does not take {} operands", name, min);
+         if max != UNLIMITED {
+            assert!(!is_arity_error(call_with_nils(&mut interp, name, max)), "{} does not take {} operands", name, max);
+            assert!(is_arity_error(call_with_nils(&mut interp, name, max + 1)), "{} takes more than {} operands", name, max);
+         }
+      }
    }
 }
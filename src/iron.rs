@@ -9,13 +9,19 @@
 extern crate collections;
 extern crate getopts;
 extern crate libc;
+extern crate regex;
+extern crate time;
 
 use std::io;
 use std::os;
 
 mod interp;
 mod ast;
+mod bytecode;
+mod check;
+mod json;
 mod parser;
+mod repl;
 
 static NAME: &'static str = "iron";
 static VERSION: &'static str = "0.1";
@@ -26,7 +32,14 @@ fn main() {
 
    let opts = [
       getopts::optflag("d", "debug", "debug mode"),
+      getopts::optmulti("L", "module-path", "add a directory to search for imported modules", "DIR"),
+      getopts::optflag("", "allow-exec", "let the script run other programs with exec and system"),
+      getopts::optflag("", "bytecode", "compile the code to bytecode before running it"),
+      getopts::optopt("", "fuel", "stop the script after evaluating this many nodes", "STEPS"),
       getopts::optflag("", "ast", "print out the AST instead of interpreting the code"),
+      getopts::optflag("", "check", "report mistakes in the code without running it"),
+      getopts::optopt("", "ast-format", "how --ast prints the AST: tree (the default), sexpr or counts", "FORMAT"),
+      getopts::optmulti("", "rename", "rename an identifier throughout the code before using it", "OLD=NEW"),
       getopts::optflag("", "status", "print out the exit status of the program"),
       getopts::optflag("V", "version", "print the version number"),
       getopts::optflag("h", "help", "print this help menu"),
@@ -41,20 +54,49 @@ fn main() {
       }
    };
 
+   let mode =
+      if matches.opt_present("d") {
+         interp::Debug
+      } else {
+         interp::Release
+      };
+
+   let fuel = match matches.opt_str("fuel") {
+      Some(fuel) => match from_str::<u64>(fuel.as_slice()) {
+         Some(fuel) => Some(fuel),
+         None => {
+            error!("--fuel expects a number of steps but got {}", fuel);
+            os::set_exit_status(1);
+            return
+         }
+      },
+      None => None
+   };
+
    if matches.opt_present("h") {
       help_menu(program, opts);
    } else if matches.opt_present("V") {
       version();
    } else if matches.free.len() == 0 {
-      error!("REPL NYI");
-      os::set_exit_status(1);
+      let mut interp = interp::Interpreter::new();
+      interp.set_mode(mode);
+      if matches.opt_present("bytecode") {
+         interp.set_backend(interp::Bytecode);
+      }
+      // the whole session shares one allowance of fuel
+      interp.set_fuel(fuel);
+      interp.allow_exec(matches.opt_present("allow-exec"));
+      for dir in matches.opt_strs("L").iter() {
+         interp.add_module_path(Path::new(dir.as_slice()));
+      }
+      match repl::run(&mut interp, &mut io::stdin(), &mut io::stdout()) {
+         Ok(status) => os::set_exit_status(status),
+         Err(f) => {
+            error!("{}", f);
+            os::set_exit_status(1);
+         }
+      }
    } else {
-      let mode =
-         if matches.opt_present("d") {
-            interp::Debug
-         } else {
-            interp::Release
-         };
       let code = match io::File::open(&Path::new(matches.free[0].as_slice())) {
          Ok(mut file) => file.read_to_string().unwrap(),
          Err(f) => {
@@ -65,18 +107,81 @@ fn main() {
       };
       let mut interp = interp::Interpreter::new();
       interp.set_mode(mode);
+      if matches.opt_present("bytecode") {
+         interp.set_backend(interp::Bytecode);
+      }
       interp.set_file(matches.free[0].to_string());
+      interp.set_args(matches.free.tail().to_vec());
+      interp.allow_exec(matches.opt_present("allow-exec"));
+      for dir in matches.opt_strs("L").iter() {
+         interp.add_module_path(Path::new(dir.as_slice()));
+      }
+      if matches.opt_present("check") {
+         let diagnostics = interp.check_file(&Path::new(matches.free[0].as_slice()));
+         for diagnostic in diagnostics.iter() {
+            interp.report_error(diagnostic);
+         }
+         let status = if diagnostics.is_empty() { 0 } else { 1 };
+         if matches.opt_present("status") {
+            println!("exit status: {}", status);
+         }
+         os::set_exit_status(status);
+         return
+      }
+      interp.set_fuel(fuel);
       //interp.load_code("(fn hi [param] (+ 1 param))".to_string());
       //interp.load_code("(fn hi 1 \"hello world\" 1.05 '(1 2 3.0 4 3.4) [hi 2.354 0.1 \"hi\" (hi)])".to_string());
       //interp.load_code("(println (add 2 3.4))".to_string());
       interp.load_code(code);
+      for rename in matches.opt_strs("rename").iter() {
+         let (from, to) = match rename.as_slice().find('=') {
+            Some(i) => (rename.as_slice().slice_to(i), rename.as_slice().slice_from(i + 1)),
+            None => {
+               error!("--rename expects OLD=NEW but got {}", rename);
+               os::set_exit_status(1);
+               return
+            }
+         };
+         match interp.rename(from, to) {
+            Ok(()) => {}
+            Err(f) => {
+               interp.report_error(&f);
+               os::set_exit_status(1);
+               return
+            }
+         }
+      }
       if matches.opt_present("ast") {
-         interp.dump_ast();
+         let format = match matches.opt_str("ast-format") {
+            None => interp::TreeFormat,
+            Some(ref format) if format.as_slice() == "tree" => interp::TreeFormat,
+            Some(ref format) if format.as_slice() == "sexpr" => interp::SexprFormat,
+            Some(ref format) if format.as_slice() == "counts" => interp::CountsFormat,
+            Some(format) => {
+               error!("--ast-format expects tree, sexpr or counts but got {}", format);
+               os::set_exit_status(1);
+               return
+            }
+         };
+         match interp.print_ast(format) {
+            Ok(()) => {}
+            Err(f) => {
+               interp.report_error(&f);
+               os::set_exit_status(1);
+            }
+         }
       } else {
-         let status = interp.execute();
+         let status = match interp.execute() {
+            Ok(status) => status,
+            Err(f) => {
+               interp.report_error(&f);
+               1
+            }
+         };
          if matches.opt_present("status") {
             println!("exit status: {}", status);
          }
+         os::set_exit_status(status);
       }
    }
 }
@@ -91,7 +196,7 @@ fn help_menu(program: &str, opts: &[getopts::OptGroup]) {
    version();
    println!("");
    println!("Usage:");
-   println!("    {} [OPTIONS...] FILES...", program);
+   println!("    {} [OPTIONS...] FILE [ARGS...]", program);
    println!("");
    print!("{}", getopts::usage("A simple, Lisp-based programming language written in Rust.", opts));
 }
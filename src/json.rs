@@ -0,0 +1,412 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use ast::*;
+use interp::{Environment, InterpError, InterpResult, TypeError, ValueError, LimitError};
+
+// arrays and objects nested deeper than this are refused rather than risking the stack
+static MAX_DEPTH: uint = 512;
+
+// objects become dicts, and numbers become integers when they are written without a fraction or
+// exponent and fit in one, so that ids too large for a float come through exactly
+pub fn parse(text: &str) -> InterpResult<ExprAst> {
+   let mut parser = JsonParser {
+      text: text,
+      pos: 0,
+      depth: 0
+   };
+   let value = try!(parser.parse_value());
+   parser.skip_whitespace();
+   if parser.pos < text.len() {
+      return Err(parser.error(format!("found {} after the value", parser.describe_next())));
+   }
+   Ok(value)
+}
+
+// pretty puts each item on its own line, indented by two spaces for each level
+pub fn encode(value: &ExprAst, pretty: bool) -> InterpResult<String> {
+   let mut encoder = JsonEncoder {
+      out: String::new(),
+      pretty: pretty,
+      open: vec!()
+   };
+   try!(encoder.encode(value));
+   Ok(encoder.out)
+}
+
+struct JsonParser<'a> {
+   text: &'a str,
+   // a byte offset, which is what errors report
+   pos: uint,
+   depth: uint
+}
+
+impl<'a> JsonParser<'a> {
+   fn parse_value(&mut self) -> InterpResult<ExprAst> {
+      self.skip_whitespace();
+      if self.pos == self.text.len() {
+         return Err(self.error("found the end of the text where a value should be".to_string()));
+      }
+      match self.text.char_at(self.pos) {
+         '{' => self.parse_object(),
+         '[' => self.parse_array(),
+         '"' => Ok(String(StringAst::new(try!(self.parse_string())))),
+         '-' | '0' .. '9' => self.parse_number(),
+         't' => self.parse_literal("true", Boolean(BooleanAst::new(true))),
+         'f' => self.parse_literal("false", Boolean(BooleanAst::new(false))),
+         'n' => self.parse_literal("null", Nil(NilAst::new())),
+         _ => Err(self.error(format!("found {} where a value should be", self.describe_next())))
+      }
+   }
+
+   fn parse_object(&mut self) -> InterpResult<ExprAst> {
+      try!(self.enter());
+      let mut entries = HashMap::new();
+      self.skip_whitespace();
+      if !self.eat('}') {
+         loop {
+            self.skip_whitespace();
+            if self.pos == self.text.len() || self.text.char_at(self.pos) != '"' {
+               return Err(self.error(format!("found {} where a key should be", self.describe_next())));
+            }
+            let key = try!(self.parse_string());
+            try!(self.expect(':'));
+            let value = try!(self.parse_value());
+            entries.insert(key, value);
+            if !try!(self.separator('}')) {
+               break;
+            }
+         }
+      }
+      self.depth -= 1;
+      Ok(Dict(DictAst::new(entries)))
+   }
+
+   fn parse_array(&mut self) -> InterpResult<ExprAst> {
+      try!(self.enter());
+      let mut items = vec!();
+      self.skip_whitespace();
+      if !self.eat(']') {
+         loop {
+            items.push(try!(self.parse_value()));
+            if !try!(self.separator(']')) {
+               break;
+            }
+         }
+      }
+      self.depth -= 1;
+      Ok(Array(ArrayAst::new(items)))
+   }
+
+   // starts on the opening quote
+   fn parse_string(&mut self) -> InterpResult<String> {
+      let start = self.pos;
+      self.pos += 1;
+      let mut result = String::new();
+      loop {
+         if self.pos == self.text.len() {
+            self.pos = start;
+            return Err(self.error("found a string that is never closed".to_string()));
+         }
+         let range = self.text.char_range_at(self.pos);
+         match range.ch {
+            '"' => {
+               self.pos = range.next;
+               return Ok(result);
+            }
+            '\\' => {
+               self.pos = range.next;
+               let ch = try!(self.parse_escape());
+               result.push_char(ch);
+            }
+            ch if (ch as u32) < 0x20 => return Err(self.error("found a control character inside a string".to_string())),
+            ch => {
+               result.push_char(ch);
+               self.pos = range.next;
+            }
+         }
+      }
+   }
+
+   // starts just after the backslash
+   fn parse_escape(&mut self) -> InterpResult<char> {
+      if self.pos == self.text.len() {
+         return Err(self.error("found the end of the text inside an escape".to_string()));
+      }
+      let ch = self.text.char_at(self.pos);
+      let escaped = match ch {
+         '"' => '"',
+         '\\' => '\\',
+         '/' => '/',
+         'b' => '\x08',
+         'f' => '\x0c',
+         'n' => '\n',
+         'r' => '\r',
+         't' => '\t',
+         'u' => {
+            let start = self.pos - 1;
+            let first = try!(self.parse_hex());
+            let code =
+               if first >= 0xD800 && first < 0xDC00 {
+                  // the first half of a character outside the basic plane; the second half has to
+                  // follow straight away
+                  if !self.text.slice_from(self.pos).starts_with("\\u") {
+                     self.pos = start;
+                     return Err(self.error("found the first half of a surrogate pair without the second".to_string()));
+                  }
+                  self.pos += 1;
+                  let second = try!(self.parse_hex());
+                  if second < 0xDC00 || second >= 0xE000 {
+                     self.pos = start;
+                     return Err(self.error("found the first half of a surrogate pair without the second".to_string()));
+                  }
+                  0x10000 + ((first - 0xD800) << 10) + (second - 0xDC00)
+               } else if first >= 0xDC00 && first < 0xE000 {
+                  self.pos = start;
+                  return Err(self.error("found the second half of a surrogate pair on its own".to_string()));
+               } else {
+                  first
+               };
+            return Ok(::std::char::from_u32(code).unwrap());
+         }
+         _ => return Err(self.error(format!("found an unknown escape \\{}", ch)))
+      };
+      self.pos += 1;
+      Ok(escaped)
+   }
+
+   // starts on the u of \uXXXX and leaves pos after the last digit
+   fn parse_hex(&mut self) -> InterpResult<u32> {
+      self.pos += 1;
+      let mut code = 0u32;
+      for _ in range(0u, 4) {
+         let digit = if self.pos < self.text.len() { self.text.char_at(self.pos).to_digit(16) } else { None };
+         match digit {
+            Some(digit) => code = code * 16 + digit as u32,
+            None => return Err(self.error("found a \\u escape without four hex digits".to_string()))
+         }
+         self.pos += 1;
+      }
+      Ok(code)
+   }
+
+   fn parse_number(&mut self) -> InterpResult<ExprAst> {
+      let start = self.pos;
+      let mut float = false;
+      self.eat('-');
+      if !self.eat('0') && self.skip_digits() == 0 {
+         return Err(self.error(format!("found {} where a digit should be", self.describe_next())));
+      }
+      if self.eat('.') {
+         float = true;
+         if self.skip_digits() == 0 {
+            return Err(self.error(format!("found {} where a digit should be", self.describe_next())));
+         }
+      }
+      if self.eat('e') || self.eat('E') {
+         float = true;
+         if !self.eat('+') {
+            self.eat('-');
+         }
+         if self.skip_digits() == 0 {
+            return Err(self.error(format!("found {} where a digit should be", self.describe_next())));
+         }
+      }
+      let text = self.text.slice(start, self.pos);
+      if !float {
+         match from_str::<i64>(text) {
+            Some(value) => return Ok(Integer(IntegerAst::new(value))),
+            // too big even for an integer, so it can only be approximated
+            None => {}
+         }
+      }
+      match from_str::<f64>(text) {
+         Some(value) if value.is_finite() => Ok(Float(FloatAst::new(value))),
+         _ => {
+            self.pos = start;
+            Err(self.error(format!("found the number {} which is too large", text)))
+         }
+      }
+   }
+
+   fn parse_literal(&mut self, word: &str, value: ExprAst) -> InterpResult<ExprAst> {
+      if !self.text.slice_from(self.pos).starts_with(word) {
+         return Err(self.error(format!("found {} where a value should be", self.describe_next())));
+      }
+      self.pos += word.len();
+      Ok(value)
+   }
+
+   fn enter(&mut self) -> InterpResult<()> {
+      if self.depth == MAX_DEPTH {
+         let err = InterpError::new(LimitError, format!("json-parse found arrays and objects nested more than {} deep at byte {}", MAX_DEPTH, self.pos));
+         return Err(err);
+      }
+      self.depth += 1;
+      self.pos += 1;
+      Ok(())
+   }
+
+   // after an item: true if a comma means another one follows, false if close ended the list
+   fn separator(&mut self, close: char) -> InterpResult<bool> {
+      self.skip_whitespace();
+      if self.eat(',') {
+         Ok(true)
+      } else if self.eat(close) {
+         Ok(false)
+      } else {
+         Err(self.error(format!("found {} where ',' or '{}' should be", self.describe_next(), close)))
+      }
+   }
+
+   fn expect(&mut self, ch: char) -> InterpResult<()> {
+      self.skip_whitespace();
+      if !self.eat(ch) {
+         return Err(self.error(format!("found {} where '{}' should be", self.describe_next(), ch)));
+      }
+      Ok(())
+   }
+
+   // only for ASCII characters, which are all the structure JSON has
+   fn eat(&mut self, ch: char) -> bool {
+      if self.pos < self.text.len() && self.text.char_at(self.pos) == ch {
+         self.pos += 1;
+         true
+      } else {
+         false
+      }
+   }
+
+   fn skip_digits(&mut self) -> uint {
+      let start = self.pos;
+      while self.pos < self.text.len() && self.text.char_at(self.pos).is_digit() {
+         self.pos += 1;
+      }
+      self.pos - start
+   }
+
+   fn skip_whitespace(&mut self) {
+      while self.pos < self.text.len() {
+         match self.text.char_at(self.pos) {
+            ' ' | '\t' | '\n' | '\r' => self.pos += 1,
+            _ => break
+         }
+      }
+   }
+
+   fn describe_next(&self) -> String {
+      if self.pos == self.text.len() {
+         "the end of the text".to_string()
+      } else {
+         format!("'{}'", self.text.char_at(self.pos))
+      }
+   }
+
+   fn error(&self, message: String) -> InterpError {
+      InterpError::new(ValueError, format!("json-parse {} at byte {}", message, self.pos))
+   }
+}
+
+struct JsonEncoder {
+   out: String,
+   pretty: bool,
+   // the arrays and dicts being encoded, to refuse one that contains itself
+   open: Vec<uint>
+}
+
+impl JsonEncoder {
+   fn encode(&mut self, value: &ExprAst) -> InterpResult<()> {
+      match *value {
+         Integer(ref ast) => self.out.push_str(ast.value.to_string().as_slice()),
+         Float(ref ast) => {
+            if !ast.value.is_finite() {
+               return Err(InterpError::new(ValueError, format!("json-encode cannot encode the float {}", ast.value)));
+            }
+            // the same digits the source would have, which keep the point so it reads back as a float
+            self.out.push_str(value.source().as_slice());
+         }
+         String(ref ast) => self.encode_string(ast.string.as_slice()),
+         Boolean(ref ast) => self.out.push_str(if ast.value { "true" } else { "false" }),
+         Nil(_) => self.out.push_str("null"),
+         Array(ref ast) => {
+            let id = &*ast.items as *const RefCell<Vec<ExprAst>> as uint;
+            try!(self.enter(id, "array"));
+            self.out.push_char('[');
+            for (i, item) in ast.items.borrow().iter().enumerate() {
+               self.item_start(i);
+               try!(self.encode(item));
+            }
+            self.leave(ast.items.borrow().len(), ']');
+         }
+         Dict(ref ast) => {
+            let id = &*ast.entries as *const RefCell<HashMap<String, ExprAst>> as uint;
+            try!(self.enter(id, "dict"));
+            self.out.push_char('{');
+            let entries = ast.entries.borrow();
+            let keys = ast.sorted_keys();
+            for (i, key) in keys.iter().enumerate() {
+               self.item_start(i);
+               self.encode_string(key.as_slice());
+               self.out.push_str(if self.pretty { ": " } else { ":" });
+               try!(self.encode(entries.find(key).unwrap()));
+            }
+            self.leave(keys.len(), '}');
+         }
+         ref other => return Err(InterpError::new(TypeError, format!("json-encode cannot encode a value of type {}", Environment::type_name(other))))
+      }
+      Ok(())
+   }
+
+   fn encode_string(&mut self, value: &str) {
+      self.out.push_char('"');
+      for ch in value.chars() {
+         match ch {
+            '"' => self.out.push_str("\\\""),
+            '\\' => self.out.push_str("\\\\"),
+            '\n' => self.out.push_str("\\n"),
+            '\r' => self.out.push_str("\\r"),
+            '\t' => self.out.push_str("\\t"),
+            '\x08' => self.out.push_str("\\b"),
+            '\x0c' => self.out.push_str("\\f"),
+            ch if (ch as u32) < 0x20 => self.out.push_str(format!("\\u{:04x}", ch as u32).as_slice()),
+            ch => self.out.push_char(ch)
+         }
+      }
+      self.out.push_char('"');
+   }
+
+   fn enter(&mut self, id: uint, kind: &str) -> InterpResult<()> {
+      if self.open.contains(&id) {
+         return Err(InterpError::new(ValueError, format!("json-encode cannot encode a {} that contains itself", kind)));
+      }
+      self.open.push(id);
+      Ok(())
+   }
+
+   fn item_start(&mut self, index: uint) {
+      if index > 0 {
+         self.out.push_char(',');
+      }
+      let level = self.open.len();
+      self.newline(level);
+   }
+
+   // empty arrays and dicts stay on one line even when pretty
+   fn leave(&mut self, count: uint, close: char) {
+      self.open.pop();
+      let level = self.open.len();
+      if count > 0 {
+         self.newline(level);
+      }
+      self.out.push_char(close);
+   }
+
+   fn newline(&mut self, level: uint) {
+      if self.pretty {
+         self.out.push_char('\n');
+         for _ in range(0, level) {
+            self.out.push_str("  ");
+         }
+      }
+   }
+}
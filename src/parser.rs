@@ -1,4 +1,4 @@
-use std::num;
+use std::fmt;
 use ast::*;
 
 macro_rules! parse_subexprs (
@@ -52,9 +52,11 @@ pub struct Parser {
 }
 
 pub struct ParseError {
-   line: uint,
-   column: uint,
-   desc: String
+   pub line: uint,
+   pub column: uint,
+   pub desc: String,
+   // the source line the error is on, filled in once parsing has stopped
+   pub snippet: String
 }
 
 pub type ParseResult<T> = Result<T, ParseError>;
@@ -64,9 +66,26 @@ impl ParseError {
       ParseError {
          line: line,
          column: col,
-         desc: desc
+         desc: desc,
+         snippet: "".to_string()
       }
    }
+
+   // the offending line with a caret under the column, or nothing if the line is unknown
+   pub fn context(&self) -> String {
+      if self.snippet.len() == 0 {
+         return "".to_string();
+      }
+      let mut marker = String::from_char(self.column - 1, ' ');
+      marker.push_char('^');
+      format!("\n    {}\n    {}", self.snippet, marker)
+   }
+}
+
+impl fmt::Show for ParseError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "syntax error at {}:{}: {}{}", self.line, self.column, self.desc, self.context())
+   }
 }
 
 impl Parser {
@@ -86,36 +105,52 @@ impl Parser {
       self.column = 1;
    }
 
-   pub fn parse_code(&mut self, code: String) -> ExprAst {
+   pub fn parse_code(&mut self, code: String) -> ParseResult<ExprAst> {
       self.load_code(code);
       self.parse()
    }
 
-   pub fn parse(&mut self) -> ExprAst {
+   // always starts from the beginning of the loaded code, so parsing twice gives the same tree
+   pub fn parse(&mut self) -> ParseResult<ExprAst> {
+      self.pos = 0;
+      self.line = 1;
+      self.column = 1;
+      match self.parse_root() {
+         Ok(root) => Ok(Root(root)),
+         Err(mut f) => {
+            f.snippet = self.line_text(f.line);
+            Err(f)
+         }
+      }
+   }
+
+   fn parse_root(&mut self) -> ParseResult<RootAst> {
       let mut root = RootAst::new();
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       while self.pos < self.code.len() {
-         let expr = match self.parse_expr() {
-            Ok(m) => m,
-            Err(f) => {
-               error!("error at line {}, column {}: {}", f.line, f.column, f.desc);
-               fail!(); // fix fail! later
-            }
-         };
-         root.push(expr);
-         self.skip_whitespace();
+         root.push(try!(self.parse_expr()));
+         try!(self.skip_whitespace());
+      }
+      Ok(root)
+   }
+
+   fn line_text(&self, line: uint) -> String {
+      match self.code.as_slice().lines().nth(line - 1) {
+         Some(text) => text.to_string(),
+         None => "".to_string()
       }
-      Root(root)
    }
 
    fn parse_expr(&mut self) -> ParseResult<ExprAst> {
-      let expr = parse_subexprs!(self, parse_sexpr, parse_float, parse_integer, parse_boolean, parse_nil, parse_ident, parse_string, parse_symbol, parse_list, parse_array, parse_comment);
-      Ok(expr)
+      try!(self.skip_whitespace());
+      let span = self.span();
+      let expr = parse_subexprs!(self, parse_sexpr, parse_number, parse_raw_string, parse_boolean, parse_nil, parse_ident, parse_string, parse_symbol, parse_list, parse_array);
+      Ok(expr.with_span(span))
    }
 
    fn parse_sexpr(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       if self.pos == code.len() {
          Err(self.eof_error())
       } else if code.char_at(self.pos) == '(' {
@@ -123,7 +158,7 @@ impl Parser {
          let op = try!(self.parse_ident_stack());
          let mut operands = vec!();
          loop {
-            self.skip_whitespace();
+            try!(self.skip_whitespace());
             if self.pos == code.len() {
                return Err(self.eof_error());
             }
@@ -139,65 +174,136 @@ impl Parser {
       }
    }
 
-   fn parse_integer(&mut self) -> ParseResult<ExprAst> {
-      Ok(Integer(IntegerAst::new(try!(self.parse_integer_val()).val0())))
-   }
-
-   fn parse_integer_val(&mut self) -> ParseResult<(i64, uint)> {
+   // integers in decimal, hex (0x), octal (0o) or binary (0b), and decimal floats with an optional
+   // exponent; any of them may start with - and have a _ between two digits
+   fn parse_number(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
+      let line = self.line;
+      let column = self.column;
+      let mut text = String::new();
+      if self.pos < code.len() && code.char_at(self.pos) == '-' {
+         text.push_char('-');
+         self.inc_pos_col();
+      }
       if self.pos == code.len() {
          return Err(self.eof_error());
+      } else if !code.char_at(self.pos).is_digit() {
+         return Err(self.unexpected_error("number", format!("'{}'", code.char_at(self.pos))));
       }
-      let neg =
-         if code.char_at(self.pos) == '-' {
-            self.inc_pos_col();
-            true
+      let radix =
+         if code.char_at(self.pos) == '0' && self.pos + 1 < code.len() {
+            match code.char_at(self.pos + 1) {
+               'x' => 16,
+               'o' => 8,
+               'b' => 2,
+               _ => 10
+            }
          } else {
-            false
+            10
          };
-      let mut number = 0;
-      let mut digits = 0;
-      while self.pos < code.len() && code.char_at(self.pos).is_digit() {
-         digits += 1;
-         number = number * 10 + code.char_at(self.pos).to_digit(10).unwrap() as i64;
+      if radix != 10 {
          self.inc_pos_col();
+         self.inc_pos_col();
+         let digits = try!(self.parse_digits(radix));
+         return Parser::integer_value(digits.as_slice(), radix, text.len() > 0, line, column);
       }
-      if digits == 0 {
-         Err(self.unexpected_error("integer", format!("'{}'", code.char_at(self.pos))))
+      let digits = try!(self.parse_digits(10));
+      text.push_str(digits.as_slice());
+      let mut float = false;
+      if self.pos < code.len() && code.char_at(self.pos) == '.' {
+         self.inc_pos_col();
+         if self.pos == code.len() {
+            return Err(self.eof_error());
+         } else if !code.char_at(self.pos).is_digit() {
+            return Err(self.unexpected_error("float", format!("'{}'", code.char_at(self.pos))));
+         }
+         text.push_char('.');
+         text.push_str(try!(self.parse_digits(10)).as_slice());
+         float = true;
+      }
+      if self.pos < code.len() && (code.char_at(self.pos) == 'e' || code.char_at(self.pos) == 'E') {
+         self.inc_pos_col();
+         text.push_char('e');
+         if self.pos < code.len() && (code.char_at(self.pos) == '-' || code.char_at(self.pos) == '+') {
+            text.push_char(code.char_at(self.pos));
+            self.inc_pos_col();
+         }
+         if self.pos == code.len() {
+            return Err(self.eof_error());
+         } else if !code.char_at(self.pos).is_digit() {
+            return Err(self.unexpected_error("exponent", format!("'{}'", code.char_at(self.pos))));
+         }
+         text.push_str(try!(self.parse_digits(10)).as_slice());
+         float = true;
+      }
+      if float {
+         match from_str::<f64>(text.as_slice()) {
+            Some(value) if value.is_finite() => Ok(Float(FloatAst::new(value))),
+            _ => Err(ParseError::new(line, column, format!("float literal {} is out of range", text)))
+         }
       } else {
-         Ok((if neg { -number } else { number }, digits))
+         Parser::integer_value(digits.as_slice(), 10, text.len() > digits.len(), line, column)
       }
    }
 
-   fn parse_float(&mut self) -> ParseResult<ExprAst> {
+   // digits valid in radix, skipping any _ that sits between two of them
+   fn parse_digits(&mut self, radix: uint) -> ParseResult<String> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      let front = try!(self.parse_integer_val()).val0();
-      if self.pos + 1 >= code.len() {
+      let mut digits = String::new();
+      while self.pos < code.len() {
+         let ch = code.char_at(self.pos);
+         if ch == '_' && digits.len() > 0 {
+            self.inc_pos_col();
+            if self.pos == code.len() || code.char_at(self.pos).to_digit(radix).is_none() {
+               return Err(self.unexpected_error("digit after '_'", if self.pos == code.len() {
+                  "end of file".to_string()
+               } else {
+                  format!("'{}'", code.char_at(self.pos))
+               }));
+            }
+         } else if ch.to_digit(radix).is_some() {
+            digits.push_char(ch);
+            self.inc_pos_col();
+         } else {
+            break;
+         }
+      }
+      if digits.len() > 0 {
+         Ok(digits)
+      } else if self.pos == code.len() {
          Err(self.eof_error())
-      } else if code.char_at(self.pos) != '.' {
-         Err(self.unexpected_error("'.'", format!("'{}'", code.char_at(self.pos))))
       } else {
-         self.inc_pos_col();
-         if !code.char_at(self.pos).is_digit() {
-            Err(self.unexpected_error("float", format!("'{}'", code.char_at(self.pos))))
-         } else {
-            let back = try!(self.parse_integer_val());
-            Ok(Float(FloatAst::new(front as f64 + back.val0() as f64 / num::pow(10u, back.val1()) as f64)))
+         Err(self.unexpected_error(format!("base {} digit", radix), format!("'{}'", code.char_at(self.pos))))
+      }
+   }
+
+   // the digits are read as a u64 so that the most negative i64, which has no positive
+   // counterpart, is still in range
+   fn integer_value(digits: &str, radix: uint, neg: bool, line: uint, column: uint) -> ParseResult<ExprAst> {
+      let limit = if neg { 1u64 << 63 } else { (1u64 << 63) - 1 };
+      let mut value = 0u64;
+      for ch in digits.chars() {
+         let digit = ch.to_digit(radix).unwrap() as u64;
+         if value > (limit - digit) / radix as u64 {
+            return Err(ParseError::new(line, column, "integer literal is out of range".to_string()));
          }
+         value = value * radix as u64 + digit;
       }
+      // negating as two's complement, which gets i64::MIN right
+      Ok(Integer(IntegerAst::new(if neg { (!value + 1) as i64 } else { value as i64 })))
    }
 
    fn parse_array(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       if self.pos + 1 >= code.len() {
          Err(self.eof_error())
       } else if code.char_at(self.pos) == '[' {
          self.inc_pos_col();
          let mut items = vec!();
          loop {
-            self.skip_whitespace();
+            try!(self.skip_whitespace());
             if self.pos == code.len() {
                return Err(self.eof_error());
             }
@@ -215,7 +321,7 @@ impl Parser {
 
    fn parse_list(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       if self.pos + 2 >= code.len() {
          Err(self.eof_error())
       } else if code.char_at(self.pos) == '\'' {
@@ -224,7 +330,7 @@ impl Parser {
             self.inc_pos_col();
             let mut items = vec!();
             loop {
-               self.skip_whitespace();
+               try!(self.skip_whitespace());
                if self.pos == code.len() {
                   return Err(self.eof_error());
                }
@@ -250,7 +356,8 @@ impl Parser {
 
    fn parse_ident_stack(&mut self) -> ParseResult<IdentAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
+      let span = self.span();
       if self.pos == code.len() {
          Err(self.eof_error())
       } else {
@@ -273,47 +380,137 @@ impl Parser {
                Err(self.unexpected_error("ident", format!("'{}'", code.char_at(self.pos))))
             }
          } else {
-            Ok(IdentAst::new(ident))
+            let mut ast = IdentAst::new(Name::new(ident.as_slice()));
+            ast.span = span;
+            Ok(ast)
          }
       }
    }
 
    fn parse_string(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       if self.pos == code.len() {
          Err(self.eof_error())
       } else if code.char_at(self.pos) == '"' {
          self.inc_pos_col();
          let mut buf = String::new();
-         while self.pos < code.len() && (code.char_at(self.pos) != '"' || code.char_at(self.pos - 1) == '\\') {
-            buf.push_char(code.char_at(self.pos));
-            if code.char_at(self.pos) == '\n' {
-               self.add_line();
+         loop {
+            if self.pos == code.len() {
+               return Err(self.eof_error());
+            }
+            let ch = code.char_at(self.pos);
+            if ch == '"' {
+               self.inc_pos_col();
+               break;
+            } else if ch == '\\' {
+               buf.push_char(try!(self.parse_escape()));
             } else {
-               self.column += 1;
+               buf.push_char(ch);
+               if ch == '\n' {
+                  self.add_line();
+                  self.pos += 1;
+               } else {
+                  self.inc_pos_col();
+               }
             }
-            self.pos += 1;
          }
+         Ok(String(StringAst::new(buf)))
+      } else {
+         Err(self.unexpected_error("\"", format!("'{}'", code.char_at(self.pos))))
+      }
+   }
+
+   // r"..." keeps everything between the quotes as written, so a backslash is just a backslash
+   // and the string cannot contain a quote
+   fn parse_raw_string(&mut self) -> ParseResult<ExprAst> {
+      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
+      try!(self.skip_whitespace());
+      if self.pos == code.len() {
+         return Err(self.eof_error());
+      } else if !code.slice_from(self.pos).starts_with("r\"") {
+         return Err(self.unexpected_error("r\"", format!("'{}'", code.char_at(self.pos))));
+      }
+      self.inc_pos_col();
+      self.inc_pos_col();
+      let mut buf = String::new();
+      loop {
          if self.pos == code.len() {
-            Err(self.eof_error())
+            return Err(self.eof_error());
+         }
+         let ch = code.char_at(self.pos);
+         if ch == '"' {
+            self.inc_pos_col();
+            break;
+         }
+         buf.push_char(ch);
+         if ch == '\n' {
+            self.add_line();
+            self.pos += 1;
          } else {
             self.inc_pos_col();
-            Ok(String(StringAst::new(buf)))
          }
-      } else {
-         Err(self.unexpected_error("\"", format!("'{}'", code.char_at(self.pos))))
+      }
+      Ok(String(StringAst::new(buf)))
+   }
+
+   fn parse_escape(&mut self) -> ParseResult<char> {
+      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
+      let line = self.line;
+      let column = self.column;
+      self.inc_pos_col();
+      if self.pos == code.len() {
+         return Err(self.eof_error());
+      }
+      let ch = code.char_at(self.pos);
+      self.inc_pos_col();
+      match ch {
+         'n' => Ok('\n'),
+         't' => Ok('\t'),
+         'r' => Ok('\r'),
+         '0' => Ok('\0'),
+         '\\' => Ok('\\'),
+         '"' => Ok('"'),
+         'u' => {
+            if self.pos == code.len() || code.char_at(self.pos) != '{' {
+               return Err(ParseError::new(line, column, "expected '{' after \\u".to_string()));
+            }
+            self.inc_pos_col();
+            let mut value = 0u32;
+            let mut digits = 0u;
+            while self.pos < code.len() && code.char_at(self.pos) != '}' {
+               match code.char_at(self.pos).to_digit(16) {
+                  Some(digit) if digits < 6 => value = value * 16 + digit as u32,
+                  _ => return Err(ParseError::new(line, column, "invalid unicode escape (expected 1 to 6 hex digits)".to_string()))
+               }
+               digits += 1;
+               self.inc_pos_col();
+            }
+            if self.pos == code.len() {
+               return Err(self.eof_error());
+            }
+            self.inc_pos_col();
+            if digits == 0 {
+               return Err(ParseError::new(line, column, "invalid unicode escape (expected 1 to 6 hex digits)".to_string()));
+            }
+            match ::std::char::from_u32(value) {
+               Some(ch) => Ok(ch),
+               None => Err(ParseError::new(line, column, format!("\\u{{{:x}}} is not a valid unicode character", value)))
+            }
+         }
+         other => Err(ParseError::new(line, column, format!("unknown escape sequence '\\{}'", other)))
       }
    }
 
    fn parse_boolean(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       if self.pos == code.len() {
          Err(self.eof_error())
       } else {
          let mut buf = String::new();
-         while self.pos < code.len() && code.char_at(self.pos).is_alphabetic() {
+         // read the whole word so that identifiers like nil? are not mistaken for nil
+         while self.pos < code.len() && self.is_ident_char(code.char_at(self.pos)) {
             buf.push_char(code.char_at(self.pos));
             self.inc_pos_col();
          }
@@ -328,12 +525,13 @@ impl Parser {
 
    fn parse_nil(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       if self.pos == code.len() {
          Err(self.eof_error())
       } else {
          let mut buf = String::new();
-         while self.pos < code.len() && code.char_at(self.pos).is_alphabetic() {
+         // read the whole word so that identifiers like nil? are not mistaken for nil
+         while self.pos < code.len() && self.is_ident_char(code.char_at(self.pos)) {
             buf.push_char(code.char_at(self.pos));
             self.inc_pos_col();
          }
@@ -348,36 +546,21 @@ impl Parser {
 
    fn parse_symbol(&mut self) -> ParseResult<ExprAst> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
-      if self.pos + 1 >= code.len() {
-         Err(self.eof_error())
-      } else if !self.is_ident_char(code.char_at(self.pos + 1)) {
-         self.column += 1;
-         Err(self.unexpected_error("alphabetic character", format!("'{}'", code.char_at(self.pos + 1))))
-      } else if code.char_at(self.pos) == '\'' {
-         self.inc_pos_col();
-         let ident = try!(self.parse_ident_stack());
-         Ok(Symbol(SymbolAst::new(ident.value)))
-      } else {
-         Err(self.unexpected_error("\"'\"", format!("'{}'", code.char_at(self.pos))))
-      }
-   }
-
-   fn parse_comment(&mut self) -> ParseResult<ExprAst> {
-      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      self.skip_whitespace();
+      try!(self.skip_whitespace());
       if self.pos == code.len() {
          Err(self.eof_error())
-      } else if code.char_at(self.pos) == ';' {
+      } else if code.char_at(self.pos) != '\'' {
+         Err(self.unexpected_error("\"'\"", format!("'{}'", code.char_at(self.pos))))
+      } else {
          self.inc_pos_col();
-         let mut buf = String::new();
-         while self.pos < code.len() && code.char_at(self.pos) != '\n' {
-            buf.push_char(code.char_at(self.pos));
-            self.inc_pos_col();
+         if self.pos == code.len() {
+            Err(self.eof_error())
+         } else if !self.is_ident_char(code.char_at(self.pos)) {
+            Err(self.unexpected_error("alphabetic character", format!("'{}'", code.char_at(self.pos))))
+         } else {
+            let ident = try!(self.parse_ident_stack());
+            Ok(Symbol(SymbolAst::new(ident.value)))
          }
-         Ok(Comment(CommentAst::new(buf)))
-      } else {
-         Err(self.unexpected_error("';'", format!("'{}'", code.char_at(self.pos))))
       }
    }
 
@@ -390,16 +573,52 @@ impl Parser {
       }
    }
 
-   #[inline(always)]
-   fn skip_whitespace(&mut self) {
+   // comments count as whitespace: ; runs to the end of the line, and #| |# blocks may nest
+   fn skip_whitespace(&mut self) -> ParseResult<()> {
+      let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
+      while self.pos < code.len() {
+         let ch = code.char_at(self.pos);
+         if ch == '\n' {
+            self.add_line();
+            self.pos += 1;
+         } else if ch.is_whitespace() {
+            self.inc_pos_col();
+         } else if ch == ';' {
+            while self.pos < code.len() && code.char_at(self.pos) != '\n' {
+               self.inc_pos_col();
+            }
+         } else if code.slice_from(self.pos).starts_with("#|") {
+            try!(self.skip_block_comment());
+         } else {
+            break;
+         }
+      }
+      Ok(())
+   }
+
+   fn skip_block_comment(&mut self) -> ParseResult<()> {
       let code: &mut str = unsafe { ::std::mem::transmute(self.code.as_slice()) };
-      while self.pos < code.len() && code.char_at(self.pos).is_whitespace() {
-         if code.char_at(self.pos) == '\n' {
+      let line = self.line;
+      let column = self.column;
+      let mut depth = 0u;
+      loop {
+         if self.pos == code.len() {
+            return Err(ParseError::new(line, column, "unterminated block comment".to_string()));
+         }
+         let rest = code.slice_from(self.pos);
+         if rest.starts_with("#|") || rest.starts_with("|#") {
+            depth = if rest.starts_with("#|") { depth + 1 } else { depth - 1 };
+            self.inc_pos_col();
+            self.inc_pos_col();
+            if depth == 0 {
+               return Ok(());
+            }
+         } else if code.char_at(self.pos) == '\n' {
             self.add_line();
+            self.pos += 1;
          } else {
-            self.column += 1;
+            self.inc_pos_col();
          }
-         self.pos += 1;
       }
    }
 
@@ -411,8 +630,14 @@ impl Parser {
 
    #[inline(always)]
    fn inc_pos_col(&mut self) {
+      // pos is a byte offset, so step over the whole (possibly multi-byte) character
       self.column += 1;
-      self.pos += 1;
+      self.pos = self.code.as_slice().char_range_at(self.pos).next;
+   }
+
+   #[inline(always)]
+   fn span(&self) -> Span {
+      Span::new(self.line, self.column, self.pos)
    }
 
    #[inline(always)]
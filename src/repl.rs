@@ -0,0 +1,151 @@
+use std::io;
+use std::mem;
+
+use ast::*;
+use interp::{Interpreter, InterpError, Exit};
+
+static PROMPT: &'static str = "iron> ";
+static CONTINUE: &'static str = "...   ";
+
+// reads forms from input until EOF or (exit), echoing the value of each one to output; the
+// status is the one given to exit, or 0 at EOF
+pub fn run(interp: &mut Interpreter, input: &mut Buffer, output: &mut Writer) -> io::IoResult<int> {
+   let mut pending = String::new();
+   loop {
+      try!(output.write_str(if pending.len() == 0 { PROMPT } else { CONTINUE }));
+      try!(output.flush());
+      let line = match input.read_line() {
+         Ok(line) => line,
+         Err(ref f) if f.kind == io::EndOfFile => {
+            try!(output.write_line(""));
+            return Ok(0);
+         }
+         Err(f) => return Err(f)
+      };
+      pending.push_str(line.as_slice());
+      if open_brackets(pending.as_slice()) > 0 {
+         continue;
+      }
+      let code = mem::replace(&mut pending, String::new());
+      if code.as_slice().trim().len() == 0 {
+         continue;
+      }
+      match interp.eval_str(code.as_slice()) {
+         Ok(Nil(_)) => {}
+         Ok(value) => try!(output.write_line(Interpreter::describe_value(&value).as_slice())),
+         Err(InterpError { kind: Exit(status), .. }) => return Ok(status),
+         // the environment is still fine after an error, so carry on
+         Err(f) => try!(output.write_line(format!("{}", f).as_slice()))
+      }
+   }
+}
+
+// how many more brackets have been opened than closed, ignoring any inside strings and comments;
+// anything above zero means the form is not finished yet, as does an unclosed string or block
+// comment
+fn open_brackets(code: &str) -> int {
+   let mut depth = 0;
+   let mut in_string = false;
+   // r"..." strings end at the next quote, whatever comes before it
+   let mut raw = false;
+   let mut in_comment = false;
+   let mut escaped = false;
+   // how deeply nested in #| |# comments the current character is
+   let mut blocks = 0;
+   let mut prev = ' ';
+   for ch in code.chars() {
+      if blocks > 0 {
+         if prev == '#' && ch == '|' {
+            blocks += 1;
+            prev = ' ';
+            continue;
+         } else if prev == '|' && ch == '#' {
+            blocks -= 1;
+            prev = ' ';
+            continue;
+         }
+      } else if in_comment {
+         in_comment = ch != '\n';
+      } else if in_string {
+         if raw {
+            in_string = ch != '"';
+         } else if escaped {
+            escaped = false;
+         } else if ch == '\\' {
+            escaped = true;
+         } else if ch == '"' {
+            in_string = false;
+         }
+      } else if prev == '#' && ch == '|' {
+         blocks = 1;
+         prev = ' ';
+         continue;
+      } else {
+         match ch {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            '"' => {
+               in_string = true;
+               raw = prev == 'r';
+            }
+            ';' => in_comment = true,
+            _ => {}
+         }
+      }
+      prev = ch;
+   }
+   if blocks > 0 || in_string { depth + 1 } else { depth }
+}
+
+#[cfg(test)]
+mod test {
+   use std::io::{MemReader, MemWriter};
+
+   use interp::{Interpreter, Bytecode};
+   use super::run;
+
+   // everything the REPL writes for the given input, and the status it ends with
+   fn transcript(interp: &mut Interpreter, input: &str) -> (String, int) {
+      let mut reader = MemReader::new(input.as_bytes().to_vec());
+      let mut writer = MemWriter::new();
+      let status = run(interp, &mut reader, &mut writer).unwrap();
+      (String::from_utf8(writer.unwrap()).unwrap(), status)
+   }
+
+   #[test]
+   fn values_are_echoed_and_definitions_kept() {
+      let (out, status) = transcript(&mut Interpreter::new(), "(define x 2)\n(+ x 40)\n(if false 1)\n");
+      assert_eq!(out.as_slice(), "iron> 2\niron> 42\niron> iron> \n");
+      assert_eq!(status, 0);
+   }
+
+   #[test]
+   fn unfinished_forms_are_continued_on_the_next_line() {
+      let input = "(+ 1\n   2)\n(define s \")\"\n)\n#| (\n|# [1\n2]\n";
+      let (out, _) = transcript(&mut Interpreter::new(), input);
+      assert_eq!(out.as_slice(), "iron> ...   3\niron> ...   )\niron> ...   ...   [1 2]\niron> \n");
+   }
+
+   #[test]
+   fn errors_are_reported_without_ending_the_session() {
+      let (out, status) = transcript(&mut Interpreter::new(), "(define a [1])\n(get a 5)\n(len a)\n");
+      assert_eq!(out.as_slice(), "iron> [1]\niron> index error at 1:1: get: index 5 is out of bounds for an array of length 1\niron> 1\niron> \n");
+      assert_eq!(status, 0);
+   }
+
+   #[test]
+   fn exit_ends_the_session_with_its_status() {
+      let (out, status) = transcript(&mut Interpreter::new(), "(exit 7)\n(println \"never\")\n");
+      assert_eq!(out.as_slice(), "iron> ");
+      assert_eq!(status, 7);
+   }
+
+   #[test]
+   fn the_backend_and_fuel_apply_to_the_session() {
+      let mut interp = Interpreter::new();
+      interp.set_backend(Bytecode);
+      interp.set_fuel(Some(1000));
+      let (out, _) = transcript(&mut interp, "(+ 1 2)\n(while true nil)\n");
+      assert!(out.as_slice().starts_with("iron> 3\niron> limit error"), "unexpected transcript: {}", out);
+   }
+}
@@ -0,0 +1,39 @@
+#!/bin/sh
+# runs every test program and checks that each line it prints (errors included) starts with the
+# matching ; expect: line; a test without any fails, since nothing it prints would be checked.
+# Any arguments, e.g. --bytecode, are given to iron too; a test whose ; run with: line already
+# has them was covered by the plain run
+status=0
+expected=$(mktemp)
+actual=$(mktemp)
+for file in test/*.irl; do
+	grep -q '^; expect:' "$file" || {
+		echo "$file has no ; expect: lines"
+		status=1
+		continue
+	}
+	command=$(sed -n 's/^; run with: iron //p' "$file")
+	[ -n "$command" ] || command="$file"
+	skip=
+	for flag in "$@"; do
+		case " $command " in
+			*" $flag "*) skip=yes ;;
+		esac
+	done
+	[ -z "$skip" ] || continue
+	sed -n 's/^; expect: \{0,1\}//p' "$file" > "$expected"
+	./iron "$@" $command < /dev/null > "$actual" 2>&1
+	awk 'NR == FNR { want[FNR] = $0; lines = FNR; next }
+	     { got[FNR] = $0; printed = FNR }
+	     END {
+	        if (printed != lines) exit 1
+	        for (i = 1; i <= lines; i++)
+	           if (want[i] == "" ? got[i] != "" : index(got[i], want[i]) != 1) exit 1
+	     }' "$expected" "$actual" || {
+		echo "$file did not print what it expects${*:+ with $*}:"
+		diff "$expected" "$actual" | sed 's/^/    /'
+		status=1
+	}
+done
+rm -f "$expected" "$actual"
+exit $status